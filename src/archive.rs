@@ -0,0 +1,772 @@
+use crate::{
+    dialect::{self, DialectOverrides},
+    redaction::Redaction,
+};
+use anyhow::{bail, Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use rayon::prelude::*;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{Cursor, Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use zip::read::ZipArchive;
+
+// Nested archives deeper than this are reported as skipped instead of being opened
+const MAX_DEPTH: usize = 16;
+// Memory reserved up front for an entry at most, the size in the header may be corrupt or crafted
+const MAX_RESERVE: u64 = 64 << 20;
+// Columns a csv needs to be taken for a treatment summary
+pub const SUMMARY_SIGNATURE: [&str; 4] =
+    ["Time", "Energy[J]", "Act. Energy[J]", "Num. of SubSonic"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ExtractLayout {
+    // Keep the folders of the archive, nested archives become folders
+    #[default]
+    Preserve,
+    // Flatten everything into one folder, prefixing file names with their archive path
+    Prefix,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExtractOptions {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub layout: ExtractLayout,
+    // Black out burned-in patient information in BMPs before they are written
+    pub redaction: Option<Redaction>,
+}
+
+impl Default for ExtractOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            include: vec!["*.bmp".to_string()],
+            exclude: Vec::new(),
+            layout: ExtractLayout::Preserve,
+            redaction: None,
+        }
+    }
+}
+
+// Named filter presets shown in the extraction settings
+pub const FILTER_PRESETS: [(&str, &[&str]); 4] = [
+    ("BMP", &["*.bmp"]),
+    ("CSV", &["*.csv"]),
+    ("DICOM", &["*.dcm", "*DICOMDIR"]),
+    ("Logs", &["*.log", "*.txt"]),
+];
+
+#[derive(Clone, Debug)]
+pub struct ExtractedFile {
+    // Location inside the archive, nested archives separated by '/'
+    pub archive_path: String,
+    // Relative output path according to the chosen layout
+    pub path: PathBuf,
+    pub modified: Option<zip::DateTime>,
+    pub data: Arc<[u8]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SkippedEntry {
+    pub archive_path: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExtractManifest {
+    pub extracted: Vec<ExtractedFile>,
+    pub skipped: Vec<SkippedEntry>,
+}
+
+impl ExtractManifest {
+    #[inline(always)]
+    pub fn total_bytes(&self) -> usize {
+        self.extracted.iter().map(|file| file.data.len()).sum()
+    }
+
+    // Write the manifest as csv: one line per extracted or skipped entry
+    #[inline(always)]
+    pub fn write_csv<W: Write>(&self, writer: W) -> Result<()> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(["status", "archive path", "output path", "bytes", "reason"])?;
+        for file in &self.extracted {
+            wtr.write_record([
+                "extracted",
+                &file.archive_path,
+                &file.path.to_string_lossy(),
+                &file.data.len().to_string(),
+                "",
+            ])?;
+        }
+        for entry in &self.skipped {
+            wtr.write_record(["skipped", &entry.archive_path, "", "", &entry.reason])?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[inline(always)]
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns.iter().map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        builder.add(GlobBuilder::new(pattern).case_insensitive(true).build()?);
+    }
+    Ok(builder.build()?)
+}
+
+struct Filters {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl Filters {
+    #[inline(always)]
+    fn skip_reason(&self, name: &str) -> Option<&'static str> {
+        if self.exclude.is_match(name) {
+            Some("excluded by filter")
+        } else if !self.include.is_empty() && !self.include.is_match(name) {
+            Some("not matched by include filter")
+        } else {
+            None
+        }
+    }
+}
+
+#[inline(always)]
+fn is_archive(name: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(".zip")
+}
+
+// Inflate an entry, the buffer grows past the reservation when the entry really is larger
+#[inline(always)]
+fn read_all(file: &mut zip::read::ZipFile<'_>) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(file.size().min(MAX_RESERVE) as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[inline(always)]
+fn output_path(layout: ExtractLayout, parents: &[PathBuf], enclosed: &Path) -> PathBuf {
+    match layout {
+        ExtractLayout::Preserve => {
+            let mut path: PathBuf = parents.iter().collect();
+            path.push(enclosed);
+            path
+        }
+        ExtractLayout::Prefix => PathBuf::from(
+            parents
+                .iter()
+                .flat_map(|parent| parent.components())
+                .chain(enclosed.components())
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join("_"),
+        ),
+    }
+}
+
+#[inline(always)]
+fn walk_archive<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    parents: &mut Vec<PathBuf>,
+    archive_prefix: &str,
+    filters: &Filters,
+    options: &ExtractOptions,
+    used: &mut HashSet<PathBuf>,
+    manifest: &mut ExtractManifest,
+) -> Result<()> {
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let archive_path = format!("{}{}", archive_prefix, file.name());
+        let Some(enclosed) = file.enclosed_name().map(Path::to_path_buf) else {
+            manifest.skipped.push(SkippedEntry {
+                archive_path,
+                reason: "unsafe path".to_string(),
+            });
+            continue;
+        };
+
+        if is_archive(file.name()) {
+            // Exclude globs can drop a whole nested archive, include globs only pick files
+            if filters.exclude.is_match(&archive_path) {
+                manifest.skipped.push(SkippedEntry {
+                    archive_path,
+                    reason: "excluded by filter".to_string(),
+                });
+                continue;
+            }
+            if parents.len() >= MAX_DEPTH {
+                manifest.skipped.push(SkippedEntry {
+                    archive_path,
+                    reason: format!("nested deeper than {} archives", MAX_DEPTH),
+                });
+                continue;
+            }
+            // If the file is a zip file, recursively extract it
+            let buffer = read_all(&mut file)?;
+            match ZipArchive::new(Cursor::new(buffer)) {
+                Ok(mut inner_archive) => {
+                    parents.push(enclosed.with_extension(""));
+                    let result = walk_archive(
+                        &mut inner_archive,
+                        parents,
+                        &format!("{}/", archive_path),
+                        filters,
+                        options,
+                        used,
+                        manifest,
+                    );
+                    parents.pop();
+                    if let Err(e) = result {
+                        manifest.skipped.push(SkippedEntry {
+                            archive_path,
+                            reason: format!("corrupt archive: {}", e),
+                        });
+                    }
+                }
+                Err(e) => manifest.skipped.push(SkippedEntry {
+                    archive_path,
+                    reason: format!("not a readable archive: {}", e),
+                }),
+            }
+            continue;
+        }
+
+        if let Some(reason) = filters.skip_reason(&archive_path) {
+            manifest.skipped.push(SkippedEntry {
+                archive_path,
+                reason: reason.to_string(),
+            });
+            continue;
+        }
+
+        let path = output_path(options.layout, parents, &enclosed);
+        if !used.insert(path.clone()) {
+            manifest.skipped.push(SkippedEntry {
+                archive_path,
+                reason: format!("duplicate output path {}", path.display()),
+            });
+            continue;
+        }
+        let data = read_all(&mut file)?;
+        manifest.extracted.push(ExtractedFile {
+            archive_path,
+            path,
+            modified: Some(file.last_modified()),
+            data: data.into(),
+        });
+    }
+    Ok(())
+}
+
+// Extract the matching entries of an archive and of every archive nested in it in memory
+#[inline(always)]
+pub fn read_archive<R: Read + Seek>(
+    reader: R,
+    options: &ExtractOptions,
+) -> Result<ExtractManifest> {
+    let filters = Filters {
+        include: build_globset(&options.include)?,
+        exclude: build_globset(&options.exclude)?,
+    };
+    let mut archive = ZipArchive::new(reader)?;
+    let mut manifest = ExtractManifest::default();
+    walk_archive(
+        &mut archive,
+        &mut Vec::new(),
+        "",
+        &filters,
+        options,
+        &mut HashSet::new(),
+        &mut manifest,
+    )?;
+    Ok(manifest)
+}
+
+#[inline(always)]
+pub fn read_zip(path: &Path, options: &ExtractOptions) -> Result<ExtractManifest> {
+    read_archive(File::open(path)?, options)
+}
+
+// Write the extracted files below dir_path, together with a manifest.csv
+#[inline(always)]
+pub fn write_manifest(
+    manifest: &ExtractManifest,
+    dir_path: &Path,
+    redaction: Option<&Redaction>,
+) -> Result<()> {
+    std::fs::create_dir_all(dir_path)?;
+    manifest.extracted.par_iter().try_for_each(|file| {
+        // Joined paths keep their ".." components, so starts_with on the result proves nothing
+        if !file
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("{} escapes the output folder", file.path.display());
+        }
+        let outpath = dir_path.join(&file.path);
+        if let Some(parent) = outpath.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let name = file.archive_path.to_ascii_lowercase();
+        match redaction {
+            Some(redaction) if name.ends_with(".bmp") => {
+                let data = redaction
+                    .redact_bmp(&file.data)
+                    .with_context(|| format!("cannot redact {}", file.archive_path))?;
+                std::fs::write(outpath, data)?;
+            }
+            Some(redaction) if name.ends_with(".dcm") => {
+                let data = redaction
+                    .redact_dicom_bytes(&file.data)
+                    .with_context(|| format!("cannot redact {}", file.archive_path))?;
+                std::fs::write(outpath, data)?;
+            }
+            _ => std::fs::write(outpath, &file.data)?,
+        }
+        Ok(())
+    })?;
+    manifest.write_csv(File::create(dir_path.join("manifest.csv"))?)
+}
+
+#[inline(always)]
+pub fn extract_zip(
+    path: &Path,
+    dir_path: &Path,
+    options: &ExtractOptions,
+) -> Result<ExtractManifest> {
+    let manifest = read_zip(path, options)?;
+    write_manifest(&manifest, dir_path, options.redaction.as_ref())?;
+    Ok(manifest)
+}
+
+#[derive(Clone, Debug)]
+pub struct ZipEntry {
+    // Location inside the archive, nested archives separated by '/'
+    pub archive_path: String,
+    pub size: u64,
+    pub modified: Option<zip::DateTime>,
+    // Nested archives are listed before their own entries
+    pub depth: usize,
+    pub is_archive: bool,
+    // Why the entries of a nested archive are missing from the listing
+    pub error: Option<String>,
+}
+
+#[inline(always)]
+fn walk_entries<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    archive_prefix: &str,
+    depth: usize,
+    entries: &mut Vec<ZipEntry>,
+) -> Result<()> {
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let archive_path = format!("{}{}", archive_prefix, file.name());
+        let nested = is_archive(file.name()) && depth < MAX_DEPTH;
+        let idx = entries.len();
+        entries.push(ZipEntry {
+            archive_path: archive_path.clone(),
+            size: file.size(),
+            modified: Some(file.last_modified()),
+            depth,
+            is_archive: nested,
+            error: None,
+        });
+        if nested {
+            // A broken nested archive is still listed, with the reason its entries are missing
+            let listed = read_all(&mut file)
+                .map_err(anyhow::Error::from)
+                .and_then(|buffer| Ok(ZipArchive::new(Cursor::new(buffer))?))
+                .and_then(|mut inner| {
+                    walk_entries(
+                        &mut inner,
+                        &format!("{}/", archive_path),
+                        depth + 1,
+                        entries,
+                    )
+                });
+            if let Err(e) = listed {
+                entries[idx].error = Some(format!("{:#}", e));
+            }
+        } else if is_archive(file.name()) {
+            entries[idx].error = Some(format!("nested deeper than {} archives", MAX_DEPTH));
+        }
+    }
+    Ok(())
+}
+
+// Every file of an archive and of the archives nested in it. Nested archives are
+// inflated in memory to list their entries, other files are not read.
+#[inline(always)]
+pub fn list_entries(path: &Path) -> Result<Vec<ZipEntry>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut entries = Vec::new();
+    walk_entries(&mut archive, "", 0, &mut entries)?;
+    Ok(entries)
+}
+
+#[inline(always)]
+fn read_nested<R: Read + Seek>(archive: &mut ZipArchive<R>, archive_path: &str) -> Result<Vec<u8>> {
+    if let Ok(mut file) = archive.by_name(archive_path) {
+        let data = read_all(&mut file)?;
+        return Ok(data);
+    }
+    // Descend into the nested archive the path starts with
+    let names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    for name in names.iter().filter(|name| is_archive(name)) {
+        let Some(rest) = archive_path.strip_prefix(&format!("{}/", name)) else {
+            continue;
+        };
+        let mut file = archive.by_name(name)?;
+        let buffer = read_all(&mut file)?;
+        return read_nested(&mut ZipArchive::new(Cursor::new(buffer))?, rest);
+    }
+    bail!("{} not found in archive", archive_path)
+}
+
+// Raw bytes of one entry, the summary loader takes care of the encoding
+#[inline(always)]
+pub fn read_entry(path: &Path, archive_path: &str) -> Result<Vec<u8>> {
+    read_nested(&mut ZipArchive::new(File::open(path)?)?, archive_path)
+}
+
+#[inline(always)]
+pub fn is_table(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    [".csv", ".txt", ".log", ".tsv"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+// True when the header of a csv has the columns every treatment summary has
+#[inline(always)]
+pub fn is_summary(bytes: &[u8]) -> bool {
+    let Ok((bytes, _)) = dialect::normalize(bytes, &DialectOverrides::default()) else {
+        return false;
+    };
+    let mut reader = csv::Reader::from_reader(bytes.as_slice());
+    let Ok(headers) = reader.headers() else {
+        return false;
+    };
+    let headers: HashSet<&str> = headers.iter().map(str::trim).collect();
+    SUMMARY_SIGNATURE.iter().all(|name| headers.contains(name))
+}
+
+// The treatment summary of an archive, found by its columns wherever it is stored
+#[inline(always)]
+pub fn find_summary(path: &Path) -> Result<Option<(String, Vec<u8>)>> {
+    Ok(search_summary(
+        &mut ZipArchive::new(File::open(path)?)?,
+        "",
+        0,
+    ))
+}
+
+#[inline(always)]
+fn is_usual_name(archive_path: &str) -> bool {
+    archive_path
+        .to_ascii_lowercase()
+        .ends_with("treatsummary.csv")
+}
+
+// Shallow entries win over nested ones and the usual file name over other names at the same
+// depth, so nested archives are only inflated when this one holds no summary. Unreadable
+// entries are left out so one broken file does not hide the summary.
+#[inline(always)]
+fn search_summary<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    archive_prefix: &str,
+    depth: usize,
+) -> Option<(String, Vec<u8>)> {
+    let mut found: Option<(String, Vec<u8>)> = None;
+    let mut nested = Vec::new();
+    for i in 0..archive.len() {
+        let Ok(mut file) = archive.by_index(i) else {
+            continue;
+        };
+        if file.is_dir() {
+            continue;
+        }
+        let archive_path = format!("{}{}", archive_prefix, file.name());
+        if is_archive(file.name()) {
+            if depth < MAX_DEPTH {
+                nested.push((i, archive_path));
+            }
+            continue;
+        }
+        if !is_table(&archive_path) {
+            continue;
+        }
+        let Ok(data) = read_all(&mut file) else {
+            continue;
+        };
+        if !is_summary(&data) {
+            continue;
+        }
+        let usual = is_usual_name(&archive_path);
+        if usual || found.is_none() {
+            found = Some((archive_path, data));
+        }
+        if usual {
+            break;
+        }
+    }
+    if found.is_some() {
+        return found;
+    }
+    nested.into_iter().find_map(|(i, archive_path)| {
+        let data = read_all(&mut archive.by_index(i).ok()?).ok()?;
+        let mut inner = ZipArchive::new(Cursor::new(data)).ok()?;
+        search_summary(&mut inner, &format!("{}/", archive_path), depth + 1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zip::{write::FileOptions, ZipWriter};
+
+    // ZIP holding the given entries in memory
+    #[inline(always)]
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[inline(always)]
+    fn read(bytes: Vec<u8>, options: &ExtractOptions) -> ExtractManifest {
+        read_archive(Cursor::new(bytes), options).unwrap()
+    }
+
+    #[inline(always)]
+    fn extracted(manifest: &ExtractManifest) -> Vec<(&str, PathBuf)> {
+        manifest
+            .extracted
+            .iter()
+            .map(|file| (file.archive_path.as_str(), file.path.clone()))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn skipped(manifest: &ExtractManifest) -> Vec<(&str, &str)> {
+        manifest
+            .skipped
+            .iter()
+            .map(|entry| (entry.archive_path.as_str(), entry.reason.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn output_path_layouts() {
+        let parents = [PathBuf::from("outer"), PathBuf::from("inner")];
+        let enclosed = Path::new("shots/a.bmp");
+        assert_eq!(
+            output_path(ExtractLayout::Preserve, &parents, enclosed),
+            Path::new("outer/inner/shots/a.bmp")
+        );
+        assert_eq!(
+            output_path(ExtractLayout::Prefix, &parents, enclosed),
+            Path::new("outer_inner_shots_a.bmp")
+        );
+        assert_eq!(
+            output_path(ExtractLayout::Prefix, &[], Path::new("a.bmp")),
+            Path::new("a.bmp")
+        );
+    }
+
+    #[test]
+    fn nested_archives_become_folders() {
+        let inner = zip(&[("b.bmp", b"b"), ("notes.txt", b"n")]);
+        let outer = zip(&[("a.bmp", b"a"), ("inner.zip", &inner)]);
+        let manifest = read(outer, &ExtractOptions::default());
+        assert_eq!(
+            extracted(&manifest),
+            vec![
+                ("a.bmp", PathBuf::from("a.bmp")),
+                ("inner.zip/b.bmp", PathBuf::from("inner/b.bmp")),
+            ]
+        );
+        assert_eq!(
+            skipped(&manifest),
+            vec![("inner.zip/notes.txt", "not matched by include filter")]
+        );
+    }
+
+    #[test]
+    fn excluded_archives_are_not_opened() {
+        let inner = zip(&[("b.bmp", b"b")]);
+        let outer = zip(&[("old.zip", &inner), ("new.zip", &inner)]);
+        let options = ExtractOptions {
+            exclude: vec!["old.zip".to_string()],
+            ..Default::default()
+        };
+        let manifest = read(outer, &options);
+        assert_eq!(
+            extracted(&manifest),
+            vec![("new.zip/b.bmp", PathBuf::from("new/b.bmp"))]
+        );
+        assert_eq!(skipped(&manifest), vec![("old.zip", "excluded by filter")]);
+    }
+
+    #[test]
+    fn nesting_is_limited_to_max_depth() {
+        let nest = |levels: usize| {
+            (0..levels).fold(zip(&[("a.bmp", b"a")]), |inner, _| {
+                zip(&[("n.zip", &inner)])
+            })
+        };
+        let manifest = read(nest(MAX_DEPTH), &ExtractOptions::default());
+        assert_eq!(manifest.extracted.len(), 1);
+        assert!(manifest.skipped.is_empty());
+
+        let manifest = read(nest(MAX_DEPTH + 1), &ExtractOptions::default());
+        assert!(manifest.extracted.is_empty());
+        let (archive_path, reason) = skipped(&manifest)[0];
+        assert_eq!(archive_path.matches("n.zip").count(), MAX_DEPTH + 1);
+        assert_eq!(reason, "nested deeper than 16 archives");
+    }
+
+    #[test]
+    fn duplicate_output_paths_keep_the_first_file() {
+        let outer = zip(&[("a/b.bmp", b"first"), ("a_b.bmp", b"second")]);
+        let options = ExtractOptions {
+            layout: ExtractLayout::Prefix,
+            ..Default::default()
+        };
+        let manifest = read(outer, &options);
+        assert_eq!(
+            extracted(&manifest),
+            vec![("a/b.bmp", PathBuf::from("a_b.bmp"))]
+        );
+        assert_eq!(&*manifest.extracted[0].data, b"first");
+        assert_eq!(
+            skipped(&manifest),
+            vec![("a_b.bmp", "duplicate output path a_b.bmp")]
+        );
+    }
+
+    #[test]
+    fn corrupt_nested_archives_are_skipped() {
+        let outer = zip(&[("broken.zip", b"not a zip"), ("a.bmp", b"a")]);
+        let manifest = read(outer, &ExtractOptions::default());
+        assert_eq!(manifest.extracted.len(), 1);
+        let (archive_path, reason) = skipped(&manifest)[0];
+        assert_eq!(archive_path, "broken.zip");
+        assert!(reason.starts_with("not a readable archive"), "{}", reason);
+    }
+
+    const SUMMARY: &[u8] = b"Time,Energy[J],Act. Energy[J],Num. of SubSonic\n1,2,3,4\n";
+
+    #[inline(always)]
+    fn summary_in(bytes: Vec<u8>) -> Option<String> {
+        search_summary(&mut ZipArchive::new(Cursor::new(bytes)).unwrap(), "", 0)
+            .map(|(archive_path, _)| archive_path)
+    }
+
+    #[test]
+    fn summary_is_found_by_columns_shallow_first() {
+        let inner = zip(&[("TreatSummary.csv", SUMMARY)]);
+        let outer = zip(&[
+            ("notes.txt", b"Time,Energy[J]\n1,2\n"),
+            ("nested.zip", &inner),
+            ("export.csv", SUMMARY),
+        ]);
+        assert_eq!(summary_in(outer).as_deref(), Some("export.csv"));
+        let outer = zip(&[("notes.txt", b"no summary"), ("nested.zip", &inner)]);
+        assert_eq!(
+            summary_in(outer).as_deref(),
+            Some("nested.zip/TreatSummary.csv")
+        );
+    }
+
+    #[test]
+    fn usual_summary_name_wins_at_the_same_depth() {
+        let outer = zip(&[
+            ("export.csv", SUMMARY),
+            ("data/TreatSummary.csv", SUMMARY),
+            ("later.csv", SUMMARY),
+        ]);
+        assert_eq!(summary_in(outer).as_deref(), Some("data/TreatSummary.csv"));
+        let outer = zip(&[("broken.zip", b"not a zip"), ("a.bmp", b"a")]);
+        assert_eq!(summary_in(outer), None);
+    }
+
+    #[test]
+    fn listing_records_unreadable_nested_archives() {
+        let inner = zip(&[("a.bmp", b"a")]);
+        let outer = zip(&[("broken.zip", b"not a zip"), ("inner.zip", &inner)]);
+        let mut entries = Vec::new();
+        walk_entries(
+            &mut ZipArchive::new(Cursor::new(outer)).unwrap(),
+            "",
+            0,
+            &mut entries,
+        )
+        .unwrap();
+        let listed: Vec<(&str, usize, bool)> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.archive_path.as_str(),
+                    entry.depth,
+                    entry.error.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                ("broken.zip", 0, true),
+                ("inner.zip", 0, false),
+                ("inner.zip/a.bmp", 1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_manifest_refuses_paths_outside_the_folder() {
+        let dir = std::env::temp_dir().join(format!("ejs-archive-{}", std::process::id()));
+        let file = |path: &str| ExtractedFile {
+            archive_path: path.to_string(),
+            path: PathBuf::from(path),
+            modified: None,
+            data: Arc::from(&b"x"[..]),
+        };
+        let manifest = ExtractManifest {
+            extracted: vec![file("../escaped.bmp")],
+            ..Default::default()
+        };
+        let error = write_manifest(&manifest, &dir, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "../escaped.bmp escapes the output folder"
+        );
+        assert!(!dir.join("manifest.csv").exists());
+
+        let manifest = ExtractManifest {
+            extracted: vec![file("shots/a.bmp")],
+            ..Default::default()
+        };
+        write_manifest(&manifest, &dir, None).unwrap();
+        assert_eq!(std::fs::read(dir.join("shots/a.bmp")).unwrap(), b"x");
+        assert!(dir.join("manifest.csv").is_file());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
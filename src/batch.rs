@@ -0,0 +1,394 @@
+use crate::{
+    archive::{self, ExtractOptions},
+    db::TreatmentDb,
+    export,
+    jobs::JobProgress,
+    summary,
+};
+use anyhow::{bail, Context, Result};
+use eframe::egui;
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+// Written last into every archive folder, its presence marks a finished extraction
+const MANIFEST: &str = "manifest.csv";
+const REPORT: &str = "batch_report.csv";
+
+#[derive(Clone, Debug)]
+pub struct BatchOptions {
+    // Leave archives alone that already have a finished output folder
+    pub skip_existing: bool,
+    // Also read each archive's TreatSummary into the database
+    pub load_summaries: bool,
+}
+
+impl Default for BatchOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            skip_existing: true,
+            load_summaries: false,
+        }
+    }
+}
+
+impl BatchOptions {
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.skip_existing, "Skip archives already extracted");
+        ui.checkbox(
+            &mut self.load_summaries,
+            "Store each treatment summary in the database",
+        );
+    }
+}
+
+enum Outcome {
+    Extracted {
+        images: usize,
+        files: usize,
+        // Sonications read from the summary, or why that failed
+        summary: Option<Result<usize, String>>,
+    },
+    Skipped,
+    Failed(String),
+}
+
+#[inline(always)]
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ["bmp", "png", "jpg", "jpeg"].contains(&ext.to_ascii_lowercase().as_str())
+        })
+}
+
+// Parse the summary of an archive, keep a simplified copy next to the images and store it
+#[inline(always)]
+fn load_summary(
+    path: &Path,
+    dest: &Path,
+    time_options: &summary::TimeOptions,
+    db: Option<&mut TreatmentDb>,
+) -> Result<usize> {
+    let (_, bytes) = archive::find_summary(path)?.context("no treatment summary in archive")?;
+    let (df, _) = summary::read_csv_bytes(bytes.clone(), time_options)?;
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("TreatSummary");
+    export::write_csv(
+        &df,
+        BufWriter::new(File::create(dest.join(format!("{}_simplify.csv", stem)))?),
+        &export::CsvOptions::default(),
+    )?;
+    if let Some(db) = db {
+        db.record_treatment(&path.display().to_string(), &bytes, &df)?;
+    }
+    Ok(df.height())
+}
+
+#[inline(always)]
+fn write_report(report: &Path, outcomes: &[(PathBuf, Outcome)]) -> Result<()> {
+    let mut wtr = csv::Writer::from_path(report)?;
+    wtr.write_record([
+        "archive",
+        "status",
+        "images",
+        "files",
+        "sonications",
+        "reason",
+    ])?;
+    for (path, outcome) in outcomes {
+        let name = path.display().to_string();
+        let record = match outcome {
+            Outcome::Extracted {
+                images,
+                files,
+                summary,
+            } => [
+                name,
+                "extracted".to_string(),
+                images.to_string(),
+                files.to_string(),
+                match summary {
+                    Some(Ok(rows)) => rows.to_string(),
+                    _ => String::new(),
+                },
+                match summary {
+                    Some(Err(e)) => format!("summary: {}", e),
+                    _ => String::new(),
+                },
+            ],
+            Outcome::Skipped => [
+                name,
+                "skipped".to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                "already extracted".to_string(),
+            ],
+            Outcome::Failed(reason) => [
+                name,
+                "failed".to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                reason.clone(),
+            ],
+        };
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Extract every ZIP directly inside dir_path into <dir_path>/extracted/<archive name>/
+#[inline(always)]
+pub fn extract_folder(
+    dir_path: &Path,
+    options: &ExtractOptions,
+    batch: &BatchOptions,
+    time_options: &summary::TimeOptions,
+    progress: &JobProgress,
+) -> Result<String> {
+    let out_dir = dir_path.join("extracted");
+    std::fs::create_dir_all(&out_dir).context("Failed to create directory")?;
+    let mut zips: Vec<PathBuf> = std::fs::read_dir(dir_path)
+        .context("Failed to read directory")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+        })
+        .collect();
+    zips.sort();
+    progress.set_total(zips.len());
+    // The UI keeps its own connection, SQLite handles both
+    let mut db = if batch.load_summaries {
+        Some(TreatmentDb::open(&TreatmentDb::default_path())?)
+    } else {
+        None
+    };
+
+    let mut outcomes = Vec::with_capacity(zips.len());
+    for path in zips {
+        progress.check()?;
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dest = out_dir.join(stem);
+        let outcome = if batch.skip_existing && dest.join(MANIFEST).exists() {
+            Outcome::Skipped
+        } else {
+            match archive::extract_zip(&path, &dest, options) {
+                Ok(manifest) => Outcome::Extracted {
+                    images: manifest
+                        .extracted
+                        .iter()
+                        .filter(|file| is_image(&file.path))
+                        .count(),
+                    files: manifest.extracted.len(),
+                    summary: batch.load_summaries.then(|| {
+                        load_summary(&path, &dest, time_options, db.as_mut())
+                            .map_err(|e| format!("{:#}", e))
+                    }),
+                },
+                Err(e) => Outcome::Failed(format!("{:#}", e)),
+            }
+        };
+        outcomes.push((path, outcome));
+        progress.inc();
+    }
+
+    let report = out_dir.join(REPORT);
+    write_report(&report, &outcomes)?;
+    let mut images = 0;
+    let mut extracted = 0;
+    let mut skipped = 0;
+    let mut failed = Vec::new();
+    for (path, outcome) in &outcomes {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match outcome {
+            Outcome::Extracted {
+                images: count,
+                summary,
+                ..
+            } => {
+                extracted += 1;
+                images += count;
+                if let Some(Err(e)) = summary {
+                    failed.push(format!("{} (summary): {}", name, e));
+                }
+            }
+            Outcome::Skipped => skipped += 1,
+            Outcome::Failed(reason) => failed.push(format!("{}: {}", name, reason)),
+        }
+    }
+    let totals = format!(
+        "{} images from {} archives, {} skipped as already extracted, report in {}",
+        images,
+        extracted,
+        skipped,
+        report.display()
+    );
+    if !failed.is_empty() {
+        bail!(
+            "{}\n{} problems:\n{}",
+            totals,
+            failed.len(),
+            failed.join("\n")
+        );
+    }
+    Ok(format!("Extracted {}", totals))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    #[inline(always)]
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    // good.zip, corrupt.zip, done.zip with a finished output folder and half.zip whose
+    // output folder has no manifest yet
+    #[inline(always)]
+    fn folder(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ejs-batch-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = zip(&[
+            ("a.bmp", b"a"),
+            ("sub/b.BMP", b"b"),
+            ("notes.txt", b"notes"),
+        ]);
+        std::fs::write(dir.join("good.zip"), &archive).unwrap();
+        std::fs::write(dir.join("corrupt.zip"), b"not a zip archive").unwrap();
+        std::fs::write(dir.join("done.zip"), &archive).unwrap();
+        std::fs::write(dir.join("half.zip"), &archive).unwrap();
+        std::fs::write(dir.join("readme.txt"), b"not an archive").unwrap();
+        let done = dir.join("extracted").join("done");
+        std::fs::create_dir_all(&done).unwrap();
+        std::fs::write(done.join(MANIFEST), b"").unwrap();
+        let half = dir.join("extracted").join("half");
+        std::fs::create_dir_all(&half).unwrap();
+        std::fs::write(half.join("a.bmp"), b"partial").unwrap();
+        dir
+    }
+
+    // Report rows without the archive folder
+    #[inline(always)]
+    fn report(dir: &Path) -> Vec<Vec<String>> {
+        csv::Reader::from_path(dir.join("extracted").join(REPORT))
+            .unwrap()
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                let mut row: Vec<String> = record.iter().map(str::to_string).collect();
+                row[0] = Path::new(&row[0])
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                row
+            })
+            .collect()
+    }
+
+    #[inline(always)]
+    fn run(dir: &Path, skip_existing: bool) -> Result<String> {
+        let batch = BatchOptions {
+            skip_existing,
+            load_summaries: false,
+        };
+        extract_folder(
+            dir,
+            &ExtractOptions::default(),
+            &batch,
+            &summary::TimeOptions::default(),
+            &JobProgress::default(),
+        )
+    }
+
+    #[test]
+    fn reports_every_archive() {
+        let dir = folder("report");
+        let result = run(&dir, true);
+        let rows = report(&dir);
+        let half = std::fs::read(dir.join("extracted").join("half").join("a.bmp")).unwrap();
+        let good_manifest = dir.join("extracted").join("good").join(MANIFEST).is_file();
+        let done_files = std::fs::read_dir(dir.join("extracted").join("done"))
+            .unwrap()
+            .count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let message = result.unwrap_err().to_string();
+        assert!(message.starts_with("4 images from 2 archives, 1 skipped as already extracted"));
+        assert!(message.contains("1 problems:\ncorrupt.zip: "));
+        let status: Vec<[&str; 5]> = rows
+            .iter()
+            .map(|row| [&row[0], &row[1], &row[2], &row[3], &row[4]].map(String::as_str))
+            .collect();
+        assert_eq!(
+            status,
+            vec![
+                ["corrupt.zip", "failed", "", "", ""],
+                ["done.zip", "skipped", "", "", ""],
+                ["good.zip", "extracted", "2", "2", ""],
+                ["half.zip", "extracted", "2", "2", ""],
+            ]
+        );
+        assert!(!rows[0][5].is_empty());
+        assert_eq!(rows[1][5], "already extracted");
+        assert_eq!(rows[2][5], "");
+        // Without a manifest the folder is extracted again
+        assert_eq!(half, b"a");
+        assert!(good_manifest);
+        assert_eq!(done_files, 1);
+    }
+
+    #[test]
+    fn extracts_everything_without_skip_existing() {
+        let dir = folder("all");
+        let result = run(&dir, false);
+        let rows = report(&dir);
+        let done = std::fs::read(dir.join("extracted").join("done").join("a.bmp"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("6 images from 3 archives, 0 skipped"));
+        assert_eq!(rows[1][1], "extracted");
+        assert_eq!(done.unwrap(), b"a");
+    }
+
+    #[test]
+    fn succeeds_when_nothing_fails() {
+        let dir = std::env::temp_dir().join(format!("ejs-batch-{}-clean", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good.zip"), zip(&[("a.bmp", b"a")])).unwrap();
+        let result = run(&dir, true);
+        let rows = report(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result
+            .unwrap()
+            .starts_with("Extracted 1 images from 1 archives, 0 skipped"));
+        assert_eq!(rows.len(), 1);
+    }
+}
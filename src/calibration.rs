@@ -0,0 +1,513 @@
+use crate::{
+    compare::Treatment,
+    steering::{self, EfficiencyProfile},
+    summary,
+};
+use eframe::egui::{self, Color32, Grid, RichText};
+use egui_plot::{Legend, Line, LineStyle, Plot, PlotPoints, Points};
+use std::path::PathBuf;
+
+// Two-sided 95 % normal quantile used for the confidence bands
+const Z_95: f64 = 1.96;
+// Spacing of the knots written for a parametric fit [mm]
+const KNOT_STEP: f64 = 5.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FitMethod {
+    // Mean efficiency per distance bin, the bin means become the knots
+    Binned,
+    // Least squares efficiency = a + b d + c d²
+    Quadratic,
+}
+
+struct CohortMember {
+    name: String,
+    treatment: Option<Treatment>,
+    natural_focus: [f64; 3],
+    // (steering distance [mm], Act. Energy / Energy [%]) per sonication
+    samples: Vec<(f64, f64)>,
+    error: Option<String>,
+}
+
+impl CohortMember {
+    #[inline(always)]
+    fn update_samples(&mut self) {
+        let Some(treatment) = &self.treatment else {
+            return;
+        };
+        (self.samples, self.error) = match samples(treatment, &self.natural_focus) {
+            Ok(samples) => (samples, None),
+            Err(e) => (Vec::new(), Some(e.to_string())),
+        };
+    }
+}
+
+#[derive(Default)]
+struct Fit {
+    // Distance, fitted efficiency and half width of the 95 % band
+    curve: Vec<(f64, f64, f64)>,
+    knots: Vec<(f64, f64)>,
+    residual_sd: f64,
+    n: usize,
+}
+
+// Observed efficiency of every sonication with a planned energy
+#[inline(always)]
+fn samples(treatment: &Treatment, natural_focus: &[f64; 3]) -> Result<Vec<(f64, f64)>, String> {
+    let distances =
+        steering::steering_distances(&treatment.df, natural_focus).ok_or("no focal RAS columns")?;
+    let planned = summary::float_values(&treatment.df, "Energy[J]").ok_or("no Energy[J] column")?;
+    let actual =
+        summary::float_values(&treatment.df, "Act. Energy[J]").ok_or("no Act. Energy[J] column")?;
+    Ok(distances
+        .iter()
+        .zip(planned.iter().zip(&actual))
+        .filter_map(|(distance, (planned, actual))| {
+            let (distance, planned, actual) = ((*distance)?, (*planned)?, (*actual)?);
+            (planned > 0.0).then(|| (distance, actual / planned * 100.0))
+        })
+        .collect())
+}
+
+#[inline(always)]
+fn fit_binned(points: &[(f64, f64)], width: f64) -> Fit {
+    let mut bins: Vec<(i64, Vec<(f64, f64)>)> = Vec::new();
+    for &(distance, efficiency) in points {
+        let bin = (distance / width).floor() as i64;
+        match bins.iter_mut().find(|(b, _)| *b == bin) {
+            Some((_, members)) => members.push((distance, efficiency)),
+            None => bins.push((bin, vec![(distance, efficiency)])),
+        }
+    }
+    bins.sort_by_key(|(bin, _)| *bin);
+    let mut fit = Fit {
+        n: points.len(),
+        ..Fit::default()
+    };
+    let mut squares = 0.0;
+    for (_, members) in &bins {
+        let n = members.len() as f64;
+        let distance = members.iter().map(|(d, _)| d).sum::<f64>() / n;
+        let mean = members.iter().map(|(_, e)| e).sum::<f64>() / n;
+        let ss: f64 = members.iter().map(|(_, e)| (e - mean).powi(2)).sum();
+        squares += ss;
+        // A single sonication gives no spread, its band is left empty
+        let half = if members.len() > 1 {
+            Z_95 * (ss / (n - 1.0)).sqrt() / n.sqrt()
+        } else {
+            0.0
+        };
+        fit.curve.push((distance, mean, half));
+        fit.knots.push((distance, mean));
+    }
+    let dof = points.len().saturating_sub(bins.len());
+    fit.residual_sd = if dof > 0 {
+        (squares / dof as f64).sqrt()
+    } else {
+        0.0
+    };
+    fit
+}
+
+// Inverse of a symmetric 3x3 matrix, None when it is singular
+#[inline(always)]
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let det = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum::<f64>();
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut inverse = [[0.0; 3]; 3];
+    for (r, row) in inverse.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = cofactor(c, r) / det;
+        }
+    }
+    Some(inverse)
+}
+
+#[inline(always)]
+fn fit_quadratic(points: &[(f64, f64)]) -> Option<Fit> {
+    if points.len() < 4 {
+        return None;
+    }
+    let basis = |d: f64| [1.0, d, d * d];
+    let mut xtx = [[0.0; 3]; 3];
+    let mut xty = [0.0; 3];
+    for &(distance, efficiency) in points {
+        let x = basis(distance);
+        for r in 0..3 {
+            xty[r] += x[r] * efficiency;
+            for c in 0..3 {
+                xtx[r][c] += x[r] * x[c];
+            }
+        }
+    }
+    let inverse = invert(xtx)?;
+    let coefficients: Vec<f64> = (0..3)
+        .map(|r| (0..3).map(|c| inverse[r][c] * xty[c]).sum())
+        .collect();
+    let predict = |d: f64| {
+        basis(d)
+            .iter()
+            .zip(&coefficients)
+            .map(|(x, b)| x * b)
+            .sum::<f64>()
+    };
+    let squares: f64 = points.iter().map(|(d, e)| (e - predict(*d)).powi(2)).sum();
+    let residual_sd = (squares / (points.len() - 3) as f64).sqrt();
+    let max = points.iter().map(|(d, _)| *d).fold(0.0, f64::max);
+    let mut fit = Fit {
+        residual_sd,
+        n: points.len(),
+        ..Fit::default()
+    };
+    let steps = (max / KNOT_STEP).ceil() as usize;
+    for step in 0..=steps {
+        let distance = (step as f64 * KNOT_STEP).min(max);
+        let x = basis(distance);
+        let leverage: f64 = (0..3)
+            .map(|r| (0..3).map(|c| x[r] * inverse[r][c] * x[c]).sum::<f64>())
+            .sum();
+        let efficiency = predict(distance);
+        fit.curve
+            .push((distance, efficiency, Z_95 * residual_sd * leverage.sqrt()));
+        fit.knots.push((distance, efficiency.max(0.0)));
+    }
+    fit.knots.dedup_by(|a, b| a.0 == b.0);
+    Some(fit)
+}
+
+pub enum CalibrationAction {
+    UseProfile(EfficiencyProfile),
+    Error(String),
+}
+
+pub struct Calibration {
+    cohort: Vec<CohortMember>,
+    method: FitMethod,
+    bin_width: f64,
+    name: String,
+    fit: Option<Fit>,
+}
+
+impl Default for Calibration {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            cohort: Vec::new(),
+            method: FitMethod::Binned,
+            bin_width: 10.0,
+            name: "Calibrated".to_string(),
+            fit: None,
+        }
+    }
+}
+
+impl Calibration {
+    #[inline(always)]
+    fn add(&mut self, paths: Vec<PathBuf>, options: &summary::TimeOptions, focus: [f64; 3]) {
+        for path in paths {
+            let mut member = match Treatment::load(&path, options) {
+                Ok(treatment) => CohortMember {
+                    name: treatment.name.clone(),
+                    treatment: Some(treatment),
+                    natural_focus: focus,
+                    samples: Vec::new(),
+                    error: None,
+                },
+                Err(e) => CohortMember {
+                    name: path.display().to_string(),
+                    treatment: None,
+                    natural_focus: focus,
+                    samples: Vec::new(),
+                    error: Some(format!("{:#}", e)),
+                },
+            };
+            member.update_samples();
+            self.cohort.push(member);
+        }
+    }
+
+    #[inline(always)]
+    fn points(&self) -> Vec<(f64, f64)> {
+        self.cohort
+            .iter()
+            .flat_map(|member| member.samples.iter().copied())
+            .collect()
+    }
+
+    #[inline(always)]
+    fn refit(&mut self) {
+        let points = self.points();
+        self.fit = match self.method {
+            _ if points.is_empty() => None,
+            FitMethod::Binned => Some(fit_binned(&points, self.bin_width)),
+            FitMethod::Quadratic => fit_quadratic(&points),
+        };
+    }
+
+    #[inline(always)]
+    fn profile(&self) -> Option<EfficiencyProfile> {
+        let fit = self.fit.as_ref()?;
+        (fit.knots.len() >= 2).then(|| EfficiencyProfile {
+            name: self.name.clone(),
+            knots: fit.knots.clone(),
+        })
+    }
+
+    #[inline(always)]
+    fn show_cohort(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        let mut remove = None;
+        Grid::new("calibration_cohort")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.strong("");
+                ui.strong("Treatment");
+                ui.strong("Natural focus R / A / S");
+                ui.strong("Sonications");
+                ui.end_row();
+                for (idx, member) in self.cohort.iter_mut().enumerate() {
+                    if ui.small_button("✖").clicked() {
+                        remove = Some(idx);
+                    }
+                    ui.label(&member.name);
+                    ui.horizontal(|ui| {
+                        let mut moved = false;
+                        for value in member.natural_focus.iter_mut() {
+                            moved |= ui.add(egui::DragValue::new(value).speed(0.1)).changed();
+                        }
+                        // The natural focus moves every distance of this treatment
+                        if moved {
+                            member.update_samples();
+                            changed = true;
+                        }
+                    });
+                    match &member.error {
+                        Some(e) => ui.colored_label(Color32::RED, e),
+                        None => ui.label(member.samples.len().to_string()),
+                    };
+                    ui.end_row();
+                }
+            });
+        if let Some(idx) = remove {
+            self.cohort.remove(idx);
+            changed = true;
+        }
+        changed
+    }
+
+    #[inline(always)]
+    fn show_plot(&self, ui: &mut egui::Ui, current: &EfficiencyProfile) {
+        let points: Vec<[f64; 2]> = self.points().iter().map(|(d, e)| [*d, *e]).collect();
+        let max = points.iter().map(|p| p[0]).fold(75.0, f64::max);
+        let current_curve: PlotPoints = (0..=100)
+            .map(|i| {
+                let d = max * i as f64 / 100.0;
+                [d, current.sample(d)]
+            })
+            .collect();
+        Plot::new("calibration_plot")
+            .height(400.0)
+            .legend(Legend::default())
+            .x_axis_label("Steering distance [mm]")
+            .y_axis_label("Act. / planned energy [%]")
+            .show(ui, |plot_ui| {
+                plot_ui.points(Points::new(points).radius(2.0).name("Sonications"));
+                plot_ui.line(
+                    Line::new(current_curve)
+                        .style(LineStyle::dashed_loose())
+                        .name(format!("Current profile ({})", current.name)),
+                );
+                if let Some(fit) = &self.fit {
+                    let curve = |offset: f64| -> PlotPoints {
+                        fit.curve
+                            .iter()
+                            .map(|(d, e, half)| [*d, e + offset * half])
+                            .collect()
+                    };
+                    plot_ui.line(Line::new(curve(0.0)).width(2.0).name("Fit"));
+                    plot_ui.line(
+                        Line::new(curve(1.0))
+                            .style(LineStyle::dotted_dense())
+                            .name("95 % band"),
+                    );
+                    plot_ui.line(
+                        Line::new(curve(-1.0))
+                            .style(LineStyle::dotted_dense())
+                            .name("95 % band"),
+                    );
+                }
+            });
+    }
+
+    #[inline(always)]
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        options: &summary::TimeOptions,
+        natural_focus: [f64; 3],
+        current: &EfficiencyProfile,
+    ) -> Option<CalibrationAction> {
+        let mut action = None;
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            if ui.button("Add treatments").clicked() {
+                if let Some(paths) = rfd::FileDialog::new()
+                    .add_filter("Treatment summary", &["csv", "zip"])
+                    .pick_files()
+                {
+                    self.add(paths, options, natural_focus);
+                    changed = true;
+                }
+            }
+            if ui.button("Clear").clicked() {
+                self.cohort.clear();
+                changed = true;
+            }
+        });
+        changed |= self.show_cohort(ui);
+        ui.separator();
+        ui.horizontal(|ui| {
+            changed |= ui
+                .radio_value(&mut self.method, FitMethod::Binned, "Binned knots")
+                .changed();
+            changed |= ui
+                .radio_value(&mut self.method, FitMethod::Quadratic, "Quadratic")
+                .changed();
+            if self.method == FitMethod::Binned {
+                changed |= ui
+                    .add(
+                        egui::DragValue::new(&mut self.bin_width)
+                            .clamp_range(1.0..=50.0)
+                            .prefix("Bin width ")
+                            .suffix(" mm"),
+                    )
+                    .changed();
+            }
+        });
+        if changed {
+            self.refit();
+        }
+        match &self.fit {
+            Some(fit) => {
+                ui.label(format!(
+                    "{} sonications, residual SD {:.2} %, {} knots",
+                    fit.n,
+                    fit.residual_sd,
+                    fit.knots.len()
+                ));
+            }
+            None if !self.cohort.is_empty() => {
+                ui.label("Not enough sonications to fit a curve");
+            }
+            None => {}
+        }
+        self.show_plot(ui, current);
+        if let Some(profile) = self.profile() {
+            ui.horizontal(|ui| {
+                ui.label("Profile name");
+                ui.text_edit_singleline(&mut self.name);
+                if ui
+                    .button(RichText::new("Use in calculator").strong())
+                    .clicked()
+                {
+                    action = Some(CalibrationAction::UseProfile(profile.clone()));
+                }
+                if ui.button("Save profile").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Efficiency profile", &["csv"])
+                        .set_file_name(format!("{}.csv", self.name))
+                        .save_file()
+                    {
+                        action = Some(match profile.save(&path) {
+                            Ok(()) => CalibrationAction::UseProfile(EfficiencyProfile {
+                                name: path
+                                    .file_stem()
+                                    .map(|stem| stem.to_string_lossy().into_owned())
+                                    .unwrap_or(profile.name),
+                                ..profile
+                            }),
+                            Err(e) => {
+                                CalibrationAction::Error(format!("Could not save profile: {}", e))
+                            }
+                        });
+                    }
+                }
+            });
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invert_matches_identity_and_rejects_singular() {
+        let m = [[4.0, 2.0, 0.0], [2.0, 3.0, 1.0], [0.0, 1.0, 2.0]];
+        let inverse = invert(m).unwrap();
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        for (r, row) in identity.iter().enumerate() {
+            for (c, expected) in row.iter().enumerate() {
+                let product: f64 = (0..3).map(|k| m[r][k] * inverse[k][c]).sum();
+                assert!((product - expected).abs() < 1e-9);
+            }
+        }
+        let singular = [[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 1.0, 1.0]];
+        assert!(invert(singular).is_none());
+    }
+
+    #[test]
+    fn quadratic_recovers_exact_curve() {
+        let points: Vec<(f64, f64)> = (0..=8)
+            .map(|d| {
+                let d = d as f64 * 2.5;
+                (d, 95.0 - 0.5 * d - 0.02 * d * d)
+            })
+            .collect();
+        let fit = fit_quadratic(&points).unwrap();
+        assert_eq!(fit.n, 9);
+        assert!(fit.residual_sd < 1e-9);
+        // Knots every 5 mm up to the largest distance
+        let distances: Vec<f64> = fit.knots.iter().map(|(d, _)| *d).collect();
+        assert_eq!(distances, vec![0.0, 5.0, 10.0, 15.0, 20.0]);
+        for (distance, efficiency) in &fit.knots {
+            let expected = 95.0 - 0.5 * distance - 0.02 * distance * distance;
+            assert!((efficiency - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn quadratic_needs_four_spread_points() {
+        assert!(fit_quadratic(&[(0.0, 90.0), (5.0, 85.0), (10.0, 80.0)]).is_none());
+        // Every point at the same distance gives a singular system
+        assert!(fit_quadratic(&[(5.0, 90.0); 5]).is_none());
+    }
+
+    #[test]
+    fn quadratic_knots_are_not_negative() {
+        let points = [(0.0, 10.0), (5.0, 5.0), (10.0, 0.0), (12.0, -2.0)];
+        let fit = fit_quadratic(&points).unwrap();
+        assert!(fit.knots.iter().all(|(_, efficiency)| *efficiency >= 0.0));
+        assert_eq!(fit.knots.last().unwrap().0, 12.0);
+    }
+
+    #[test]
+    fn binned_means_and_spread() {
+        let points = [(1.0, 90.0), (2.0, 94.0), (7.0, 80.0)];
+        let fit = fit_binned(&points, 5.0);
+        assert_eq!(fit.n, 3);
+        assert_eq!(fit.knots, vec![(1.5, 92.0), (7.0, 80.0)]);
+        // The single sonication in the second bin has no band
+        assert_eq!(fit.curve[1].2, 0.0);
+        assert!(fit.curve[0].2 > 0.0);
+        assert!((fit.residual_sd - 8f64.sqrt()).abs() < 1e-9);
+    }
+}
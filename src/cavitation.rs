@@ -0,0 +1,322 @@
+use crate::summary;
+use eframe::egui::{self, Color32, Grid, RichText};
+use egui_plot::{Bar, BarChart, HLine, Legend, Plot, Points};
+use polars::prelude::*;
+use std::collections::HashMap;
+
+pub const CAV_DOSE_COLUMN: &str = "Target Cav.Dose";
+
+const WARN_COLOR: Color32 = Color32::from_rgb(200, 170, 0);
+const HIGH_COLOR: Color32 = Color32::from_rgb(170, 60, 200);
+
+// Pearson correlation of the rows where both values are present
+#[inline(always)]
+fn correlation(x: &[Option<f64>], y: &[Option<f64>]) -> Option<f64> {
+    let pairs: Vec<(f64, f64)> = x
+        .iter()
+        .zip(y)
+        .filter_map(|(x, y)| Some(((*x)?, (*y)?)))
+        .collect();
+    if pairs.len() < 3 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (x, y) in &pairs {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+    let r = cov / (var_x * var_y).sqrt();
+    r.is_finite().then_some(r)
+}
+
+// First numeric column that holds the sonication power
+#[inline(always)]
+fn power_column(df: &DataFrame) -> Option<String> {
+    df.get_columns()
+        .iter()
+        .filter(|series| series.dtype().is_numeric())
+        .map(|series| series.name())
+        .find(|name| name.to_lowercase().contains("power"))
+        .map(str::to_string)
+}
+
+pub struct CavitationAnalysis {
+    // Sonications at or above these doses are highlighted
+    pub warn: f64,
+    pub high: f64,
+    doses: Vec<Option<f64>>,
+    energy: Vec<Option<f64>>,
+    // No actual energy was logged, the planned energy is correlated instead
+    planned_energy: bool,
+    power: Option<(String, Vec<Option<f64>>)>,
+}
+
+impl Default for CavitationAnalysis {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            warn: 50.0,
+            high: 100.0,
+            doses: Vec::new(),
+            energy: Vec::new(),
+            planned_energy: false,
+            power: None,
+        }
+    }
+}
+
+impl CavitationAnalysis {
+    #[inline(always)]
+    fn energy_label(&self) -> &'static str {
+        if self.planned_energy {
+            "planned energy [J]"
+        } else {
+            "delivered energy [J]"
+        }
+    }
+
+    #[inline(always)]
+    pub fn analyse(&mut self, df: &DataFrame) {
+        self.doses = summary::float_values(df, CAV_DOSE_COLUMN).unwrap_or_default();
+        (self.energy, self.planned_energy) = summary::sonication_energy(df).unwrap_or_default();
+        self.power = power_column(df).and_then(|name| {
+            let values = summary::float_values(df, &name)?;
+            Some((name, values))
+        });
+    }
+
+    #[inline(always)]
+    fn level(&self, dose: f64) -> Option<(Color32, &'static str)> {
+        if dose >= self.high {
+            Some((HIGH_COLOR, "High cavitation"))
+        } else if dose >= self.warn {
+            Some((WARN_COLOR, "Elevated cavitation"))
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn highlights(&self) -> HashMap<usize, (Color32, String)> {
+        self.doses
+            .iter()
+            .enumerate()
+            .filter_map(|(row, dose)| {
+                let dose = (*dose)?;
+                let (color, label) = self.level(dose)?;
+                Some((row, (color, format!("{}: dose {:.2}", label, dose))))
+            })
+            .collect()
+    }
+
+    #[inline(always)]
+    fn show_statistics(&self, ui: &mut egui::Ui) {
+        let present: Vec<f64> = self.doses.iter().flatten().copied().collect();
+        let total: f64 = present.iter().sum();
+        let max = present.iter().copied().fold(f64::NAN, f64::max);
+        let above = |limit: f64| present.iter().filter(|dose| **dose >= limit).count();
+        let format_r = |r: Option<f64>| {
+            r.map(|r| format!("{:.2}", r))
+                .unwrap_or_else(|| "n/a".to_string())
+        };
+        Grid::new("cavitation_statistics")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("Sonications with dose");
+                ui.label(present.len().to_string());
+                ui.end_row();
+                ui.label("Total dose");
+                ui.label(format!("{:.2}", total));
+                ui.end_row();
+                ui.label("Mean dose");
+                ui.label(if present.is_empty() {
+                    String::new()
+                } else {
+                    format!("{:.2}", total / present.len() as f64)
+                });
+                ui.end_row();
+                ui.label("Max dose");
+                ui.label(if present.is_empty() {
+                    String::new()
+                } else {
+                    format!("{:.2}", max)
+                });
+                ui.end_row();
+                ui.colored_label(WARN_COLOR, format!("≥ {:.2}", self.warn));
+                ui.label(above(self.warn).to_string());
+                ui.end_row();
+                ui.colored_label(HIGH_COLOR, format!("≥ {:.2}", self.high));
+                ui.label(above(self.high).to_string());
+                ui.end_row();
+                ui.label(format!("r with {}", self.energy_label()));
+                ui.label(format_r(correlation(&self.doses, &self.energy)));
+                ui.end_row();
+                if let Some((name, power)) = &self.power {
+                    ui.label(format!("r with {}", name));
+                    ui.label(format_r(correlation(&self.doses, power)));
+                    ui.end_row();
+                }
+            });
+    }
+
+    // Returns true when a threshold changed and the highlights need to be updated
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui) -> bool {
+        if self.doses.is_empty() {
+            ui.label(format!("This export has no \"{}\" column", CAV_DOSE_COLUMN));
+            return false;
+        }
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut self.warn)
+                        .speed(0.5)
+                        .prefix("Elevated from "),
+                )
+                .changed();
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut self.high)
+                        .speed(0.5)
+                        .prefix("High from "),
+                )
+                .changed();
+        });
+        if self.high < self.warn {
+            self.high = self.warn;
+        }
+        ui.horizontal_top(|ui| {
+            self.show_statistics(ui);
+            ui.vertical(|ui| {
+                ui.label(RichText::new("Cavitation dose per sonication").strong());
+                let bars: Vec<Bar> = self
+                    .doses
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(row, dose)| {
+                        let dose = (*dose)?;
+                        let bar = Bar::new(row as f64 + 1.0, dose).width(0.8);
+                        Some(match self.level(dose) {
+                            Some((color, _)) => bar.fill(color),
+                            None => bar,
+                        })
+                    })
+                    .collect();
+                Plot::new("cavitation_dose")
+                    .height(200.0)
+                    .width(400.0)
+                    .x_axis_label("Sonication")
+                    .show(ui, |plot_ui| {
+                        plot_ui.bar_chart(BarChart::new(bars).name("Cav. dose"));
+                        plot_ui.hline(HLine::new(self.warn).color(WARN_COLOR));
+                        plot_ui.hline(HLine::new(self.high).color(HIGH_COLOR));
+                    });
+            });
+            ui.vertical(|ui| {
+                ui.label(RichText::new("Dose vs energy and power").strong());
+                let points = |x: &[Option<f64>]| -> Vec<[f64; 2]> {
+                    x.iter()
+                        .zip(&self.doses)
+                        .filter_map(|(x, y)| Some([(*x)?, (*y)?]))
+                        .collect()
+                };
+                Plot::new("cavitation_correlation")
+                    .height(200.0)
+                    .width(400.0)
+                    .legend(Legend::default())
+                    .y_axis_label("Cav. dose")
+                    .show(ui, |plot_ui| {
+                        plot_ui.points(
+                            Points::new(points(&self.energy))
+                                .radius(3.0)
+                                .name(self.energy_label()),
+                        );
+                        if let Some((name, power)) = &self.power {
+                            plot_ui.points(Points::new(points(power)).radius(3.0).name(name));
+                        }
+                    });
+            });
+        });
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn analysis(doses: &[Option<f64>]) -> CavitationAnalysis {
+        let df = df!(
+            CAV_DOSE_COLUMN => doses,
+            "Act. Energy[J]" => &vec![Some(1000.0); doses.len()]
+        )
+        .unwrap();
+        let mut analysis = CavitationAnalysis::default();
+        analysis.analyse(&df);
+        analysis
+    }
+
+    #[test]
+    fn correlation_needs_three_pairs() {
+        let x = [Some(1.0), Some(2.0), None, Some(4.0)];
+        let y = [Some(2.0), Some(4.0), Some(6.0), None];
+        assert_eq!(correlation(&x, &y), None);
+        let y = [Some(2.0), Some(4.0), Some(6.0), Some(8.0)];
+        let x = [Some(1.0), Some(2.0), Some(3.0), Some(4.0)];
+        assert!((correlation(&x, &y).unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn correlation_of_a_constant_is_none() {
+        let x = [Some(5.0), Some(5.0), Some(5.0)];
+        let y = [Some(1.0), Some(2.0), Some(3.0)];
+        assert_eq!(correlation(&x, &y), None);
+        assert_eq!(correlation(&y, &x), None);
+    }
+
+    #[test]
+    fn negative_correlation() {
+        let x = [Some(1.0), Some(2.0), Some(3.0)];
+        let y = [Some(3.0), Some(2.0), Some(1.0)];
+        assert!((correlation(&x, &y).unwrap() + 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn highlights_start_at_the_thresholds() {
+        let analysis = analysis(&[Some(49.99), Some(50.0), Some(99.99), Some(100.0), None]);
+        let highlights = analysis.highlights();
+        assert_eq!(highlights.len(), 3);
+        assert!(!highlights.contains_key(&0));
+        assert_eq!(highlights[&1].0, WARN_COLOR);
+        assert_eq!(highlights[&1].1, "Elevated cavitation: dose 50.00");
+        assert_eq!(highlights[&2].0, WARN_COLOR);
+        assert_eq!(highlights[&3].0, HIGH_COLOR);
+        assert_eq!(highlights[&3].1, "High cavitation: dose 100.00");
+        assert!(!highlights.contains_key(&4));
+    }
+
+    #[test]
+    fn no_highlights_without_a_dose_column() {
+        let df = df!("Act. Energy[J]" => &[1000.0]).unwrap();
+        let mut analysis = CavitationAnalysis::default();
+        analysis.analyse(&df);
+        assert!(analysis.highlights().is_empty());
+    }
+
+    #[test]
+    fn power_column_is_the_first_numeric_match() {
+        let df = df!(
+            "Power mode" => &["fixed"],
+            "Act. Power[W]" => &[50.0],
+            "Power[W]" => &[60.0]
+        )
+        .unwrap();
+        assert_eq!(power_column(&df).as_deref(), Some("Act. Power[W]"));
+    }
+}
@@ -0,0 +1,565 @@
+use crate::{gallery::Snapshot, jobs::JobProgress, redaction::Redaction};
+use anyhow::{bail, Result};
+use chrono::NaiveDateTime;
+use eframe::egui::{self, RichText};
+use image::{
+    codecs::{
+        gif::{GifEncoder, Repeat},
+        jpeg::JpegEncoder,
+    },
+    imageops::FilterType,
+    Delay, Rgba, RgbaImage,
+};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// 3x5 glyphs, one row per byte with the leftmost pixel in bit 2
+const GLYPHS: [(char, [u8; 5]); 17] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CineFormat {
+    #[default]
+    Gif,
+    Apng,
+    // Motion JPEG in an AVI container, plays in most presentation software
+    Avi,
+}
+
+impl CineFormat {
+    pub const ALL: [CineFormat; 3] = [CineFormat::Gif, CineFormat::Apng, CineFormat::Avi];
+
+    #[inline(always)]
+    pub fn label(self) -> &'static str {
+        match self {
+            CineFormat::Gif => "Animated GIF",
+            CineFormat::Apng => "Animated PNG",
+            CineFormat::Avi => "MJPEG AVI",
+        }
+    }
+
+    #[inline(always)]
+    pub fn extension(self) -> &'static str {
+        match self {
+            CineFormat::Gif => "gif",
+            CineFormat::Apng => "png",
+            CineFormat::Avi => "avi",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CineScope {
+    #[default]
+    Treatment,
+    SelectedSonication,
+}
+
+impl CineScope {
+    #[inline(always)]
+    fn includes(self, snapshot: &Snapshot, selected_row: Option<usize>) -> bool {
+        match self {
+            CineScope::Treatment => true,
+            CineScope::SelectedSonication => selected_row.is_some() && snapshot.row == selected_row,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CineOptions {
+    pub format: CineFormat,
+    pub fps: f64,
+    pub scope: CineScope,
+    pub burn_sonication: bool,
+    pub burn_time: bool,
+    // Regions blacked out before the frame is scaled
+    pub redaction: Option<Redaction>,
+}
+
+impl Default for CineOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            format: CineFormat::default(),
+            fps: 2.0,
+            scope: CineScope::default(),
+            burn_sonication: true,
+            burn_time: true,
+            redaction: None,
+        }
+    }
+}
+
+pub struct CineFrame {
+    pub data: Arc<[u8]>,
+    pub time: Option<NaiveDateTime>,
+    pub row: Option<usize>,
+}
+
+// Snapshots in scope ordered by capture time, those without a time go last
+#[inline(always)]
+pub fn select_frames(
+    snapshots: &[Snapshot],
+    scope: CineScope,
+    selected_row: Option<usize>,
+) -> Vec<CineFrame> {
+    let mut frames: Vec<CineFrame> = snapshots
+        .iter()
+        .filter(|snapshot| scope.includes(snapshot, selected_row))
+        .map(|snapshot| CineFrame {
+            data: snapshot.data.clone(),
+            time: snapshot.time,
+            row: snapshot.row,
+        })
+        .collect();
+    frames.sort_by_key(|frame| (frame.time.is_none(), frame.time));
+    frames
+}
+
+#[inline(always)]
+fn fill_rect(image: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>) {
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            image.put_pixel(px, py, color);
+        }
+    }
+}
+
+// White text on a black box in the top left corner
+#[inline(always)]
+fn burn_text(image: &mut RgbaImage, text: &str) {
+    let scale = (image.height() / 160).max(1);
+    let advance = 4 * scale;
+    let margin = 2 * scale;
+    fill_rect(
+        image,
+        0,
+        0,
+        text.chars().count() as u32 * advance + 2 * margin,
+        5 * scale + 2 * margin,
+        Rgba([0, 0, 0, 255]),
+    );
+    for (idx, c) in text.chars().enumerate() {
+        let Some((_, rows)) = GLYPHS.iter().find(|(glyph, _)| *glyph == c) else {
+            continue;
+        };
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill_rect(
+                        image,
+                        margin + idx as u32 * advance + col * scale,
+                        margin + row as u32 * scale,
+                        scale,
+                        scale,
+                        Rgba([255, 255, 255, 255]),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn overlay(frame: &CineFrame, options: &CineOptions) -> String {
+    let mut parts = Vec::new();
+    if options.burn_sonication {
+        if let Some(row) = frame.row {
+            parts.push(format!("SON. {}", row + 1));
+        }
+    }
+    if options.burn_time {
+        if let Some(time) = frame.time {
+            parts.push(time.format("%Y-%m-%d %H:%M:%S").to_string());
+        }
+    }
+    parts.join("  ")
+}
+
+// Decode and redact a frame, scale it to the size of the first one and burn in the overlay
+#[inline(always)]
+fn render(frame: &CineFrame, size: Option<(u32, u32)>, options: &CineOptions) -> Result<RgbaImage> {
+    let mut image = image::load_from_memory(&frame.data)?.to_rgba8();
+    if let Some(redaction) = &options.redaction {
+        if !redaction.mask(&mut image) {
+            bail!(
+                "no redaction template for {}x{} snapshots",
+                image.width(),
+                image.height()
+            );
+        }
+    }
+    if let Some((width, height)) = size {
+        if image.dimensions() != (width, height) {
+            image = image::imageops::resize(&image, width, height, FilterType::Triangle);
+        }
+    }
+    let text = overlay(frame, options);
+    if !text.is_empty() {
+        burn_text(&mut image, &text);
+    }
+    Ok(image)
+}
+
+#[inline(always)]
+fn write_gif(
+    frames: &[CineFrame],
+    path: &Path,
+    options: &CineOptions,
+    progress: &JobProgress,
+) -> Result<()> {
+    let mut encoder = GifEncoder::new_with_speed(BufWriter::new(File::create(path)?), 10);
+    encoder.set_repeat(Repeat::Infinite)?;
+    let delay = Delay::from_numer_denom_ms(100_000, (options.fps * 100.0).round() as u32);
+    let mut size = None;
+    for frame in frames {
+        progress.check()?;
+        let image = render(frame, size, options)?;
+        size = Some(image.dimensions());
+        encoder.encode_frame(image::Frame::from_parts(image, 0, 0, delay))?;
+        progress.inc();
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn write_apng(
+    frames: &[CineFrame],
+    path: &Path,
+    options: &CineOptions,
+    progress: &JobProgress,
+) -> Result<()> {
+    let first = render(&frames[0], None, options)?;
+    let (width, height) = first.dimensions();
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    encoder.set_frame_delay(100, (options.fps * 100.0).round() as u16)?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(first.as_raw())?;
+    progress.inc();
+    for frame in &frames[1..] {
+        progress.check()?;
+        writer.write_image_data(render(frame, Some((width, height)), options)?.as_raw())?;
+        progress.inc();
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[inline(always)]
+fn chunk_header(out: &mut Vec<u8>, fourcc: &[u8; 4], size: usize) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(size as u32).to_le_bytes());
+}
+
+#[inline(always)]
+fn put_u32(out: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+// RIFF AVI with one MJPG video stream and an idx1 index
+#[inline(always)]
+fn avi_bytes(jpegs: &[Vec<u8>], width: u32, height: u32, fps: f64) -> Vec<u8> {
+    let frames = jpegs.len() as u32;
+    let max_frame = jpegs.iter().map(Vec::len).max().unwrap_or(0) as u32;
+    let (scale, rate) = (100, (fps * 100.0).round() as u32);
+
+    let mut avih = Vec::with_capacity(56);
+    put_u32(
+        &mut avih,
+        &[
+            (1e6 / fps).round() as u32,
+            (max_frame as f64 * fps).ceil() as u32,
+            0,
+            // AVIF_HASINDEX
+            0x10,
+            frames,
+            0,
+            1,
+            max_frame,
+            width,
+            height,
+            0,
+            0,
+            0,
+            0,
+        ],
+    );
+    let mut strh = Vec::with_capacity(56);
+    strh.extend_from_slice(b"vids");
+    strh.extend_from_slice(b"MJPG");
+    put_u32(
+        &mut strh,
+        &[0, 0, 0, scale, rate, 0, frames, max_frame, u32::MAX, 0],
+    );
+    for value in [0u16, 0, width as u16, height as u16] {
+        strh.extend_from_slice(&value.to_le_bytes());
+    }
+    // BITMAPINFOHEADER
+    let mut strf = Vec::with_capacity(40);
+    put_u32(&mut strf, &[40, width, height]);
+    strf.extend_from_slice(&1u16.to_le_bytes());
+    strf.extend_from_slice(&24u16.to_le_bytes());
+    strf.extend_from_slice(b"MJPG");
+    put_u32(&mut strf, &[width * height * 3, 0, 0, 0, 0]);
+
+    let mut strl = Vec::new();
+    strl.extend_from_slice(b"strl");
+    chunk_header(&mut strl, b"strh", strh.len());
+    strl.extend_from_slice(&strh);
+    chunk_header(&mut strl, b"strf", strf.len());
+    strl.extend_from_slice(&strf);
+    let mut hdrl = Vec::new();
+    hdrl.extend_from_slice(b"hdrl");
+    chunk_header(&mut hdrl, b"avih", avih.len());
+    hdrl.extend_from_slice(&avih);
+    chunk_header(&mut hdrl, b"LIST", strl.len());
+    hdrl.extend_from_slice(&strl);
+
+    let mut movi = Vec::new();
+    movi.extend_from_slice(b"movi");
+    let mut idx1 = Vec::with_capacity(jpegs.len() * 16);
+    for jpeg in jpegs {
+        idx1.extend_from_slice(b"00dc");
+        // AVIIF_KEYFRAME, offset from the "movi" tag
+        put_u32(&mut idx1, &[0x10, movi.len() as u32, jpeg.len() as u32]);
+        chunk_header(&mut movi, b"00dc", jpeg.len());
+        movi.extend_from_slice(jpeg);
+        if jpeg.len() % 2 == 1 {
+            movi.push(0);
+        }
+    }
+
+    let mut avi = Vec::with_capacity(hdrl.len() + movi.len() + idx1.len() + 40);
+    avi.extend_from_slice(b"AVI ");
+    chunk_header(&mut avi, b"LIST", hdrl.len());
+    avi.extend_from_slice(&hdrl);
+    chunk_header(&mut avi, b"LIST", movi.len());
+    avi.extend_from_slice(&movi);
+    chunk_header(&mut avi, b"idx1", idx1.len());
+    avi.extend_from_slice(&idx1);
+    let mut riff = Vec::with_capacity(avi.len() + 8);
+    chunk_header(&mut riff, b"RIFF", avi.len());
+    riff.extend_from_slice(&avi);
+    riff
+}
+
+#[inline(always)]
+fn write_avi(
+    frames: &[CineFrame],
+    path: &Path,
+    options: &CineOptions,
+    progress: &JobProgress,
+) -> Result<()> {
+    let mut size = None;
+    let mut jpegs = Vec::with_capacity(frames.len());
+    for frame in frames {
+        progress.check()?;
+        let image = image::DynamicImage::ImageRgba8(render(frame, size, options)?).to_rgb8();
+        size = Some(image.dimensions());
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 90).encode(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            image::ColorType::Rgb8,
+        )?;
+        jpegs.push(jpeg);
+        progress.inc();
+    }
+    let (width, height) = size.unwrap_or_default();
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&avi_bytes(&jpegs, width, height, options.fps))?;
+    file.flush()?;
+    Ok(())
+}
+
+#[inline(always)]
+pub fn export_cine(
+    frames: Vec<CineFrame>,
+    path: &Path,
+    options: &CineOptions,
+    progress: &JobProgress,
+) -> Result<String> {
+    if frames.is_empty() {
+        bail!("no snapshots to export");
+    }
+    progress.set_total(frames.len());
+    let written = match options.format {
+        CineFormat::Gif => write_gif(&frames, path, options, progress),
+        CineFormat::Apng => write_apng(&frames, path, options, progress),
+        CineFormat::Avi => write_avi(&frames, path, options, progress),
+    };
+    if let Err(e) = written {
+        // Do not leave a truncated file behind
+        let _ = std::fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(format!(
+        "Wrote {} frames at {} fps to {}",
+        frames.len(),
+        options.fps,
+        path.display()
+    ))
+}
+
+#[derive(Default)]
+pub struct CineDialog {
+    pub open: bool,
+    pub options: CineOptions,
+}
+
+impl CineDialog {
+    // Returns the output path once the user starts the export
+    #[inline(always)]
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        snapshots: &[Snapshot],
+        selected_row: Option<usize>,
+    ) -> Option<PathBuf> {
+        let mut start = None;
+        let mut open = self.open;
+        egui::Window::new("Export cine")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.options.scope, CineScope::Treatment, "Treatment");
+                    ui.radio_value(
+                        &mut self.options.scope,
+                        CineScope::SelectedSonication,
+                        match selected_row {
+                            Some(row) => format!("Sonication {}", row + 1),
+                            None => "Selected sonication".to_string(),
+                        },
+                    );
+                });
+                let count = snapshots
+                    .iter()
+                    .filter(|snapshot| self.options.scope.includes(snapshot, selected_row))
+                    .count();
+                ui.label(format!("{} snapshots, ordered by capture time", count));
+                egui::ComboBox::from_label("Format")
+                    .selected_text(self.options.format.label())
+                    .show_ui(ui, |ui| {
+                        for format in CineFormat::ALL {
+                            ui.selectable_value(&mut self.options.format, format, format.label());
+                        }
+                    });
+                ui.add(
+                    egui::DragValue::new(&mut self.options.fps)
+                        .clamp_range(0.1..=30.0)
+                        .speed(0.1)
+                        .suffix(" fps"),
+                );
+                ui.checkbox(
+                    &mut self.options.burn_sonication,
+                    "Burn in sonication number",
+                );
+                ui.checkbox(&mut self.options.burn_time, "Burn in capture time");
+                ui.label(RichText::new("Frames are scaled to the size of the first one").weak());
+                if ui
+                    .add_enabled(count > 0, egui::Button::new("Export…"))
+                    .clicked()
+                {
+                    start = rfd::FileDialog::new()
+                        .add_filter(
+                            self.options.format.label(),
+                            &[self.options.format.extension()],
+                        )
+                        .set_file_name(format!("cine.{}", self.options.format.extension()))
+                        .save_file();
+                }
+            });
+        self.open = open;
+        start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn avi_chunk_sizes_and_index() {
+        let jpegs = vec![vec![0xFF; 5], vec![0xAA; 8]];
+        let avi = avi_bytes(&jpegs, 64, 48, 2.0);
+        assert_eq!(&avi[0..4], b"RIFF");
+        assert_eq!(u32_at(&avi, 4) as usize, avi.len() - 8);
+        assert_eq!(&avi[8..12], b"AVI ");
+        assert_eq!(&avi[12..16], b"LIST");
+        let hdrl = u32_at(&avi, 16) as usize;
+        assert_eq!(&avi[20..24], b"hdrl");
+        assert_eq!(&avi[24..28], b"avih");
+        // Microseconds per frame, flags, frame count, width and height
+        assert_eq!(u32_at(&avi, 32), 500_000);
+        assert_eq!(u32_at(&avi, 44), 0x10);
+        assert_eq!(u32_at(&avi, 48), 2);
+        assert_eq!(u32_at(&avi, 64), 64);
+        assert_eq!(u32_at(&avi, 68), 48);
+
+        let movi_start = 20 + hdrl;
+        assert_eq!(&avi[movi_start..movi_start + 4], b"LIST");
+        let movi = u32_at(&avi, movi_start + 4) as usize;
+        let movi_tag = movi_start + 8;
+        assert_eq!(&avi[movi_tag..movi_tag + 4], b"movi");
+        // 5 bytes padded to 6, then 8 bytes, each with an 8 byte header
+        assert_eq!(movi, 4 + 8 + 6 + 8 + 8);
+
+        let idx1 = movi_tag + movi;
+        assert_eq!(&avi[idx1..idx1 + 4], b"idx1");
+        assert_eq!(u32_at(&avi, idx1 + 4), 32);
+        let entries: Vec<(u32, u32)> = (0..2)
+            .map(|i| {
+                let entry = idx1 + 8 + i * 16;
+                assert_eq!(&avi[entry..entry + 4], b"00dc");
+                (u32_at(&avi, entry + 8), u32_at(&avi, entry + 12))
+            })
+            .collect();
+        assert_eq!(entries, vec![(4, 5), (18, 8)]);
+        for (offset, size) in entries {
+            let chunk = movi_tag + offset as usize;
+            assert_eq!(&avi[chunk..chunk + 4], b"00dc");
+            assert_eq!(u32_at(&avi, chunk + 4), size);
+        }
+        assert_eq!(idx1 + 8 + 32, avi.len());
+    }
+
+    #[test]
+    fn avi_without_frames_is_well_formed() {
+        let avi = avi_bytes(&[], 16, 16, 10.0);
+        assert_eq!(u32_at(&avi, 4) as usize, avi.len() - 8);
+        assert_eq!(&avi[avi.len() - 8..avi.len() - 4], b"idx1");
+        assert_eq!(u32_at(&avi, avi.len() - 4), 0);
+    }
+}
@@ -0,0 +1,581 @@
+use crate::{archive, summary, table};
+use anyhow::{Context, Result};
+use eframe::egui::{self, Color32, Grid, RichText};
+use egui_plot::{Legend, Line, Plot};
+use polars::prelude::*;
+use std::path::Path;
+
+// One value per sonication, missing where the cell is empty
+type Values = Vec<Option<f64>>;
+type ValuesFn = fn(&Treatment) -> Values;
+
+const ENERGY_TITLE: &str = "Accumulated delivered energy [J]";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Alignment {
+    #[default]
+    Index,
+    // Match sonications by time since the first sonication of each treatment
+    Time,
+}
+
+pub struct Treatment {
+    pub name: String,
+    pub df: DataFrame,
+}
+
+impl Treatment {
+    #[inline(always)]
+    pub fn load(path: &Path, options: &summary::TimeOptions) -> Result<Self> {
+        let is_zip = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+        let bytes = if is_zip {
+            archive::find_summary(path)?
+                .context("no treatment summary found in archive")?
+                .1
+        } else {
+            std::fs::read(path)?
+        };
+        let (df, _) = summary::read_csv_bytes(bytes, options)?;
+        Ok(Self {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            df,
+        })
+    }
+
+    // Seconds since the first sonication
+    #[inline(always)]
+    fn elapsed(&self) -> Vec<Option<f64>> {
+        let times = summary::sonication_times(&self.df);
+        let start = times.iter().flatten().min().copied();
+        times
+            .into_iter()
+            .map(|time| Some((time? - start?).num_milliseconds() as f64 / 1000.0))
+            .collect()
+    }
+
+    #[inline(always)]
+    fn values(&self, name: &str) -> Vec<Option<f64>> {
+        summary::float_values(&self.df, name).unwrap_or_else(|| vec![None; self.df.height()])
+    }
+
+    #[inline(always)]
+    fn energy(&self) -> Vec<Option<f64>> {
+        summary::sonication_energy(&self.df)
+            .map(|(energy, _)| energy)
+            .unwrap_or_else(|| vec![None; self.df.height()])
+    }
+
+    // Only the planned energy is available, the energy plot says so
+    #[inline(always)]
+    fn planned_energy(&self) -> bool {
+        summary::sonication_energy(&self.df).is_some_and(|(_, planned)| planned)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ColumnChange {
+    name: String,
+    mean_a: Option<f64>,
+    mean_b: Option<f64>,
+    // Aligned pairs whose values differ
+    changed: usize,
+}
+
+#[inline(always)]
+fn mean(values: &[Option<f64>]) -> Option<f64> {
+    let present: Vec<f64> = values.iter().flatten().copied().collect();
+    (!present.is_empty()).then(|| present.iter().sum::<f64>() / present.len() as f64)
+}
+
+#[inline(always)]
+fn accumulate(values: &[Option<f64>]) -> Vec<f64> {
+    values
+        .iter()
+        .scan(0.0, |total, value| {
+            *total += value.unwrap_or(0.0);
+            Some(*total)
+        })
+        .collect()
+}
+
+// Pairs of row indices of A and B, unmatched sonications get None on the other side
+#[inline(always)]
+fn align(
+    a: &Treatment,
+    b: &Treatment,
+    alignment: Alignment,
+    tolerance: f64,
+) -> Vec<(Option<usize>, Option<usize>)> {
+    match alignment {
+        Alignment::Index => (0..a.df.height().max(b.df.height()))
+            .map(|idx| {
+                (
+                    (idx < a.df.height()).then_some(idx),
+                    (idx < b.df.height()).then_some(idx),
+                )
+            })
+            .collect(),
+        Alignment::Time => {
+            let (ta, tb) = (a.elapsed(), b.elapsed());
+            let mut pairs = Vec::new();
+            let mut j = 0;
+            for (i, time_a) in ta.iter().enumerate() {
+                let Some(time_a) = time_a else {
+                    pairs.push((Some(i), None));
+                    continue;
+                };
+                // Sonications of B without a time or clearly before this one have no partner
+                while let Some(time_b) = tb.get(j) {
+                    match time_b {
+                        Some(time_b) if *time_b >= time_a - tolerance => break,
+                        _ => {
+                            pairs.push((None, Some(j)));
+                            j += 1;
+                        }
+                    }
+                }
+                match tb.get(j) {
+                    Some(Some(time_b)) if (time_b - time_a).abs() <= tolerance => {
+                        pairs.push((Some(i), Some(j)));
+                        j += 1;
+                    }
+                    _ => pairs.push((Some(i), None)),
+                }
+            }
+            pairs.extend((j..tb.len()).map(|j| (None, Some(j))));
+            pairs
+        }
+    }
+}
+
+pub struct Comparison {
+    pub a: Option<Treatment>,
+    pub b: Option<Treatment>,
+    alignment: Alignment,
+    // Largest start time difference [s] still matched when aligning by time
+    tolerance: f64,
+    only_changed: bool,
+    pairs: Vec<(Option<usize>, Option<usize>)>,
+    changes: Vec<ColumnChange>,
+    dirty: bool,
+}
+
+impl Default for Comparison {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            a: None,
+            b: None,
+            alignment: Alignment::default(),
+            tolerance: 30.0,
+            only_changed: false,
+            pairs: Vec::new(),
+            changes: Vec::new(),
+            dirty: false,
+        }
+    }
+}
+
+impl Comparison {
+    #[inline(always)]
+    pub fn set(&mut self, slot_b: bool, treatment: Treatment) {
+        if slot_b {
+            self.b = Some(treatment);
+        } else {
+            self.a = Some(treatment);
+        }
+        self.dirty = true;
+    }
+
+    #[inline(always)]
+    fn rebuild(&mut self) {
+        self.dirty = false;
+        self.pairs.clear();
+        self.changes.clear();
+        let (Some(a), Some(b)) = (&self.a, &self.b) else {
+            return;
+        };
+        self.pairs = align(a, b, self.alignment, self.tolerance);
+        // Numeric columns present in both treatments, in the order of A
+        let columns: Vec<String> =
+            a.df.get_columns()
+                .iter()
+                .filter(|series| series.dtype().is_numeric())
+                .map(|series| series.name().to_string())
+                .filter(|name| {
+                    b.df.column(name)
+                        .is_ok_and(|series| series.dtype().is_numeric())
+                })
+                .collect();
+        for name in &columns {
+            let (va, vb) = (a.values(name), b.values(name));
+            let changed = self
+                .pairs
+                .iter()
+                .filter(|(i, j)| match (i, j) {
+                    (Some(i), Some(j)) => match (va[*i], vb[*j]) {
+                        (Some(x), Some(y)) => (x - y).abs() > 1e-9,
+                        (x, y) => x.is_some() != y.is_some(),
+                    },
+                    _ => false,
+                })
+                .count();
+            self.changes.push(ColumnChange {
+                name: name.clone(),
+                mean_a: mean(&va),
+                mean_b: mean(&vb),
+                changed,
+            });
+        }
+    }
+
+    #[inline(always)]
+    fn show_summary(&self, ui: &mut egui::Ui, a: &Treatment, b: &Treatment) {
+        let matched = self
+            .pairs
+            .iter()
+            .filter(|(i, j)| i.is_some() && j.is_some())
+            .count();
+        ui.label(format!(
+            "{} sonications in A, {} in B, {} matched",
+            a.df.height(),
+            b.df.height(),
+            matched
+        ));
+        let only = |x: &DataFrame, y: &DataFrame| -> Vec<String> {
+            x.get_column_names()
+                .into_iter()
+                .filter(|name| y.column(name).is_err())
+                .map(str::to_string)
+                .collect()
+        };
+        for (label, names) in [
+            ("Only in A", only(&a.df, &b.df)),
+            ("Only in B", only(&b.df, &a.df)),
+        ] {
+            if !names.is_empty() {
+                ui.label(format!("{}: {}", label, names.join(", ")));
+            }
+        }
+        Grid::new("compare_summary").striped(true).show(ui, |ui| {
+            for header in ["Parameter", "Mean A", "Mean B", "Δ mean", "Changed pairs"] {
+                ui.strong(header);
+            }
+            ui.end_row();
+            let format = |v: Option<f64>| v.map(|v| format!("{:.2}", v)).unwrap_or_default();
+            for change in &self.changes {
+                if self.only_changed && change.changed == 0 {
+                    continue;
+                }
+                let color = if change.changed > 0 {
+                    Color32::from_rgb(230, 140, 0)
+                } else {
+                    ui.visuals().text_color()
+                };
+                ui.colored_label(color, &change.name);
+                ui.label(format(change.mean_a));
+                ui.label(format(change.mean_b));
+                ui.label(format(change.mean_b.zip(change.mean_a).map(|(b, a)| b - a)));
+                ui.label(change.changed.to_string());
+                ui.end_row();
+            }
+        });
+    }
+
+    #[inline(always)]
+    fn show_plots(&self, ui: &mut egui::Ui, a: &Treatment, b: &Treatment) {
+        // By time the x axis is seconds since the first sonication, else the sonication number
+        let x_values = |treatment: &Treatment| -> Vec<f64> {
+            match self.alignment {
+                Alignment::Index => (1..=treatment.df.height()).map(|i| i as f64).collect(),
+                Alignment::Time => treatment
+                    .elapsed()
+                    .into_iter()
+                    .map(|t| t.unwrap_or(f64::NAN))
+                    .collect(),
+            }
+        };
+        let points = |x: Vec<f64>, y: Vec<f64>| -> Vec<[f64; 2]> {
+            x.into_iter().zip(y).map(|(x, y)| [x, y]).collect()
+        };
+        let x_label = match self.alignment {
+            Alignment::Index => "Sonication",
+            Alignment::Time => "Elapsed [s]",
+        };
+        let plots: [(&str, ValuesFn); 2] = [
+            (ENERGY_TITLE, Treatment::energy),
+            ("Accumulated volume [cc]", |t| {
+                t.values("Target Volume [cc]")
+            }),
+        ];
+        ui.columns(2, |columns| {
+            for (ui, (title, values)) in columns.iter_mut().zip(plots) {
+                ui.label(RichText::new(title).strong());
+                Plot::new(title)
+                    .legend(Legend::default())
+                    .height(250.0)
+                    .x_axis_label(x_label)
+                    .show(ui, |plot_ui| {
+                        for (name, treatment) in [("A", a), ("B", b)] {
+                            let planned = title == ENERGY_TITLE && treatment.planned_energy();
+                            plot_ui.line(
+                                Line::new(points(
+                                    x_values(treatment),
+                                    accumulate(&values(treatment)),
+                                ))
+                                .name(format!(
+                                    "{}: {}{}",
+                                    name,
+                                    treatment.name,
+                                    if planned { " (planned)" } else { "" }
+                                )),
+                            );
+                        }
+                    });
+            }
+        });
+    }
+
+    #[inline(always)]
+    fn show_differences(&self, ui: &mut egui::Ui, a: &Treatment, b: &Treatment) {
+        let columns: Vec<(String, Values, Values)> = self
+            .changes
+            .iter()
+            .filter(|change| !self.only_changed || change.changed > 0)
+            .map(|change| {
+                (
+                    change.name.clone(),
+                    a.values(&change.name),
+                    b.values(&change.name),
+                )
+            })
+            .collect();
+        let (ta, tb) = (
+            summary::sonication_times(&a.df),
+            summary::sonication_times(&b.df),
+        );
+        egui::ScrollArea::both()
+            .id_source("compare_differences")
+            .show(ui, |ui| {
+                Grid::new("compare_grid").striped(true).show(ui, |ui| {
+                    ui.strong("A #");
+                    ui.strong("B #");
+                    ui.strong("Δ start [s]");
+                    for (name, _, _) in &columns {
+                        ui.strong(format!("Δ {}", name));
+                    }
+                    ui.end_row();
+                    for (i, j) in &self.pairs {
+                        ui.label(
+                            i.map(|i| (i + 1).to_string())
+                                .unwrap_or_else(|| "-".to_string()),
+                        );
+                        ui.label(
+                            j.map(|j| (j + 1).to_string())
+                                .unwrap_or_else(|| "-".to_string()),
+                        );
+                        let start = match (i.and_then(|i| ta[i]), j.and_then(|j| tb[j])) {
+                            (Some(x), Some(y)) => {
+                                format!("{:.0}", (y - x).num_milliseconds() as f64 / 1000.0)
+                            }
+                            _ => String::new(),
+                        };
+                        ui.label(start);
+                        for (_, va, vb) in &columns {
+                            let x = i.and_then(|i| va[i]);
+                            let y = j.and_then(|j| vb[j]);
+                            match (x, y) {
+                                (Some(x), Some(y)) => {
+                                    let delta = y - x;
+                                    let text = table::format_value(&AnyValue::Float64(delta));
+                                    let label = if delta.abs() > 1e-9 {
+                                        ui.colored_label(Color32::from_rgb(230, 140, 0), text)
+                                    } else {
+                                        ui.label(text)
+                                    };
+                                    label.on_hover_text(format!("A {:.3}, B {:.3}", x, y));
+                                }
+                                (x, y) => {
+                                    let side =
+                                        x.or(y).map(|v| format!("{:.2}", v)).unwrap_or_default();
+                                    ui.colored_label(Color32::GRAY, side);
+                                }
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+    }
+
+    // Returns an error message when a treatment could not be loaded
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui, options: &summary::TimeOptions) -> Option<String> {
+        let mut error = None;
+        ui.horizontal(|ui| {
+            for (slot_b, label) in [(false, "Load A"), (true, "Load B")] {
+                if ui.button(RichText::new(label).size(20.0)).clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("TreatSummary", &["csv", "zip"])
+                        .pick_file()
+                    {
+                        match Treatment::load(&path, options) {
+                            Ok(treatment) => self.set(slot_b, treatment),
+                            Err(e) => {
+                                error = Some(format!("Could not load {}: {:#}", path.display(), e))
+                            }
+                        }
+                    }
+                }
+                let name = if slot_b { &self.b } else { &self.a };
+                ui.label(
+                    name.as_ref()
+                        .map(|t| t.name.as_str())
+                        .unwrap_or("nothing loaded"),
+                );
+                ui.separator();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Align by:");
+            self.dirty |= ui
+                .radio_value(&mut self.alignment, Alignment::Index, "Sonication number")
+                .changed();
+            self.dirty |= ui
+                .radio_value(&mut self.alignment, Alignment::Time, "Time since start")
+                .changed();
+            if self.alignment == Alignment::Time {
+                self.dirty |= ui
+                    .add(
+                        egui::DragValue::new(&mut self.tolerance)
+                            .clamp_range(1.0..=3600.0)
+                            .prefix("within ")
+                            .suffix(" s"),
+                    )
+                    .changed();
+            }
+            ui.checkbox(&mut self.only_changed, "Only changed parameters");
+        });
+        if self.dirty {
+            self.rebuild();
+        }
+        let (Some(a), Some(b)) = (&self.a, &self.b) else {
+            ui.label("Load two TreatSummaries to compare them");
+            return error;
+        };
+        egui::CollapsingHeader::new(RichText::new("Changed parameters").size(20.0))
+            .default_open(true)
+            .show(ui, |ui| self.show_summary(ui, a, b));
+        egui::CollapsingHeader::new(RichText::new("Accumulation").size(20.0))
+            .default_open(true)
+            .show(ui, |ui| self.show_plots(ui, a, b));
+        egui::CollapsingHeader::new(RichText::new("Differences per sonication").size(20.0))
+            .default_open(true)
+            .show(ui, |ui| self.show_differences(ui, a, b));
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sonications starting at the given seconds, None without a logged time
+    #[inline(always)]
+    fn treatment(seconds: &[Option<f64>]) -> Treatment {
+        let millis: Vec<Option<i64>> = seconds
+            .iter()
+            .map(|s| s.map(|s| 1_686_830_400_000 + (s * 1000.0) as i64))
+            .collect();
+        Treatment {
+            name: "test".to_string(),
+            df: df!("Time" => &millis).unwrap(),
+        }
+    }
+
+    #[inline(always)]
+    fn by_time(
+        a: &[Option<f64>],
+        b: &[Option<f64>],
+        tolerance: f64,
+    ) -> Vec<(Option<usize>, Option<usize>)> {
+        align(&treatment(a), &treatment(b), Alignment::Time, tolerance)
+    }
+
+    #[test]
+    fn index_alignment_pads_the_shorter_treatment() {
+        let (a, b) = (treatment(&[Some(0.0); 3]), treatment(&[Some(0.0)]));
+        assert_eq!(
+            align(&a, &b, Alignment::Index, 0.0),
+            vec![(Some(0), Some(0)), (Some(1), None), (Some(2), None)]
+        );
+    }
+
+    #[test]
+    fn unmatched_sonications_keep_their_order() {
+        let pairs = by_time(
+            &[Some(0.0), Some(60.0), Some(120.0)],
+            &[Some(0.0), Some(30.0), Some(125.0), Some(200.0)],
+            10.0,
+        );
+        assert_eq!(
+            pairs,
+            vec![
+                (Some(0), Some(0)),
+                (None, Some(1)),
+                (Some(1), None),
+                (Some(2), Some(2)),
+                (None, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn tolerance_is_inclusive() {
+        let pairs = by_time(&[Some(0.0), Some(100.0)], &[Some(0.0), Some(110.0)], 10.0);
+        assert_eq!(pairs, vec![(Some(0), Some(0)), (Some(1), Some(1))]);
+        let pairs = by_time(&[Some(0.0), Some(100.0)], &[Some(0.0), Some(110.5)], 10.0);
+        assert_eq!(
+            pairs,
+            vec![(Some(0), Some(0)), (Some(1), None), (None, Some(1))]
+        );
+    }
+
+    #[test]
+    fn times_are_relative_to_the_first_sonication() {
+        let a = treatment(&[Some(0.0), Some(60.0)]);
+        let b = treatment(&[Some(3600.0), Some(3660.0)]);
+        assert_eq!(
+            align(&a, &b, Alignment::Time, 5.0),
+            vec![(Some(0), Some(0)), (Some(1), Some(1))]
+        );
+    }
+
+    #[test]
+    fn missing_times_are_unmatched() {
+        let pairs = by_time(
+            &[Some(0.0), None, Some(60.0)],
+            &[Some(0.0), Some(60.0)],
+            10.0,
+        );
+        assert_eq!(
+            pairs,
+            vec![(Some(0), Some(0)), (Some(1), None), (Some(2), Some(1))]
+        );
+        // A sonication of B without a time does not take the partner of the next one
+        let pairs = by_time(
+            &[Some(0.0), Some(60.0)],
+            &[Some(0.0), None, Some(60.0)],
+            10.0,
+        );
+        assert_eq!(
+            pairs,
+            vec![(Some(0), Some(0)), (None, Some(1)), (Some(1), Some(2))]
+        );
+    }
+}
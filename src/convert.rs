@@ -0,0 +1,624 @@
+use crate::{gallery, jobs::JobProgress, redaction::Redaction};
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDateTime;
+use eframe::egui::{self, RichText};
+use image::{codecs::webp::WebPEncoder, codecs::webp::WebPQuality, imageops::FilterType};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TargetFormat {
+    #[default]
+    Png,
+    // Lossless, the snapshots are measured on
+    WebP,
+}
+
+impl TargetFormat {
+    #[inline(always)]
+    pub fn extension(self) -> &'static str {
+        match self {
+            TargetFormat::Png => "png",
+            TargetFormat::WebP => "webp",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConvertOptions {
+    pub format: TargetFormat,
+    // Longest side after conversion, None keeps the size
+    pub max_size: Option<u32>,
+    // Regions blacked out before encoding
+    pub redaction: Option<Redaction>,
+}
+
+// Provenance written into every converted image, empty fields are unknown and left out
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotMetadata {
+    pub source_archive: String,
+    pub original_name: String,
+    // Console exports are named after the treatment, the archive stem is used as ID
+    pub treatment_id: String,
+    pub capture_time: Option<NaiveDateTime>,
+}
+
+impl SnapshotMetadata {
+    #[inline(always)]
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("Source", self.source_archive.clone()),
+            ("Title", self.original_name.clone()),
+            ("Treatment ID", self.treatment_id.clone()),
+        ];
+        if let Some(time) = self.capture_time {
+            fields.push((
+                "Creation Time",
+                time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            ));
+        }
+        fields.retain(|(_, value)| !value.is_empty());
+        fields
+    }
+}
+
+pub enum ImageSource {
+    Memory(Arc<[u8]>),
+    File(PathBuf),
+}
+
+pub struct SourceImage {
+    pub metadata: SnapshotMetadata,
+    // Output path relative to the output folder, without extension
+    pub relative: PathBuf,
+    pub data: ImageSource,
+}
+
+#[inline(always)]
+fn encode_png(image: &image::RgbImage, metadata: &SnapshotMetadata) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Best);
+    for (keyword, text) in metadata.fields() {
+        // tEXt only holds Latin-1, anything else goes into an iTXt chunk
+        if text.chars().all(|c| (c as u32) < 256) {
+            encoder.add_text_chunk(keyword.to_string(), text)?;
+        } else {
+            encoder.add_itxt_chunk(keyword.to_string(), text)?;
+        }
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image.as_raw())?;
+    writer.finish()?;
+    Ok(buffer)
+}
+
+#[inline(always)]
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[inline(always)]
+fn xmp_packet(metadata: &SnapshotMetadata) -> String {
+    let properties: String = metadata
+        .fields()
+        .iter()
+        .map(|(keyword, text)| {
+            format!(
+                "<snap:{}>{}</snap:{}>",
+                keyword.replace(' ', ""),
+                escape_xml(text),
+                keyword.replace(' ', "")
+            )
+        })
+        .collect();
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+         <rdf:Description rdf:about=\"\" xmlns:snap=\"urn:sonalasense:snapshot:1.0/\">\
+         {}</rdf:Description></rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>",
+        properties
+    )
+}
+
+#[inline(always)]
+fn riff_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    // Chunks are padded to an even size
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+// The simple WebP container has no room for metadata, rewrite it as VP8X with an XMP chunk
+#[inline(always)]
+fn add_webp_xmp(webp: &[u8], width: u32, height: u32, xmp: &str) -> Result<Vec<u8>> {
+    if webp.len() < 20 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        bail!("the encoder did not return a WebP file");
+    }
+    let image_chunks = &webp[12..];
+    if image_chunks.starts_with(b"VP8X") {
+        bail!("the encoder already wrote an extended WebP file");
+    }
+    let mut vp8x = [0u8; 10];
+    // Only the XMP flag, the snapshots have no alpha
+    vp8x[0] = 0x04;
+    vp8x[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+    let mut chunks = Vec::with_capacity(webp.len() + xmp.len() + 32);
+    chunks.extend_from_slice(b"WEBP");
+    riff_chunk(&mut chunks, b"VP8X", &vp8x);
+    chunks.extend_from_slice(image_chunks);
+    riff_chunk(&mut chunks, b"XMP ", xmp.as_bytes());
+    let mut out = Vec::with_capacity(chunks.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+    out.extend_from_slice(&chunks);
+    Ok(out)
+}
+
+#[inline(always)]
+fn encode_webp(image: &image::RgbImage, metadata: &SnapshotMetadata) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    WebPEncoder::new_with_quality(&mut buffer, WebPQuality::lossless()).encode(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        image::ColorType::Rgb8,
+    )?;
+    add_webp_xmp(
+        &buffer,
+        image.width(),
+        image.height(),
+        &xmp_packet(metadata),
+    )
+}
+
+#[inline(always)]
+pub fn convert_image(
+    data: &[u8],
+    metadata: &SnapshotMetadata,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>> {
+    let mut image = image::load_from_memory(data)?;
+    if let Some(redaction) = &options.redaction {
+        let mut masked = image.to_rgba8();
+        if !redaction.mask(&mut masked) {
+            bail!(
+                "no redaction template for {}x{} images",
+                masked.width(),
+                masked.height()
+            );
+        }
+        image = image::DynamicImage::ImageRgba8(masked);
+    }
+    if let Some(max) = options.max_size {
+        if image.width() > max || image.height() > max {
+            image = image.resize(max, max, FilterType::Lanczos3);
+        }
+    }
+    let image = image.to_rgb8();
+    match options.format {
+        TargetFormat::Png => encode_png(&image, metadata),
+        TargetFormat::WebP => encode_webp(&image, metadata),
+    }
+}
+
+#[inline(always)]
+pub fn is_bmp(name: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(".bmp")
+}
+
+// Every BMP below dir, the first folder level is the treatment as laid out by "From Snapshots".
+// The archive a folder came from is not known, so no source archive is recorded.
+#[inline(always)]
+pub fn collect_folder(dir: &Path) -> Result<Vec<SourceImage>> {
+    let mut pending = vec![dir.to_path_buf()];
+    let mut images = Vec::new();
+    while let Some(folder) = pending.pop() {
+        for entry in std::fs::read_dir(&folder)
+            .with_context(|| format!("cannot read {}", folder.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            if !is_bmp(&path.to_string_lossy()) {
+                continue;
+            }
+            let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+            let treatment_id = relative
+                .components()
+                .next()
+                .filter(|_| relative.components().count() > 1)
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let original_name = relative.to_string_lossy().replace('\\', "/");
+            images.push(SourceImage {
+                metadata: SnapshotMetadata {
+                    source_archive: String::new(),
+                    treatment_id,
+                    capture_time: gallery::parse_capture_time(&original_name),
+                    original_name,
+                },
+                relative: relative.with_extension(""),
+                data: ImageSource::File(path),
+            });
+        }
+    }
+    images.sort_by(|a, b| a.relative.cmp(&b.relative));
+    Ok(images)
+}
+
+// Convert the images below out_dir and report how much space was saved by the converted ones
+#[inline(always)]
+pub fn convert_all(
+    images: Vec<SourceImage>,
+    out_dir: &Path,
+    options: &ConvertOptions,
+    progress: &JobProgress,
+) -> Result<String> {
+    progress.set_total(images.len());
+    let mut before = 0u64;
+    let mut after = 0u64;
+    let mut converted = 0;
+    let mut failed = Vec::new();
+    for image in images {
+        progress.check()?;
+        let result = (|| -> Result<(u64, u64)> {
+            let data = match image.data {
+                ImageSource::Memory(data) => data,
+                ImageSource::File(path) => std::fs::read(path)?.into(),
+            };
+            let encoded = convert_image(&data, &image.metadata, options)?;
+            let outpath = out_dir
+                .join(&image.relative)
+                .with_extension(options.format.extension());
+            if let Some(parent) = outpath.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(outpath, &encoded)?;
+            Ok((data.len() as u64, encoded.len() as u64))
+        })();
+        match result {
+            Ok((original, size)) => {
+                before += original;
+                after += size;
+                converted += 1;
+            }
+            Err(e) => failed.push(format!("{}: {:#}", image.metadata.original_name, e)),
+        }
+        progress.inc();
+    }
+    let saved = if before > 0 {
+        (1.0 - after as f64 / before as f64) * 100.0
+    } else {
+        0.0
+    };
+    let totals = format!(
+        "{} images, {:.1} MB to {:.1} MB ({:.0} % saved)",
+        converted,
+        before as f64 / 1e6,
+        after as f64 / 1e6,
+        saved
+    );
+    if !failed.is_empty() {
+        bail!(
+            "{}\n{} failed and are not counted in the totals:\n{}",
+            totals,
+            failed.len(),
+            failed.join("\n")
+        );
+    }
+    Ok(format!("Converted {}", totals))
+}
+
+pub enum ConvertSource {
+    // The snapshots of the loaded ZIP
+    Loaded,
+    Folder(PathBuf),
+}
+
+#[derive(Default)]
+pub struct ConvertDialog {
+    pub open: bool,
+    pub options: ConvertOptions,
+    downscale: bool,
+    max_size: u32,
+    out_dir: Option<PathBuf>,
+}
+
+impl ConvertDialog {
+    // Returns what to convert and where once the user starts the conversion
+    #[inline(always)]
+    pub fn show(&mut self, ctx: &egui::Context, loaded: usize) -> Option<(ConvertSource, PathBuf)> {
+        let mut start = None;
+        let mut open = self.open;
+        egui::Window::new("Convert snapshots")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Format:");
+                    ui.radio_value(&mut self.options.format, TargetFormat::Png, "PNG");
+                    ui.radio_value(
+                        &mut self.options.format,
+                        TargetFormat::WebP,
+                        "WebP (lossless)",
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.downscale, "Downscale to at most");
+                    if self.max_size == 0 {
+                        self.max_size = 1024;
+                    }
+                    ui.add_enabled(
+                        self.downscale,
+                        egui::DragValue::new(&mut self.max_size)
+                            .clamp_range(64..=8192)
+                            .suffix(" px"),
+                    );
+                });
+                self.options.max_size = self.downscale.then_some(self.max_size);
+                ui.horizontal(|ui| {
+                    if ui.button("Output folder").clicked() {
+                        self.out_dir = rfd::FileDialog::new().pick_folder();
+                    }
+                    ui.label(
+                        self.out_dir
+                            .as_ref()
+                            .map(|path| path.display().to_string())
+                            .unwrap_or_else(|| "no output folder".to_string()),
+                    );
+                });
+                ui.label(
+                    RichText::new(
+                        "Source archive, original name, treatment ID and capture time are \
+                         written into each image",
+                    )
+                    .weak(),
+                );
+                let Some(out_dir) = self.out_dir.clone() else {
+                    return;
+                };
+                ui.horizontal(|ui| {
+                    if ui
+                        .add_enabled(
+                            loaded > 0,
+                            egui::Button::new(format!("Convert {} loaded snapshots", loaded)),
+                        )
+                        .clicked()
+                    {
+                        start = Some((ConvertSource::Loaded, out_dir.clone()));
+                    }
+                    if ui.button("Convert BMPs in folder…").clicked() {
+                        if let Some(folder) = rfd::FileDialog::new().pick_folder() {
+                            start = Some((ConvertSource::Folder(folder), out_dir.clone()));
+                        }
+                    }
+                });
+            });
+        self.open = open;
+        start
+    }
+}
+
+// Source images for the snapshots of a loaded archive
+#[inline(always)]
+pub fn from_gallery(snapshots: &[gallery::Snapshot], archive: &Path) -> Vec<SourceImage> {
+    let source_archive = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let treatment_id = archive
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    snapshots
+        .iter()
+        .filter(|snapshot| is_bmp(&snapshot.archive_path))
+        .map(|snapshot| SourceImage {
+            metadata: SnapshotMetadata {
+                source_archive: source_archive.clone(),
+                original_name: snapshot.archive_path.clone(),
+                treatment_id: treatment_id.clone(),
+                capture_time: snapshot.time,
+            },
+            relative: PathBuf::from(&treatment_id)
+                .join(&snapshot.archive_path)
+                .with_extension(""),
+            data: ImageSource::Memory(snapshot.data.clone()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn metadata() -> SnapshotMetadata {
+        SnapshotMetadata {
+            source_archive: "T1.zip".to_string(),
+            original_name: "snap_20230615120000.bmp".to_string(),
+            treatment_id: "Patient <1> µ → 2".to_string(),
+            capture_time: gallery::parse_capture_time("snap_20230615120000.bmp"),
+        }
+    }
+
+    // Odd sizes so the RIFF chunks need padding
+    #[inline(always)]
+    fn image() -> image::RgbImage {
+        image::RgbImage::from_fn(5, 3, |x, y| image::Rgb([x as u8 * 40, y as u8 * 80, 7]))
+    }
+
+    // FourCC and data of every chunk after the WEBP header
+    #[inline(always)]
+    fn riff_chunks(webp: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut pos = 12;
+        while pos + 8 <= webp.len() {
+            let fourcc = webp[pos..pos + 4].try_into().unwrap();
+            let len = u32::from_le_bytes(webp[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            chunks.push((fourcc, &webp[pos + 8..pos + 8 + len]));
+            pos += 8 + len + len % 2;
+        }
+        assert_eq!(pos, webp.len());
+        chunks
+    }
+
+    #[test]
+    fn png_keeps_the_pixels_and_metadata() {
+        let encoded = encode_png(&image(), &metadata()).unwrap();
+        let decoded = image::load_from_memory(&encoded).unwrap().to_rgb8();
+        assert_eq!(decoded, image());
+        let reader = png::Decoder::new(encoded.as_slice()).read_info().unwrap();
+        let info = reader.info();
+        let latin1: Vec<(&str, &str)> = info
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()))
+            .collect();
+        assert_eq!(
+            latin1,
+            vec![
+                ("Source", "T1.zip"),
+                ("Title", "snap_20230615120000.bmp"),
+                ("Creation Time", "2023-06-15 12:00:00"),
+            ]
+        );
+        assert_eq!(info.utf8_text.len(), 1);
+        assert_eq!(info.utf8_text[0].keyword, "Treatment ID");
+        assert_eq!(info.utf8_text[0].get_text().unwrap(), "Patient <1> µ → 2");
+    }
+
+    #[test]
+    fn empty_fields_are_left_out() {
+        let metadata = SnapshotMetadata {
+            original_name: "a.bmp".to_string(),
+            ..Default::default()
+        };
+        let encoded = encode_png(&image(), &metadata).unwrap();
+        let reader = png::Decoder::new(encoded.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().uncompressed_latin1_text.len(), 1);
+        assert!(reader.info().utf8_text.is_empty());
+        assert!(!xmp_packet(&metadata).contains("TreatmentID"));
+    }
+
+    #[test]
+    fn webp_is_extended_with_xmp() {
+        let encoded = encode_webp(&image(), &metadata()).unwrap();
+        assert_eq!(&encoded[0..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(encoded[4..8].try_into().unwrap()) as usize,
+            encoded.len() - 8
+        );
+        assert_eq!(&encoded[8..12], b"WEBP");
+        let chunks = riff_chunks(&encoded);
+        let fourccs: Vec<&[u8; 4]> = chunks.iter().map(|(fourcc, _)| fourcc).collect();
+        assert_eq!(fourccs, vec![b"VP8X", b"VP8L", b"XMP "]);
+        let vp8x = chunks[0].1;
+        assert_eq!(vp8x.len(), 10);
+        assert_eq!(vp8x[0], 0x04);
+        // Canvas width and height minus one, 24 bit little endian
+        assert_eq!(&vp8x[4..7], &[4, 0, 0]);
+        assert_eq!(&vp8x[7..10], &[2, 0, 0]);
+        let xmp = std::str::from_utf8(chunks[2].1).unwrap();
+        assert!(xmp.contains("<snap:Source>T1.zip</snap:Source>"));
+        assert!(xmp.contains("<snap:TreatmentID>Patient &lt;1&gt; µ → 2</snap:TreatmentID>"));
+        assert!(xmp.contains("<snap:CreationTime>2023-06-15 12:00:00</snap:CreationTime>"));
+        let decoded = image::load_from_memory(&encoded).unwrap().to_rgb8();
+        assert_eq!(decoded, image());
+    }
+
+    #[test]
+    fn canvas_size_uses_three_bytes() {
+        let simple = [
+            b"RIFF".as_slice(),
+            &[12, 0, 0, 0],
+            b"WEBP",
+            b"VP8L",
+            &[0; 4],
+        ]
+        .concat();
+        let extended = add_webp_xmp(&simple, 70_000, 256, "x").unwrap();
+        let chunks = riff_chunks(&extended);
+        // 69999 = 0x01116F
+        assert_eq!(&chunks[0].1[4..7], &[0x6F, 0x11, 0x01]);
+        assert_eq!(&chunks[0].1[7..10], &[0xFF, 0, 0]);
+        assert_eq!(chunks[2], (*b"XMP ", b"x".as_slice()));
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_simple_webp() {
+        assert!(add_webp_xmp(b"not a webp file at all", 1, 1, "").is_err());
+        let extended = [
+            b"RIFF".as_slice(),
+            &[14, 0, 0, 0],
+            b"WEBP",
+            b"VP8X",
+            &[0; 6],
+        ]
+        .concat();
+        assert!(add_webp_xmp(&extended, 1, 1, "").is_err());
+    }
+
+    #[test]
+    fn folder_images_take_the_treatment_from_the_first_level() {
+        let dir = std::env::temp_dir().join(format!("ejs-convert-{}-folder", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("T1").join("Snapshots")).unwrap();
+        std::fs::create_dir_all(dir.join("T2")).unwrap();
+        for name in [
+            "top.bmp",
+            "notes.txt",
+            "T1/Snapshots/snap_20230615120000.BMP",
+            "T2/b.bmp",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let images = collect_folder(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let found: Vec<(PathBuf, &str, &str)> = images
+            .iter()
+            .map(|image| {
+                (
+                    image.relative.clone(),
+                    image.metadata.treatment_id.as_str(),
+                    image.metadata.original_name.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    Path::new("T1")
+                        .join("Snapshots")
+                        .join("snap_20230615120000"),
+                    "T1",
+                    "T1/Snapshots/snap_20230615120000.BMP"
+                ),
+                (Path::new("T2").join("b"), "T2", "T2/b.bmp"),
+                (PathBuf::from("top"), "", "top.bmp"),
+            ]
+        );
+        assert_eq!(
+            images[0].metadata.capture_time,
+            gallery::parse_capture_time("snap_20230615120000.bmp")
+        );
+        assert!(images
+            .iter()
+            .all(|image| image.metadata.source_archive.is_empty()));
+    }
+}
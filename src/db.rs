@@ -0,0 +1,887 @@
+use crate::{
+    dialect::{self, DialectOverrides},
+    summary, table,
+};
+use anyhow::{Context, Result};
+use eframe::egui::{self, Color32, Grid, RichText};
+use polars::prelude::*;
+use rusqlite::{params, types::Value, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const SCHEMA: &str = "
+PRAGMA foreign_keys = ON;
+CREATE TABLE IF NOT EXISTS treatments (
+    id INTEGER PRIMARY KEY,
+    source TEXT NOT NULL,
+    loaded_at TEXT NOT NULL,
+    start_time TEXT,
+    end_time TEXT,
+    protocol TEXT,
+    sonications INTEGER NOT NULL,
+    total_energy REAL,
+    max_energy REAL,
+    summary BLOB NOT NULL,
+    summary_hash TEXT
+);
+CREATE INDEX IF NOT EXISTS treatments_start ON treatments(start_time);
+CREATE TABLE IF NOT EXISTS sonication_values (
+    treatment_id INTEGER NOT NULL REFERENCES treatments(id) ON DELETE CASCADE,
+    row INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    value,
+    PRIMARY KEY (treatment_id, row, name)
+);
+CREATE TABLE IF NOT EXISTS calculator_sessions (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    saved_at TEXT NOT NULL,
+    rows TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS dicom_series (
+    folder TEXT NOT NULL,
+    series_uid TEXT NOT NULL,
+    images INTEGER NOT NULL,
+    indexed_at TEXT NOT NULL,
+    PRIMARY KEY (folder, series_uid)
+);
+CREATE TABLE IF NOT EXISTS saved_queries (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    query TEXT NOT NULL,
+    saved_at TEXT NOT NULL
+);
+";
+
+// Created after the migration, older databases lack the hash column until then
+const HASH_INDEX: &str = "CREATE INDEX IF NOT EXISTS treatments_hash ON treatments(summary_hash);";
+
+// SHA-256 of the raw summary, used to find a file that was loaded before
+#[inline(always)]
+fn summary_hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// Add the summary hash to databases created before it existed
+#[inline(always)]
+fn migrate(conn: &Connection) -> Result<()> {
+    let has_hash = conn
+        .prepare("SELECT 1 FROM pragma_table_info('treatments') WHERE name = 'summary_hash'")?
+        .exists([])?;
+    if !has_hash {
+        conn.execute("ALTER TABLE treatments ADD COLUMN summary_hash TEXT", [])?;
+    }
+    let missing: Vec<(i64, Vec<u8>)> = conn
+        .prepare("SELECT id, summary FROM treatments WHERE summary_hash IS NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    for (id, bytes) in missing {
+        conn.execute(
+            "UPDATE treatments SET summary_hash = ?1 WHERE id = ?2",
+            params![summary_hash(&bytes), id],
+        )?;
+    }
+    conn.execute_batch(HASH_INDEX)?;
+    Ok(())
+}
+
+// Stored in UTC like the treatment times, so both sort and compare the same way
+#[inline(always)]
+fn now() -> String {
+    chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+// "Protocol Name" is dropped by the summary pipeline, read it from the raw csv
+#[inline(always)]
+fn protocol_name(bytes: &[u8]) -> Option<String> {
+    let (bytes, _) = dialect::normalize(bytes, &DialectOverrides::default()).ok()?;
+    let mut reader = csv::Reader::from_reader(bytes.as_slice());
+    let column = reader
+        .headers()
+        .ok()?
+        .iter()
+        .position(|name| name.trim() == "Protocol Name")?;
+    reader
+        .records()
+        .filter_map(|record| record.ok())
+        .filter_map(|record| record.get(column).map(|v| v.trim().to_string()))
+        .find(|v| !v.is_empty())
+}
+
+#[inline(always)]
+fn column_f64(df: &DataFrame, name: &str) -> Option<Float64Chunked> {
+    df.column(name)
+        .ok()?
+        .cast(&DataType::Float64)
+        .ok()?
+        .f64()
+        .ok()
+        .cloned()
+}
+
+#[derive(Clone, Debug)]
+pub struct TreatmentRecord {
+    pub id: i64,
+    pub source: String,
+    pub loaded_at: String,
+    pub start_time: Option<String>,
+    pub protocol: Option<String>,
+    pub sonications: i64,
+    pub total_energy: Option<f64>,
+    pub max_energy: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionRecord {
+    pub id: i64,
+    pub name: String,
+    pub saved_at: String,
+    pub rows: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct SeriesRecord {
+    pub folder: String,
+    pub series_uid: String,
+    pub images: i64,
+    pub indexed_at: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SavedQuery {
+    pub id: i64,
+    pub name: String,
+    pub query: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TreatmentQuery {
+    // Dates as YYYY-MM-DD, both inclusive
+    pub date_from: Option<String>,
+    pub date_to: Option<String>,
+    pub protocol: Option<String>,
+    // Range of the highest energy per subspot [J] of a treatment
+    pub energy_min: Option<f64>,
+    pub energy_max: Option<f64>,
+}
+
+pub struct TreatmentDb {
+    conn: Connection,
+}
+
+impl TreatmentDb {
+    #[inline(always)]
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("cannot open database {}", path.display()))?;
+        // The folder watcher writes through a second connection
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        Ok(Self { conn })
+    }
+
+    // Database file next to the app settings
+    #[inline(always)]
+    pub fn default_path() -> PathBuf {
+        let dir = eframe::storage_dir("SonALAsense Parameter Tool").unwrap_or_default();
+        let _ = std::fs::create_dir_all(&dir);
+        dir.join("treatments.sqlite")
+    }
+
+    // Store a loaded summary, loading the same file again replaces the earlier entry
+    #[inline(always)]
+    pub fn record_treatment(&mut self, source: &str, bytes: &[u8], df: &DataFrame) -> Result<i64> {
+        let span = summary::treatment_span(df);
+        let format_time =
+            |time: chrono::NaiveDateTime| time.format("%Y-%m-%d %H:%M:%S").to_string();
+        let energy = column_f64(df, "Energy per subspot");
+        let subspots = column_f64(df, "Num. of SubSonic");
+        let total_energy = match (&energy, &subspots) {
+            (Some(energy), Some(subspots)) => (energy * subspots).sum(),
+            _ => None,
+        };
+        let max_energy = energy.as_ref().and_then(|energy| energy.max());
+
+        let hash = summary_hash(bytes);
+
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "DELETE FROM treatments WHERE summary_hash = ?1",
+            params![hash],
+        )?;
+        tx.execute(
+            "INSERT INTO treatments (source, loaded_at, start_time, end_time, protocol,
+             sonications, total_energy, max_energy, summary, summary_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                source,
+                now(),
+                span.map(|(start, _)| format_time(start)),
+                span.map(|(_, end)| format_time(end)),
+                protocol_name(bytes),
+                df.height() as i64,
+                total_energy,
+                max_energy,
+                bytes,
+                hash,
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        {
+            // Prepared once for every cell of the treatment
+            let mut insert = tx.prepare(
+                "INSERT INTO sonication_values (treatment_id, row, name, value)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for series in df.get_columns() {
+                let values: Vec<Value> = if series.name() == "Time" {
+                    summary::sonication_times(df)
+                        .into_iter()
+                        .map(|time| time.map_or(Value::Null, |time| Value::Text(format_time(time))))
+                        .collect()
+                } else if series.dtype().is_numeric() {
+                    series
+                        .cast(&DataType::Float64)?
+                        .f64()?
+                        .into_iter()
+                        .map(|value| value.map_or(Value::Null, Value::Real))
+                        .collect()
+                } else {
+                    series
+                        .iter()
+                        .map(|value| Value::Text(table::format_value(&value)))
+                        .collect()
+                };
+                for (row, value) in values.into_iter().enumerate() {
+                    insert.execute(params![id, row as i64, series.name(), value])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    #[inline(always)]
+    pub fn search(&self, query: &TreatmentQuery) -> Result<Vec<TreatmentRecord>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, source, loaded_at, start_time, protocol, sonications,
+             total_energy, max_energy FROM treatments
+             WHERE (?1 IS NULL OR start_time >= ?1)
+             AND (?2 IS NULL OR start_time < date(?2, '+1 day'))
+             AND (?3 IS NULL OR protocol LIKE '%' || ?3 || '%')
+             AND (?4 IS NULL OR max_energy >= ?4)
+             AND (?5 IS NULL OR max_energy <= ?5)
+             ORDER BY start_time DESC",
+        )?;
+        let records = statement
+            .query_map(
+                params![
+                    query.date_from,
+                    query.date_to,
+                    query.protocol,
+                    query.energy_min,
+                    query.energy_max
+                ],
+                |row| {
+                    Ok(TreatmentRecord {
+                        id: row.get(0)?,
+                        source: row.get(1)?,
+                        loaded_at: row.get(2)?,
+                        start_time: row.get(3)?,
+                        protocol: row.get(4)?,
+                        sonications: row.get(5)?,
+                        total_energy: row.get(6)?,
+                        max_energy: row.get(7)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    // Source path and raw summary bytes of a stored treatment
+    #[inline(always)]
+    pub fn load_treatment(&self, id: i64) -> Result<(String, Vec<u8>)> {
+        self.conn
+            .query_row(
+                "SELECT source, summary FROM treatments WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .context("treatment no longer in the database")
+    }
+
+    #[inline(always)]
+    pub fn delete_treatment(&self, id: i64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM treatments WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn save_session(&self, name: &str, rows: &[Vec<String>]) -> Result<i64> {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        for row in rows {
+            wtr.write_record(row)?;
+        }
+        let text = String::from_utf8(wtr.into_inner()?)?;
+        self.conn.execute(
+            "INSERT INTO calculator_sessions (name, saved_at, rows) VALUES (?1, ?2, ?3)",
+            params![name, now(), text],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    #[inline(always)]
+    pub fn sessions(&self) -> Result<Vec<SessionRecord>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, name, saved_at, rows FROM calculator_sessions ORDER BY saved_at DESC",
+        )?;
+        let records = statement
+            .query_map([], |row| {
+                let rows: String = row.get(3)?;
+                Ok(SessionRecord {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    saved_at: row.get(2)?,
+                    rows: csv::ReaderBuilder::new()
+                        .has_headers(false)
+                        .from_reader(rows.as_bytes())
+                        .records()
+                        .count(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    #[inline(always)]
+    pub fn load_session(&self, id: i64) -> Result<Vec<Vec<String>>> {
+        let text: String = self
+            .conn
+            .query_row(
+                "SELECT rows FROM calculator_sessions WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .context("session no longer in the database")?;
+        let mut rows = Vec::new();
+        for record in csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(text.as_bytes())
+            .records()
+        {
+            rows.push(record?.iter().map(str::to_string).collect());
+        }
+        Ok(rows)
+    }
+
+    #[inline(always)]
+    pub fn delete_session(&self, id: i64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM calculator_sessions WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn record_dicom_series(&mut self, folder: &Path, series: &[(String, usize)]) -> Result<()> {
+        let folder = folder.display().to_string();
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO dicom_series (folder, series_uid, images, indexed_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            let indexed_at = now();
+            for (uid, images) in series {
+                insert.execute(params![folder, uid, *images as i64, indexed_at])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn dicom_series(&self) -> Result<Vec<SeriesRecord>> {
+        let mut statement = self.conn.prepare(
+            "SELECT folder, series_uid, images, indexed_at FROM dicom_series
+             ORDER BY indexed_at DESC, folder, series_uid",
+        )?;
+        let records = statement
+            .query_map([], |row| {
+                Ok(SeriesRecord {
+                    folder: row.get(0)?,
+                    series_uid: row.get(1)?,
+                    images: row.get(2)?,
+                    indexed_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    // Saving under an existing name replaces that query
+    #[inline(always)]
+    pub fn save_query(&self, name: &str, query: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO saved_queries (name, query, saved_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET query = excluded.query, saved_at = excluded.saved_at",
+            params![name, query, now()],
+        )?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn saved_queries(&self) -> Result<Vec<SavedQuery>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT id, name, query FROM saved_queries ORDER BY name")?;
+        let records = statement
+            .query_map([], |row| {
+                Ok(SavedQuery {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    query: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    #[inline(always)]
+    pub fn delete_query(&self, id: i64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM saved_queries WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
+
+pub enum BrowserAction {
+    Treatment(i64),
+    Session(i64),
+    DicomFolder(PathBuf),
+}
+
+#[derive(Default)]
+pub struct DbBrowser {
+    date_from: String,
+    date_to: String,
+    protocol: String,
+    energy_min: String,
+    energy_max: String,
+    treatments: Vec<TreatmentRecord>,
+    sessions: Vec<SessionRecord>,
+    series: Vec<SeriesRecord>,
+    loaded: bool,
+    error: Option<String>,
+}
+
+#[inline(always)]
+fn non_empty(text: &str) -> Option<String> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+impl DbBrowser {
+    // Search again the next time the browser is shown
+    #[inline(always)]
+    pub fn refresh(&mut self) {
+        self.loaded = false;
+    }
+
+    #[inline(always)]
+    fn query(&self) -> Result<TreatmentQuery> {
+        let parse_date = |text: &str| -> Result<Option<String>> {
+            non_empty(text)
+                .map(|date| {
+                    chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                        .with_context(|| format!("{} is not a YYYY-MM-DD date", date))?;
+                    Ok(date)
+                })
+                .transpose()
+        };
+        let parse_energy = |text: &str| -> Result<Option<f64>> {
+            non_empty(text)
+                .map(|v| v.parse().with_context(|| format!("{} is not a number", v)))
+                .transpose()
+        };
+        Ok(TreatmentQuery {
+            date_from: parse_date(&self.date_from)?,
+            date_to: parse_date(&self.date_to)?,
+            protocol: non_empty(&self.protocol),
+            energy_min: parse_energy(&self.energy_min)?,
+            energy_max: parse_energy(&self.energy_max)?,
+        })
+    }
+
+    #[inline(always)]
+    fn load(&mut self, db: &TreatmentDb) {
+        let result = self.query().and_then(|query| {
+            self.treatments = db.search(&query)?;
+            self.sessions = db.sessions()?;
+            self.series = db.dicom_series()?;
+            Ok(())
+        });
+        self.error = result.err().map(|e| format!("{:#}", e));
+        self.loaded = true;
+    }
+
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui, db: &TreatmentDb) -> Option<BrowserAction> {
+        if !self.loaded {
+            self.load(db);
+        }
+        let mut action = None;
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("From:");
+            changed |= ui
+                .add(
+                    egui::TextEdit::singleline(&mut self.date_from)
+                        .hint_text("YYYY-MM-DD")
+                        .desired_width(90.0),
+                )
+                .changed();
+            ui.label("To:");
+            changed |= ui
+                .add(
+                    egui::TextEdit::singleline(&mut self.date_to)
+                        .hint_text("YYYY-MM-DD")
+                        .desired_width(90.0),
+                )
+                .changed();
+            ui.label("Protocol:");
+            changed |= ui
+                .add(egui::TextEdit::singleline(&mut self.protocol).desired_width(120.0))
+                .changed();
+            ui.label("Energy per subspot [J]:");
+            changed |= ui
+                .add(
+                    egui::TextEdit::singleline(&mut self.energy_min)
+                        .hint_text("min")
+                        .desired_width(50.0),
+                )
+                .changed();
+            changed |= ui
+                .add(
+                    egui::TextEdit::singleline(&mut self.energy_max)
+                        .hint_text("max")
+                        .desired_width(50.0),
+                )
+                .changed();
+            if ui.button("Refresh").clicked() {
+                changed = true;
+            }
+        });
+        if changed {
+            self.load(db);
+        }
+        if let Some(error) = &self.error {
+            ui.colored_label(Color32::RED, error);
+        }
+
+        let mut delete = None;
+        egui::CollapsingHeader::new(
+            RichText::new(format!("Treatments ({})", self.treatments.len())).size(20.0),
+        )
+        .default_open(true)
+        .show(ui, |ui| {
+            egui::ScrollArea::vertical()
+                .id_source("db_treatments")
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    Grid::new("db_treatments_grid")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for header in [
+                                "Start (UTC)",
+                                "Protocol",
+                                "Sonications",
+                                "Total energy [J]",
+                                "Max J/spot",
+                                "Source",
+                                "Loaded (UTC)",
+                                "",
+                                "",
+                            ] {
+                                ui.strong(header);
+                            }
+                            ui.end_row();
+                            for record in &self.treatments {
+                                ui.label(record.start_time.as_deref().unwrap_or("-"));
+                                ui.label(record.protocol.as_deref().unwrap_or("-"));
+                                ui.label(record.sonications.to_string());
+                                ui.label(
+                                    record
+                                        .total_energy
+                                        .map(|v| format!("{:.1}", v))
+                                        .unwrap_or_default(),
+                                );
+                                ui.label(
+                                    record
+                                        .max_energy
+                                        .map(|v| format!("{:.2}", v))
+                                        .unwrap_or_default(),
+                                );
+                                ui.label(&record.source);
+                                ui.label(&record.loaded_at);
+                                if ui.button("Open").clicked() {
+                                    action = Some(BrowserAction::Treatment(record.id));
+                                }
+                                if ui.button("Delete").clicked() {
+                                    delete = Some(record.id);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+        });
+        if let Some(id) = delete {
+            if let Err(e) = db.delete_treatment(id) {
+                self.error = Some(format!("{:#}", e));
+            } else {
+                self.load(db);
+            }
+        }
+
+        let mut delete = None;
+        egui::CollapsingHeader::new(
+            RichText::new(format!("Calculator sessions ({})", self.sessions.len())).size(20.0),
+        )
+        .show(ui, |ui| {
+            Grid::new("db_sessions_grid").striped(true).show(ui, |ui| {
+                for session in &self.sessions {
+                    ui.label(&session.name);
+                    ui.label(&session.saved_at);
+                    // The first row holds the column titles
+                    ui.label(format!("{} rows", session.rows.saturating_sub(1)));
+                    if ui.button("Open").clicked() {
+                        action = Some(BrowserAction::Session(session.id));
+                    }
+                    if ui.button("Delete").clicked() {
+                        delete = Some(session.id);
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+        if let Some(id) = delete {
+            if let Err(e) = db.delete_session(id) {
+                self.error = Some(format!("{:#}", e));
+            } else {
+                self.load(db);
+            }
+        }
+
+        egui::CollapsingHeader::new(
+            RichText::new(format!("DICOM series ({})", self.series.len())).size(20.0),
+        )
+        .show(ui, |ui| {
+            Grid::new("db_series_grid").striped(true).show(ui, |ui| {
+                for series in &self.series {
+                    ui.label(&series.folder);
+                    ui.label(&series.series_uid);
+                    ui.label(format!("{} images", series.images));
+                    ui.label(&series.indexed_at);
+                    if ui.button("Open folder").clicked() {
+                        action = Some(BrowserAction::DicomFolder(PathBuf::from(&series.folder)));
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn memory_db() -> TreatmentDb {
+        TreatmentDb::open(Path::new(":memory:")).unwrap()
+    }
+
+    #[inline(always)]
+    fn millis(text: &str) -> i64 {
+        chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S")
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    // Two sonications starting at start, the raw bytes carry the protocol name
+    #[inline(always)]
+    fn record(db: &mut TreatmentDb, source: &str, start: &str, energy: f64, protocol: &str) -> i64 {
+        let bytes = format!("Protocol Name,Start\n{},{}\n", protocol, start);
+        let df = df!(
+            "Time" => &[millis(start), millis(start) + 60_000],
+            "Energy per subspot" => &[energy / 2.0, energy],
+            "Num. of SubSonic" => &[2.0, 2.0],
+            "Mode" => &["Single", "Single"]
+        )
+        .unwrap();
+        db.record_treatment(source, bytes.as_bytes(), &df).unwrap()
+    }
+
+    #[inline(always)]
+    fn count(db: &TreatmentDb, table: &str) -> i64 {
+        db.conn
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[inline(always)]
+    fn sources(db: &TreatmentDb, query: TreatmentQuery) -> Vec<String> {
+        db.search(&query)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.source)
+            .collect()
+    }
+
+    #[test]
+    fn loading_the_same_summary_again_replaces_it() {
+        let mut db = memory_db();
+        record(&mut db, "a.csv", "2023-06-15 10:00:00", 100.0, "Brain");
+        record(&mut db, "copy/a.csv", "2023-06-15 10:00:00", 100.0, "Brain");
+        assert_eq!(sources(&db, TreatmentQuery::default()), vec!["copy/a.csv"]);
+        // The values of the replaced treatment are deleted with it
+        assert_eq!(count(&db, "sonication_values"), 2 * 4);
+        record(&mut db, "b.csv", "2023-06-16 10:00:00", 100.0, "Brain");
+        assert_eq!(count(&db, "treatments"), 2);
+    }
+
+    #[test]
+    fn stored_values_and_summary_columns() {
+        let mut db = memory_db();
+        let id = record(&mut db, "a.csv", "2023-06-15 10:00:00", 100.0, "Brain");
+        let record = &db.search(&TreatmentQuery::default()).unwrap()[0];
+        assert_eq!(record.start_time.as_deref(), Some("2023-06-15 10:00:00"));
+        assert_eq!(record.protocol.as_deref(), Some("Brain"));
+        assert_eq!(record.sonications, 2);
+        assert_eq!(record.total_energy, Some(300.0));
+        assert_eq!(record.max_energy, Some(100.0));
+        let value = |name: &str| -> Value {
+            db.conn
+                .query_row(
+                    "SELECT value FROM sonication_values
+                     WHERE treatment_id = ?1 AND row = 1 AND name = ?2",
+                    params![id, name],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(
+            value("Time"),
+            Value::Text("2023-06-15 10:01:00".to_string())
+        );
+        assert_eq!(value("Energy per subspot"), Value::Real(100.0));
+        assert_eq!(value("Mode"), Value::Text("Single".to_string()));
+    }
+
+    #[test]
+    fn search_filters() {
+        let mut db = memory_db();
+        record(&mut db, "a.csv", "2023-06-15 10:00:00", 100.0, "Brain A");
+        record(&mut db, "b.csv", "2023-06-16 23:30:00", 150.0, "Brain B");
+        record(&mut db, "c.csv", "2023-06-17 00:00:00", 200.0, "Spine");
+        let query = |from: &str, to: &str, protocol: &str, min: &str, max: &str| {
+            let browser = DbBrowser {
+                date_from: from.to_string(),
+                date_to: to.to_string(),
+                protocol: protocol.to_string(),
+                energy_min: min.to_string(),
+                energy_max: max.to_string(),
+                ..Default::default()
+            };
+            browser.query().unwrap()
+        };
+        assert_eq!(
+            sources(&db, query("", "", "", "", "")),
+            vec!["c.csv", "b.csv", "a.csv"]
+        );
+        // Both dates are inclusive
+        assert_eq!(
+            sources(&db, query("2023-06-16", "2023-06-16", "", "", "")),
+            vec!["b.csv"]
+        );
+        assert_eq!(
+            sources(&db, query("", "", "brain", "", "")),
+            vec!["b.csv", "a.csv"]
+        );
+        assert_eq!(
+            sources(&db, query("", "", "", "150", "200")),
+            vec!["c.csv", "b.csv"]
+        );
+        assert_eq!(
+            sources(&db, query("", "2023-06-16", "", "", "149.9")),
+            vec!["a.csv"]
+        );
+    }
+
+    #[test]
+    fn invalid_filters_are_reported() {
+        let browser = DbBrowser {
+            date_from: "15.06.2023".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            browser.query().unwrap_err().to_string(),
+            "15.06.2023 is not a YYYY-MM-DD date"
+        );
+        let browser = DbBrowser {
+            energy_min: "lots".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            browser.query().unwrap_err().to_string(),
+            "lots is not a number"
+        );
+    }
+
+    #[test]
+    fn migration_adds_and_fills_the_summary_hash() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE treatments (
+                id INTEGER PRIMARY KEY,
+                source TEXT NOT NULL,
+                loaded_at TEXT NOT NULL,
+                start_time TEXT,
+                end_time TEXT,
+                protocol TEXT,
+                sonications INTEGER NOT NULL,
+                total_energy REAL,
+                max_energy REAL,
+                summary BLOB NOT NULL
+            );
+            INSERT INTO treatments (source, loaded_at, sonications, summary)
+            VALUES ('old.csv', '2023-01-01 00:00:00', 0, x'414243');",
+        )
+        .unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        migrate(&conn).unwrap();
+        let hash: String = conn
+            .query_row("SELECT summary_hash FROM treatments", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(hash, summary_hash(b"ABC"));
+        let indexed = conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE name = 'treatments_hash'")
+            .unwrap()
+            .exists([])
+            .unwrap();
+        assert!(indexed);
+        // Running it again on a migrated database changes nothing
+        migrate(&conn).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+use crate::summary;
+use eframe::egui::{self, Color32, Grid, RichText};
+use polars::prelude::*;
+use std::collections::HashMap;
+
+// Actual values are exported next to the planned ones as "Act. <planned name>"
+const ACTUAL_PREFIX: &str = "Act. ";
+const OUT_OF_TOLERANCE_COLOR: Color32 = Color32::from_rgb(230, 100, 30);
+
+#[inline(always)]
+pub fn deviation_column(planned: &str) -> String {
+    format!("Dev. {}", planned)
+}
+
+#[inline(always)]
+pub fn percent_column(planned: &str) -> String {
+    format!("Dev. % {}", planned)
+}
+
+// Planned columns that have a numeric actual counterpart
+#[inline(always)]
+pub fn planned_actual_pairs(df: &DataFrame) -> Vec<String> {
+    df.get_columns()
+        .iter()
+        .filter(|series| series.dtype().is_numeric())
+        .filter_map(|series| series.name().strip_prefix(ACTUAL_PREFIX))
+        .filter(|planned| {
+            df.column(planned)
+                .is_ok_and(|series| series.dtype().is_numeric())
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+// Actual - planned and the same relative to the planned value, for every pair
+#[inline(always)]
+pub fn add_deviation_columns(df: &mut DataFrame) -> PolarsResult<()> {
+    for planned in planned_actual_pairs(df) {
+        let (Some(plan), Some(actual)) = (
+            summary::float_values(df, &planned),
+            summary::float_values(df, &format!("{}{}", ACTUAL_PREFIX, planned)),
+        ) else {
+            continue;
+        };
+        let deviation: Vec<Option<f64>> = plan
+            .iter()
+            .zip(&actual)
+            .map(|(plan, actual)| Some((*actual)? - (*plan)?))
+            .collect();
+        let percent: Vec<Option<f64>> = plan
+            .iter()
+            .zip(&deviation)
+            .map(|(plan, deviation)| {
+                let (plan, deviation) = ((*plan)?, (*deviation)?);
+                (plan != 0.0).then(|| deviation / plan * 100.0)
+            })
+            .collect();
+        df.with_column(Series::new(&deviation_column(&planned), &deviation))?;
+        df.with_column(Series::new(&percent_column(&planned), &percent))?;
+    }
+    Ok(())
+}
+
+struct PairStats {
+    planned: String,
+    // Deviation in % per row
+    percent: Vec<Option<f64>>,
+}
+
+pub struct DeviationCheck {
+    // Allowed deviation in % per planned column, the default covers the others
+    pub default_tolerance: f64,
+    pub tolerances: HashMap<String, f64>,
+    // The treatment fails when more rows than this are out of tolerance
+    pub max_failures: usize,
+    pairs: Vec<PairStats>,
+}
+
+impl Default for DeviationCheck {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            default_tolerance: 10.0,
+            tolerances: HashMap::new(),
+            max_failures: 0,
+            pairs: Vec::new(),
+        }
+    }
+}
+
+impl DeviationCheck {
+    #[inline(always)]
+    pub fn analyse(&mut self, df: &DataFrame) {
+        self.pairs = planned_actual_pairs(df)
+            .into_iter()
+            .filter_map(|planned| {
+                let percent = summary::float_values(df, &percent_column(&planned))?;
+                Some(PairStats { planned, percent })
+            })
+            .collect();
+    }
+
+    #[inline(always)]
+    fn tolerance(&self, planned: &str) -> f64 {
+        self.tolerances
+            .get(planned)
+            .copied()
+            .unwrap_or(self.default_tolerance)
+    }
+
+    // Reasons per row that is out of tolerance for at least one pair
+    #[inline(always)]
+    fn failures(&self) -> HashMap<usize, Vec<String>> {
+        let mut rows: HashMap<usize, Vec<String>> = HashMap::new();
+        for pair in &self.pairs {
+            let tolerance = self.tolerance(&pair.planned);
+            for (row, percent) in pair.percent.iter().enumerate() {
+                if let Some(percent) = percent.filter(|p| p.abs() > tolerance) {
+                    rows.entry(row)
+                        .or_default()
+                        .push(format!("{} {:+.1} %", pair.planned, percent));
+                }
+            }
+        }
+        rows
+    }
+
+    #[inline(always)]
+    pub fn passed(&self) -> bool {
+        self.failures().len() <= self.max_failures
+    }
+
+    #[inline(always)]
+    pub fn highlights(&self) -> HashMap<usize, (Color32, String)> {
+        self.failures()
+            .into_iter()
+            .map(|(row, reasons)| {
+                (
+                    row,
+                    (
+                        OUT_OF_TOLERANCE_COLOR,
+                        format!("Out of tolerance: {}", reasons.join(", ")),
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    // Returns true when a tolerance changed and the highlights need to be updated
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui) -> bool {
+        if self.pairs.is_empty() {
+            ui.label("This export has no planned and actual column pairs");
+            return false;
+        }
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut self.default_tolerance)
+                        .clamp_range(0.0..=100.0)
+                        .speed(0.1)
+                        .prefix("Default tolerance ± ")
+                        .suffix(" %"),
+                )
+                .changed();
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut self.max_failures)
+                        .clamp_range(0..=1000)
+                        .prefix("Fail with more than ")
+                        .suffix(" rows out of tolerance"),
+                )
+                .changed();
+        });
+        let failures = self.failures();
+        let verdict = if failures.len() <= self.max_failures {
+            RichText::new(format!(
+                "QA PASS ({} rows out of tolerance)",
+                failures.len()
+            ))
+            .color(Color32::GREEN)
+        } else {
+            RichText::new(format!(
+                "QA FAIL ({} rows out of tolerance)",
+                failures.len()
+            ))
+            .color(Color32::RED)
+        };
+        ui.label(verdict.strong().size(18.0));
+        Grid::new("deviation_pairs").striped(true).show(ui, |ui| {
+            ui.strong("Planned column");
+            ui.strong("Tolerance ± %");
+            ui.strong("Mean dev. %");
+            ui.strong("Max |dev.| %");
+            ui.strong("Rows out");
+            ui.end_row();
+            for pair in &self.pairs {
+                ui.label(&pair.planned);
+                let mut tolerance = self
+                    .tolerances
+                    .get(&pair.planned)
+                    .copied()
+                    .unwrap_or(self.default_tolerance);
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut tolerance)
+                            .clamp_range(0.0..=100.0)
+                            .speed(0.1),
+                    )
+                    .changed()
+                {
+                    self.tolerances.insert(pair.planned.clone(), tolerance);
+                    changed = true;
+                }
+                let present: Vec<f64> = pair.percent.iter().flatten().copied().collect();
+                ui.label(if present.is_empty() {
+                    String::new()
+                } else {
+                    format!("{:+.2}", present.iter().sum::<f64>() / present.len() as f64)
+                });
+                ui.label(
+                    present
+                        .iter()
+                        .map(|p| p.abs())
+                        .reduce(f64::max)
+                        .map(|p| format!("{:.2}", p))
+                        .unwrap_or_default(),
+                );
+                let out = present.iter().filter(|p| p.abs() > tolerance).count();
+                if out > 0 {
+                    ui.colored_label(OUT_OF_TOLERANCE_COLOR, out.to_string());
+                } else {
+                    ui.label("0");
+                }
+                ui.end_row();
+            }
+        });
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn summary() -> DataFrame {
+        let mut df = df!(
+            "Energy[J]" => &[Some(100.0), Some(0.0), Some(200.0), Some(100.0)],
+            "Act. Energy[J]" => &[Some(105.0), Some(5.0), None, Some(80.0)],
+            "Power[W]" => &[50.0, 50.0, 50.0, 50.0],
+            "Act. Power[W]" => &[54.0, 50.0, 50.0, 50.0],
+            "Act. Mode" => &["a", "b", "c", "d"],
+            "Mode" => &["a", "b", "c", "d"]
+        )
+        .unwrap();
+        add_deviation_columns(&mut df).unwrap();
+        df
+    }
+
+    #[inline(always)]
+    fn check(df: &DataFrame) -> DeviationCheck {
+        let mut check = DeviationCheck::default();
+        check.analyse(df);
+        check
+    }
+
+    #[inline(always)]
+    fn failing_rows(check: &DeviationCheck) -> Vec<usize> {
+        let mut rows: Vec<usize> = check.failures().into_keys().collect();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn deviations_of_numeric_pairs() {
+        let df = summary();
+        assert_eq!(planned_actual_pairs(&df), vec!["Energy[J]", "Power[W]"]);
+        assert!(df.column("Dev. Mode").is_err());
+        assert_eq!(
+            summary::float_values(&df, "Dev. Energy[J]").unwrap(),
+            vec![Some(5.0), Some(5.0), None, Some(-20.0)]
+        );
+        // Nothing was planned in the second row, so there is no relative deviation
+        assert_eq!(
+            summary::float_values(&df, "Dev. % Energy[J]").unwrap(),
+            vec![Some(5.0), None, None, Some(-20.0)]
+        );
+    }
+
+    #[test]
+    fn rows_beyond_the_tolerance_fail() {
+        let check = check(&summary());
+        assert_eq!(failing_rows(&check), vec![3]);
+        assert_eq!(check.failures()[&3], vec!["Energy[J] -20.0 %"]);
+    }
+
+    #[test]
+    fn column_tolerance_overrides_the_default() {
+        let mut check = check(&summary());
+        check.default_tolerance = 4.0;
+        assert_eq!(failing_rows(&check), vec![0, 3]);
+        check.tolerances.insert("Energy[J]".to_string(), 25.0);
+        assert_eq!(failing_rows(&check), vec![0]);
+        assert_eq!(check.failures()[&0], vec!["Power[W] +8.0 %"]);
+    }
+
+    #[test]
+    fn max_failures_is_inclusive() {
+        let mut check = check(&summary());
+        check.default_tolerance = 4.0;
+        check.max_failures = 1;
+        assert!(!check.passed());
+        check.max_failures = 2;
+        assert!(check.passed());
+    }
+}
@@ -0,0 +1,408 @@
+use anyhow::{bail, Result};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+// Delimiters tried when none is given
+pub const DELIMITERS: [(u8, &str); 4] = [(b',', ","), (b';', ";"), (b'\t', "Tab"), (b'|', "|")];
+// Records looked at when detecting the dialect
+const SAMPLE_RECORDS: usize = 50;
+// The header has to be within the first lines of the file
+const MAX_HEADER_ROW: usize = 20;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    // Read as Windows-1252, the superset regional consoles write
+    Latin1,
+}
+
+impl TextEncoding {
+    pub const ALL: [TextEncoding; 4] = [
+        TextEncoding::Utf8,
+        TextEncoding::Utf16Le,
+        TextEncoding::Utf16Be,
+        TextEncoding::Latin1,
+    ];
+
+    #[inline(always)]
+    pub fn label(self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => "UTF-8",
+            TextEncoding::Utf16Le => "UTF-16 LE",
+            TextEncoding::Utf16Be => "UTF-16 BE",
+            TextEncoding::Latin1 => "Latin-1",
+        }
+    }
+
+    #[inline(always)]
+    fn encoding(self) -> &'static Encoding {
+        match self {
+            TextEncoding::Utf8 => UTF_8,
+            TextEncoding::Utf16Le => UTF_16LE,
+            TextEncoding::Utf16Be => UTF_16BE,
+            TextEncoding::Latin1 => WINDOWS_1252,
+        }
+    }
+
+    #[inline(always)]
+    fn from_encoding(encoding: &'static Encoding) -> Self {
+        if encoding == UTF_16LE {
+            TextEncoding::Utf16Le
+        } else if encoding == UTF_16BE {
+            TextEncoding::Utf16Be
+        } else {
+            TextEncoding::Utf8
+        }
+    }
+}
+
+// Manual choices in the time settings, None is detected from the file
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct DialectOverrides {
+    pub encoding: Option<TextEncoding>,
+    pub delimiter: Option<u8>,
+    pub decimal_comma: Option<bool>,
+    pub header_row: Option<usize>,
+}
+
+// What was used to read a file
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Dialect {
+    pub encoding: TextEncoding,
+    pub bom: bool,
+    pub delimiter: u8,
+    pub decimal_comma: bool,
+    // Lines before the header are skipped
+    pub header_row: usize,
+}
+
+impl Default for Dialect {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            encoding: TextEncoding::Utf8,
+            bom: false,
+            delimiter: b',',
+            decimal_comma: false,
+            header_row: 0,
+        }
+    }
+}
+
+impl Dialect {
+    #[inline(always)]
+    pub fn delimiter_label(&self) -> String {
+        DELIMITERS
+            .iter()
+            .find(|(delimiter, _)| *delimiter == self.delimiter)
+            .map(|(_, label)| label.to_string())
+            .unwrap_or_else(|| (self.delimiter as char).to_string())
+    }
+
+    #[inline(always)]
+    pub fn describe(&self) -> String {
+        format!(
+            "{}{}, delimiter {}, decimal {}, header on line {}",
+            self.encoding.label(),
+            if self.bom { " with BOM" } else { "" },
+            self.delimiter_label(),
+            if self.decimal_comma { "comma" } else { "point" },
+            self.header_row + 1
+        )
+    }
+}
+
+// UTF-16 without a BOM shows up as every other byte being zero
+#[inline(always)]
+fn detect_encoding(bytes: &[u8]) -> TextEncoding {
+    let sample = &bytes[..bytes.len().min(4096)];
+    let zeros = |offset: usize| {
+        sample
+            .iter()
+            .skip(offset)
+            .step_by(2)
+            .filter(|b| **b == 0)
+            .count()
+    };
+    let half = sample.len() / 2;
+    if half > 0 && zeros(1) * 3 > half {
+        TextEncoding::Utf16Le
+    } else if half > 0 && zeros(0) * 3 > half {
+        TextEncoding::Utf16Be
+    } else if std::str::from_utf8(bytes).is_ok() {
+        TextEncoding::Utf8
+    } else {
+        TextEncoding::Latin1
+    }
+}
+
+#[inline(always)]
+fn records(text: &str, delimiter: u8, limit: usize) -> Vec<Vec<String>> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(text.as_bytes())
+        .records()
+        .filter_map(|record| record.ok())
+        .take(limit)
+        .map(|record| record.iter().map(str::to_string).collect())
+        .collect()
+}
+
+// Most common field count and how many records have it
+#[inline(always)]
+fn modal_width(records: &[Vec<String>]) -> (usize, usize) {
+    let mut counts: Vec<(usize, usize)> = Vec::new();
+    for record in records {
+        match counts.iter_mut().find(|(width, _)| *width == record.len()) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((record.len(), 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(width, count)| (*count, *width))
+        .unwrap_or_default()
+}
+
+// The delimiter that splits most records into the same number of fields
+#[inline(always)]
+fn detect_delimiter(text: &str) -> u8 {
+    DELIMITERS
+        .iter()
+        .map(|(delimiter, _)| {
+            let (width, count) = modal_width(&records(text, *delimiter, SAMPLE_RECORDS));
+            (*delimiter, if width > 1 { count * width } else { 0 })
+        })
+        .max_by_key(|(_, score)| *score)
+        .filter(|(_, score)| *score > 0)
+        .map_or(b',', |(delimiter, _)| delimiter)
+}
+
+// The row naming the "Time" column, otherwise the first row as wide as the data
+#[inline(always)]
+fn detect_header(records: &[Vec<String>]) -> usize {
+    let limit = records.len().min(MAX_HEADER_ROW);
+    if let Some(row) = records[..limit]
+        .iter()
+        .position(|record| record.iter().any(|field| field.trim() == "Time"))
+    {
+        return row;
+    }
+    let (width, _) = modal_width(records);
+    records[..limit]
+        .iter()
+        .position(|record| record.len() == width)
+        .unwrap_or(0)
+}
+
+#[inline(always)]
+fn is_decimal(field: &str, separator: char) -> bool {
+    let field = field.trim().trim_start_matches('-');
+    match field.split_once(separator) {
+        Some((whole, fraction)) => {
+            !whole.is_empty()
+                && !fraction.is_empty()
+                && whole.chars().all(|c| c.is_ascii_digit())
+                && fraction.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+// A comma delimiter rules out decimal commas, otherwise the majority wins
+#[inline(always)]
+fn detect_decimal_comma(records: &[Vec<String>], delimiter: u8) -> bool {
+    if delimiter == b',' {
+        return false;
+    }
+    let fields = || records.iter().flatten();
+    let commas = fields().filter(|field| is_decimal(field, ',')).count();
+    let points = fields().filter(|field| is_decimal(field, '.')).count();
+    commas > points
+}
+
+// Decode the bytes, skip to the header and rewrite them as UTF-8 with commas and decimal points
+#[inline(always)]
+pub fn normalize(bytes: &[u8], overrides: &DialectOverrides) -> Result<(Vec<u8>, Dialect)> {
+    let bom = Encoding::for_bom(bytes);
+    let encoding = overrides.encoding.unwrap_or_else(|| match bom {
+        Some((encoding, _)) => TextEncoding::from_encoding(encoding),
+        None => detect_encoding(bytes),
+    });
+    let bom_length = match bom {
+        Some((found, length)) if found == encoding.encoding() => length,
+        _ => 0,
+    };
+    let (text, had_errors) = encoding
+        .encoding()
+        .decode_without_bom_handling(&bytes[bom_length..]);
+    if had_errors && overrides.encoding.is_none() && encoding != TextEncoding::Latin1 {
+        bail!("the file is not valid {}", encoding.label());
+    }
+
+    let delimiter = overrides
+        .delimiter
+        .unwrap_or_else(|| detect_delimiter(&text));
+    let sample = records(&text, delimiter, SAMPLE_RECORDS + MAX_HEADER_ROW);
+    let header_row = overrides
+        .header_row
+        .unwrap_or_else(|| detect_header(&sample));
+    let decimal_comma = overrides.decimal_comma.unwrap_or_else(|| {
+        detect_decimal_comma(&sample[header_row.min(sample.len())..], delimiter)
+    });
+    let dialect = Dialect {
+        encoding,
+        bom: bom_length > 0,
+        delimiter,
+        decimal_comma,
+        header_row,
+    };
+    if dialect == Dialect::default() {
+        return Ok((bytes.to_vec(), dialect));
+    }
+
+    let mut wtr = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_writer(Vec::with_capacity(text.len()));
+    for record in records(&text, delimiter, usize::MAX)
+        .into_iter()
+        .skip(header_row)
+    {
+        if decimal_comma {
+            let fields: Vec<String> = record
+                .iter()
+                .map(|field| {
+                    if is_decimal(field, ',') {
+                        field.replacen(',', ".", 1)
+                    } else {
+                        field.clone()
+                    }
+                })
+                .collect();
+            wtr.write_record(&fields)?;
+        } else {
+            wtr.write_record(&record)?;
+        }
+    }
+    Ok((wtr.into_inner()?, dialect))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn normalized(bytes: &[u8]) -> (String, Dialect) {
+        let (bytes, dialect) = normalize(bytes, &DialectOverrides::default()).unwrap();
+        (String::from_utf8(bytes).unwrap(), dialect)
+    }
+
+    #[test]
+    fn plain_csv_is_passed_through() {
+        let text = "Time,Energy[J]\n20230101120000,1.5\n";
+        let (out, dialect) = normalized(text.as_bytes());
+        assert_eq!(dialect, Dialect::default());
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn semicolons_and_decimal_commas_are_rewritten() {
+        let text =
+            "Time;Energy[J];Num. of SubSonic\n20230101120000;1,5;4\n20230101120100;-2,25;4\n";
+        let (out, dialect) = normalized(text.as_bytes());
+        assert_eq!(dialect.delimiter, b';');
+        assert!(dialect.decimal_comma);
+        assert_eq!(
+            out,
+            "Time,Energy[J],Num. of SubSonic\n20230101120000,1.5,4\n20230101120100,-2.25,4\n"
+        );
+    }
+
+    #[test]
+    fn detects_tab_delimiter() {
+        let text = "Time\tEnergy[J]\tNum. of SubSonic\n1\t2.5\t3\n4\t5.5\t6\n";
+        assert_eq!(detect_delimiter(text), b'\t');
+    }
+
+    #[test]
+    fn comma_delimiter_rules_out_decimal_commas() {
+        let quoted = records("a,b\n\"1,5\",\"2,5\"\n", b',', SAMPLE_RECORDS);
+        assert!(!detect_decimal_comma(&quoted, b','));
+        let commas = records_of(&[&["1,5", "2,5", "3.5"]]);
+        assert!(detect_decimal_comma(&commas, b';'));
+        let points = records_of(&[&["1.5", "2.5", "3,5"]]);
+        assert!(!detect_decimal_comma(&points, b';'));
+    }
+
+    #[inline(always)]
+    fn records_of(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|field| field.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn only_plain_numbers_are_decimals() {
+        assert!(is_decimal("1,5", ','));
+        assert!(is_decimal(" -12,05 ", ','));
+        assert!(!is_decimal("1,5,6", ','));
+        assert!(!is_decimal("1,", ','));
+        assert!(!is_decimal(",5", ','));
+        assert!(!is_decimal("Energy, planned", ','));
+        assert!(!is_decimal("15", ','));
+    }
+
+    #[test]
+    fn lines_before_the_header_are_skipped() {
+        let text = "Exported by console\nVersion 2.1\nTime,Energy[J]\n20230101120000,1.5\n";
+        let (out, dialect) = normalized(text.as_bytes());
+        assert_eq!(dialect.header_row, 2);
+        assert_eq!(out, "Time,Energy[J]\n20230101120000,1.5\n");
+    }
+
+    #[test]
+    fn utf16_with_bom_is_decoded() {
+        let text = "Time;Energy[J]\n20230101120000;1,5\n";
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        let (out, dialect) = normalized(&bytes);
+        assert_eq!(dialect.encoding, TextEncoding::Utf16Le);
+        assert!(dialect.bom);
+        assert_eq!(out, "Time,Energy[J]\n20230101120000,1.5\n");
+    }
+
+    #[test]
+    fn utf16_without_bom_is_detected() {
+        let text = "Time,Energy[J]\n20230101120000,1.5\n";
+        let bytes: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(detect_encoding(&bytes), TextEncoding::Utf16Be);
+    }
+
+    #[test]
+    fn invalid_utf8_is_read_as_latin1() {
+        // 0xB5 is the micro sign in Windows-1252
+        let bytes = b"Time,Pulse Duration [\xB5s]\n20230101120000,10\n";
+        let (out, dialect) = normalized(bytes);
+        assert_eq!(dialect.encoding, TextEncoding::Latin1);
+        assert_eq!(out, "Time,Pulse Duration [µs]\n20230101120000,10\n");
+    }
+
+    #[test]
+    fn overrides_win_over_detection() {
+        let text = "Time;Energy[J]\n20230101120000;1,5\n";
+        let overrides = DialectOverrides {
+            decimal_comma: Some(false),
+            ..Default::default()
+        };
+        let (bytes, dialect) = normalize(text.as_bytes(), &overrides).unwrap();
+        assert!(!dialect.decimal_comma);
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "Time,Energy[J]\n20230101120000,\"1,5\"\n"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    jobs::JobProgress,
+    redaction::{DicomRedaction, Redaction},
+};
+use anyhow::{Context, Result};
+use dashmap::{DashMap, DashSet};
+use dicom::object::{open_file, FileDicomObject, InMemDicomObject, Tag};
+use jwalk::WalkDirGeneric;
+use rayon::prelude::*;
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Mutex,
+    },
+};
+
+// DICOM objects grouped by Series Instance UID
+pub type DicomSeries = DashMap<String, Vec<(FileDicomObject<InMemDicomObject>, PathBuf)>>;
+
+pub const TAGS_TO_ANONYMIZE: [Tag; 26] = [
+    Tag(0x0008, 0x0014), // Instance Creator UID
+    Tag(0x0008, 0x0018), // SOP Instance UID
+    Tag(0x0008, 0x0050), // Accession Number
+    Tag(0x0008, 0x0080), // Institution Name
+    Tag(0x0008, 0x0081), // Institution Address
+    Tag(0x0008, 0x0090), // Referring Physician's Name
+    Tag(0x0008, 0x0092), // Referring Physician's Address
+    Tag(0x0008, 0x0094), // Referring Physician's Telephone numbers
+    Tag(0x0008, 0x1010), // Station Name
+    Tag(0x0008, 0x1030), // Study Description
+    Tag(0x0008, 0x103E), // Series Description
+    Tag(0x0008, 0x1040), // Institutional Department name
+    Tag(0x0008, 0x1048), // Physician(s) of Record
+    Tag(0x0008, 0x1050), // Performing Physicians' Name
+    Tag(0x0008, 0x1060), // Name of Physician(s) Reading study
+    Tag(0x0008, 0x1070), // Operator's Name
+    Tag(0x0008, 0x1080), // Admitting Diagnoses Description
+    Tag(0x0008, 0x1155), // Referenced SOP Instance UID
+    Tag(0x0008, 0x2111), // Derivation Description
+    Tag(0x0010, 0x0010), // Patient's Name
+    Tag(0x0010, 0x0020), // Patient ID
+    Tag(0x0010, 0x0030), // Patient Birth Day
+    Tag(0x0010, 0x0032), // Patient's Birth Time
+    Tag(0x0010, 0x0040), // Patient's Sex
+    Tag(0x0010, 0x1010), // Patient's Age
+    Tag(0x0014, 1001),   // Custom Tag
+];
+
+#[inline(always)]
+pub fn get_image_position(obj: &InMemDicomObject) -> Option<f64> {
+    obj.element(Tag(0x0020, 0x0032))
+        .ok()
+        .and_then(|e| e.to_multi_float64().ok())
+        .map(|v| v[2])
+}
+
+// Open every DICOM file below path and sort it into its series
+#[inline(always)]
+pub fn index_folder(
+    path: &Path,
+    presorted: &DicomSeries,
+    unique_ids: &DashSet<String>,
+    progress: &JobProgress,
+) -> Result<String> {
+    let files: Vec<PathBuf> = WalkDirGeneric::<(u32, bool)>::new(path)
+        .process_read_dir(|_depth, _path, read_dir_state, _children| {
+            *read_dir_state += 1;
+        })
+        .try_into_iter()
+        .context("Cannot Make Iterator")?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.path())
+        .collect();
+    progress.set_total(files.len());
+
+    let objects: Vec<_> = files
+        .into_par_iter()
+        .filter_map(|file| {
+            if progress.is_cancelled() {
+                return None;
+            }
+            let object = open_file(&file).ok();
+            progress.inc();
+            object.map(|object| (object, file))
+        })
+        .collect();
+    progress.check()?;
+    let count = objects.len();
+
+    objects.into_par_iter().for_each(|(object, file)| {
+        if let Ok(series_instance_uid) = object.element(Tag(0x0020, 0x000E)) {
+            if let Ok(uid) = series_instance_uid.to_str() {
+                // Assuming `sop_instance_uid` is a unique identifier for each DICOM object
+                if let Ok(sop_instance_uid) = object.element(Tag(0x0008, 0x0018)) {
+                    if let Ok(sop_id) = sop_instance_uid.to_str() {
+                        if unique_ids.insert(sop_id.to_string()) {
+                            presorted
+                                .entry(uid.to_string())
+                                .or_default()
+                                .push((object, file));
+                        }
+                    }
+                }
+            }
+        }
+    });
+    presorted.par_iter_mut().for_each(|mut series| {
+        series.par_sort_by(|(a, _), (b, _)| {
+            get_image_position(a)
+                .partial_cmp(&get_image_position(b))
+                .unwrap_or(Ordering::Equal)
+        });
+    });
+
+    Ok(format!(
+        "Indexed {} DICOM files in {} series",
+        count,
+        presorted.len()
+    ))
+}
+
+#[inline(always)]
+fn write_series(
+    objects: &[(FileDicomObject<InMemDicomObject>, PathBuf)],
+    new_dir: &Path,
+    progress: &JobProgress,
+) -> Result<()> {
+    std::fs::create_dir_all(new_dir).context("Failed to create directory")?;
+    for (object, file) in objects {
+        progress.check()?;
+        let new_path = new_dir.join(file.file_name().context("Failed to get file name")?);
+        object
+            .write_to_file(&new_path)
+            .with_context(|| format!("Unable to write {}", new_path.display()))?;
+        progress.inc();
+    }
+    Ok(())
+}
+
+// Copy the selected series (or every series when none is selected) to processed/sorted
+#[inline(always)]
+pub fn move_sorted(
+    path: &Path,
+    presorted: &DicomSeries,
+    selected_uid: &str,
+    progress: &JobProgress,
+) -> Result<String> {
+    if let Some(objects) = presorted.get(selected_uid) {
+        progress.set_total(objects.len());
+        write_series(
+            objects.value(),
+            &path.join(format!("processed/sorted/{}", selected_uid)),
+            progress,
+        )?;
+        return Ok(format!(
+            "Copied {} files of {}",
+            objects.len(),
+            selected_uid
+        ));
+    }
+    progress.set_total(presorted.iter().map(|entry| entry.value().len()).sum());
+    // Process each object and its path in parallel using rayon
+    presorted.par_iter().try_for_each(|entry| {
+        write_series(
+            entry.value(),
+            &path.join(format!("processed/sorted/{}", entry.key())),
+            progress,
+        )
+    })?;
+    Ok(format!("Copied {} series", presorted.len()))
+}
+
+// Files that were withheld are listed in the result, up to this many
+const WITHHELD_SHOWN: usize = 10;
+
+// Anonymized copies go to processed/anonymized, the indexed objects are left untouched.
+// With redaction on, files whose pixels cannot be blacked out are not written at all.
+#[inline(always)]
+pub fn anonymize(
+    path: &Path,
+    presorted: &DicomSeries,
+    redaction: Option<&Redaction>,
+    progress: &JobProgress,
+) -> Result<String> {
+    progress.set_total(presorted.iter().map(|entry| entry.value().len()).sum());
+    let written = AtomicUsize::new(0);
+    let redacted = AtomicUsize::new(0);
+    let withheld = Mutex::new(Vec::new());
+    // Anonymize each object in parallel using rayon
+    presorted.par_iter().try_for_each(|entry| {
+        let new_dir = path.join(format!("processed/anonymized/{}", entry.key()));
+        std::fs::create_dir_all(&new_dir).context("Failed to create directory")?;
+
+        for (object, file) in entry.value() {
+            progress.check()?;
+            let mut object = object.clone();
+            for tag in TAGS_TO_ANONYMIZE {
+                object.remove_element(tag);
+            }
+            // Save the anonymized DICOM object to disk with a new name
+            let new_file_name = format!(
+                "{}_anonymized.dcm",
+                file.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .context("Failed to get file name")?
+            );
+            let new_path = new_dir.join(new_file_name);
+            if let Some(redaction) = redaction {
+                let reason = match redaction.redact_dicom(&mut object) {
+                    Ok(DicomRedaction::Redacted) => {
+                        redacted.fetch_add(1, AtomicOrdering::Relaxed);
+                        None
+                    }
+                    Ok(DicomRedaction::NoPixelData) => None,
+                    Ok(DicomRedaction::NoTemplate) => {
+                        Some("no template for the image size".to_string())
+                    }
+                    Ok(DicomRedaction::Skipped(reason)) => Some(reason),
+                    Err(e) => Some(format!("{:#}", e)),
+                };
+                if let Some(reason) = reason {
+                    // Do not leave a copy from an earlier run behind
+                    let _ = std::fs::remove_file(&new_path);
+                    let name = file.file_name().unwrap_or_default().to_string_lossy();
+                    withheld
+                        .lock()
+                        .unwrap()
+                        .push(format!("{}: {}", name, reason));
+                    progress.inc();
+                    continue;
+                }
+            }
+            object
+                .write_to_file(&new_path)
+                .with_context(|| format!("Unable to write {}", new_path.display()))?;
+            written.fetch_add(1, AtomicOrdering::Relaxed);
+            progress.inc();
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+    let mut summary = format!(
+        "Anonymized {} files in {} series",
+        written.into_inner(),
+        presorted.len()
+    );
+    if redaction.is_some() {
+        let withheld = withheld.into_inner().unwrap();
+        summary += &format!(
+            ", blacked out {} images, withheld {} that could not be redacted",
+            redacted.into_inner(),
+            withheld.len()
+        );
+        for line in withheld.iter().take(WITHHELD_SHOWN) {
+            summary += &format!("\n{}", line);
+        }
+        if withheld.len() > WITHHELD_SHOWN {
+            summary += &format!("\n… and {} more", withheld.len() - WITHHELD_SHOWN);
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,659 @@
+use crate::units::UnitRegistry;
+use anyhow::{bail, Result};
+use eframe::egui::{self, RichText};
+use polars::prelude::*;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+const PREVIEW_ROWS: usize = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+    Ipc,
+    Json,
+    Xlsx,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 5] = [
+        ExportFormat::Csv,
+        ExportFormat::Parquet,
+        ExportFormat::Ipc,
+        ExportFormat::Json,
+        ExportFormat::Xlsx,
+    ];
+
+    #[inline(always)]
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "CSV",
+            ExportFormat::Parquet => "Parquet",
+            ExportFormat::Ipc => "Arrow IPC",
+            ExportFormat::Json => "JSON",
+            ExportFormat::Xlsx => "Excel (XLSX)",
+        }
+    }
+
+    #[inline(always)]
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Ipc => "arrow",
+            ExportFormat::Json => "json",
+            ExportFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+// Where the unit of each column goes
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum UnitsPlacement {
+    None,
+    // Second row of CSV and XLSX, binary formats fall back to the sidecar
+    #[default]
+    Row,
+    // <name>.units.csv next to the export
+    Sidecar,
+}
+
+impl UnitsPlacement {
+    pub const ALL: [UnitsPlacement; 3] = [
+        UnitsPlacement::None,
+        UnitsPlacement::Row,
+        UnitsPlacement::Sidecar,
+    ];
+
+    #[inline(always)]
+    pub fn name(self) -> &'static str {
+        match self {
+            UnitsPlacement::None => "None",
+            UnitsPlacement::Row => "Row below the header",
+            UnitsPlacement::Sidecar => "Sidecar file",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CsvOptions {
+    pub precision: usize,
+    pub delimiter: char,
+    pub decimal: char,
+}
+
+impl Default for CsvOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        // Same output as the former "_simplify.csv"
+        Self {
+            precision: 2,
+            delimiter: ',',
+            decimal: '.',
+        }
+    }
+}
+
+#[inline(always)]
+fn csv_value(value: &AnyValue, options: &CsvOptions) -> String {
+    let text = match value {
+        AnyValue::Null => return String::new(),
+        AnyValue::Float64(v) => format!("{:.*}", options.precision, v),
+        AnyValue::Float32(v) => format!("{:.*}", options.precision, v),
+        AnyValue::Utf8(v) => return v.to_string(),
+        other => format!("{}", other),
+    };
+    if options.decimal != '.' && matches!(value, AnyValue::Float64(_) | AnyValue::Float32(_)) {
+        text.replace('.', &options.decimal.to_string())
+    } else {
+        text
+    }
+}
+
+#[inline(always)]
+pub fn write_csv<W: Write>(df: &DataFrame, writer: W, options: &CsvOptions) -> Result<()> {
+    write_csv_with_units(df, writer, options, None)
+}
+
+#[inline(always)]
+fn write_csv_with_units<W: Write>(
+    df: &DataFrame,
+    writer: W,
+    options: &CsvOptions,
+    units: Option<&[String]>,
+) -> Result<()> {
+    if !options.delimiter.is_ascii() || options.delimiter == options.decimal {
+        bail!("the delimiter must be an ASCII character different from the decimal separator");
+    }
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(options.delimiter as u8)
+        .from_writer(writer);
+    wtr.write_record(df.get_column_names())?;
+    if let Some(units) = units {
+        wtr.write_record(units)?;
+    }
+    let columns = df.get_columns();
+    for row in 0..df.height() {
+        let record: Vec<String> = columns
+            .iter()
+            .map(|series| {
+                series
+                    .get(row)
+                    .map(|value| csv_value(&value, options))
+                    .unwrap_or_default()
+            })
+            .collect();
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[inline(always)]
+fn write_xlsx(df: &DataFrame, path: &Path, units: Option<&[String]>) -> Result<()> {
+    let mut workbook = rust_xlsxwriter::Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    for (col, name) in df.get_column_names().iter().enumerate() {
+        worksheet.write_string(0, col as u16, *name)?;
+    }
+    if let Some(units) = units {
+        for (col, unit) in units.iter().enumerate() {
+            worksheet.write_string(1, col as u16, unit)?;
+        }
+    }
+    let first_row = if units.is_some() { 2 } else { 1 };
+    for (col, series) in df.get_columns().iter().enumerate() {
+        let numbers = if series.dtype().is_numeric() {
+            series.cast(&DataType::Float64).ok()
+        } else {
+            None
+        };
+        for row in 0..df.height() {
+            let xlsx_row = row as u32 + first_row;
+            match numbers
+                .as_ref()
+                .map(|numbers| numbers.f64().map(|ca| ca.get(row)))
+            {
+                Some(Ok(Some(v))) => {
+                    worksheet.write_number(xlsx_row, col as u16, v)?;
+                }
+                Some(_) => {}
+                None => {
+                    let text = series
+                        .get(row)
+                        .map(|value| csv_value(&value, &CsvOptions::default()))
+                        .unwrap_or_default();
+                    worksheet.write_string(xlsx_row, col as u16, &text)?;
+                }
+            }
+        }
+    }
+    workbook.save(path)?;
+    Ok(())
+}
+
+// column,unit for every exported column
+#[inline(always)]
+fn write_units_sidecar(df: &DataFrame, path: &Path, units: &[String]) -> Result<()> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut wtr = csv::Writer::from_path(path.with_file_name(format!("{}.units.csv", stem)))?;
+    wtr.write_record(["column", "unit"])?;
+    for (name, unit) in df.get_column_names().iter().zip(units) {
+        wtr.write_record([*name, unit.as_str()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Values are exported in their stored units, units holds them per column
+#[inline(always)]
+pub fn export(
+    df: &DataFrame,
+    path: &Path,
+    format: ExportFormat,
+    csv_options: &CsvOptions,
+    units: &[String],
+    placement: UnitsPlacement,
+) -> Result<()> {
+    let mut df = df.clone();
+    let has_row = matches!(format, ExportFormat::Csv | ExportFormat::Xlsx);
+    let row = (placement == UnitsPlacement::Row && has_row).then_some(units);
+    if placement == UnitsPlacement::Sidecar || (placement == UnitsPlacement::Row && !has_row) {
+        write_units_sidecar(&df, path, units)?;
+    }
+    match format {
+        ExportFormat::Csv => {
+            write_csv_with_units(&df, BufWriter::new(File::create(path)?), csv_options, row)?
+        }
+        ExportFormat::Parquet => {
+            ParquetWriter::new(File::create(path)?).finish(&mut df)?;
+        }
+        ExportFormat::Ipc => IpcWriter::new(File::create(path)?).finish(&mut df)?,
+        ExportFormat::Json => JsonWriter::new(BufWriter::new(File::create(path)?))
+            .with_json_format(JsonFormat::Json)
+            .finish(&mut df)?,
+        ExportFormat::Xlsx => write_xlsx(&df, path, row)?,
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct ExportDialog {
+    pub open: bool,
+    format: ExportFormat,
+    csv_options: CsvOptions,
+    units: UnitsPlacement,
+    // Column name and whether it is exported, in export order
+    columns: Vec<(String, bool)>,
+    preview: Option<String>,
+}
+
+impl ExportDialog {
+    // Open the dialog for a DataFrame, keeping the column choices of columns that still exist
+    #[inline(always)]
+    pub fn open_for(&mut self, df: &DataFrame) {
+        let names: Vec<String> = df
+            .get_column_names()
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+        self.columns.retain(|(name, _)| names.contains(name));
+        for name in names {
+            if !self.columns.iter().any(|(existing, _)| *existing == name) {
+                self.columns.push((name, true));
+            }
+        }
+        self.preview = None;
+        self.open = true;
+    }
+
+    #[inline(always)]
+    fn selected(&self, df: &DataFrame) -> PolarsResult<DataFrame> {
+        df.select(
+            self.columns
+                .iter()
+                .filter(|(_, export)| *export)
+                .map(|(name, _)| name.as_str()),
+        )
+    }
+
+    #[inline(always)]
+    fn units_row(&self, df: &DataFrame, units: &UnitRegistry) -> Result<Vec<String>> {
+        Ok(units.units_row(&self.selected(df)?.get_column_names()))
+    }
+
+    #[inline(always)]
+    fn render_preview(&self, df: &DataFrame, units: &UnitRegistry) -> Result<String> {
+        let mut head = self.selected(df)?.head(Some(PREVIEW_ROWS));
+        let units_row = self.units_row(df, units)?;
+        let row = (self.units == UnitsPlacement::Row).then_some(units_row.as_slice());
+        let mut buffer = Vec::new();
+        match self.format {
+            ExportFormat::Json => JsonWriter::new(&mut buffer)
+                .with_json_format(JsonFormat::Json)
+                .finish(&mut head)?,
+            ExportFormat::Csv => write_csv_with_units(&head, &mut buffer, &self.csv_options, row)?,
+            _ => {
+                buffer.extend_from_slice(
+                    format!(
+                        "{} is a binary format, the exported data:\n\n",
+                        self.format.name()
+                    )
+                    .as_bytes(),
+                );
+                write_csv(&head, &mut buffer, &self.csv_options)?;
+            }
+        }
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+
+    // Returns a message for the notification area once an export finished or failed
+    #[inline(always)]
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        df: &DataFrame,
+        file_stem: &str,
+        units: &UnitRegistry,
+    ) -> Option<Result<String>> {
+        let mut result = None;
+        let mut open = self.open;
+        egui::Window::new("Export summary")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let before = (
+                    self.format,
+                    self.csv_options.clone(),
+                    self.units,
+                    self.columns.clone(),
+                );
+                ui.horizontal(|ui| {
+                    for format in ExportFormat::ALL {
+                        ui.radio_value(&mut self.format, format, format.name());
+                    }
+                });
+                if self.format == ExportFormat::Csv {
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut self.csv_options.precision)
+                                .clamp_range(0..=10)
+                                .prefix("Precision: "),
+                        );
+                        ui.label("Delimiter:");
+                        for (label, delimiter) in
+                            [(",", ','), (";", ';'), ("Tab", '\t'), ("|", '|')]
+                        {
+                            ui.radio_value(&mut self.csv_options.delimiter, delimiter, label);
+                        }
+                        ui.label("Decimal:");
+                        ui.radio_value(&mut self.csv_options.decimal, '.', ".");
+                        ui.radio_value(&mut self.csv_options.decimal, ',', ",");
+                    });
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Units:");
+                    for placement in UnitsPlacement::ALL {
+                        ui.radio_value(&mut self.units, placement, placement.name());
+                    }
+                });
+                if self.units == UnitsPlacement::Row
+                    && !matches!(self.format, ExportFormat::Csv | ExportFormat::Xlsx)
+                {
+                    ui.label(
+                        RichText::new(format!(
+                            "{} has no room for a units row, a sidecar file is written",
+                            self.format.name()
+                        ))
+                        .weak(),
+                    );
+                }
+                ui.separator();
+                ui.label(RichText::new("Columns").strong());
+                egui::ScrollArea::vertical()
+                    .id_source("export_columns")
+                    .max_height(200.0)
+                    .show(ui, |ui| {
+                        let mut swap = None;
+                        let count = self.columns.len();
+                        for (idx, (name, export)) in self.columns.iter_mut().enumerate() {
+                            ui.horizontal(|ui| {
+                                if ui.add_enabled(idx > 0, egui::Button::new("⏶")).clicked() {
+                                    swap = Some((idx, idx - 1));
+                                }
+                                if ui
+                                    .add_enabled(idx + 1 < count, egui::Button::new("⏷"))
+                                    .clicked()
+                                {
+                                    swap = Some((idx, idx + 1));
+                                }
+                                ui.checkbox(export, name.as_str());
+                            });
+                        }
+                        if let Some((a, b)) = swap {
+                            self.columns.swap(a, b);
+                        }
+                    });
+                if before
+                    != (
+                        self.format,
+                        self.csv_options.clone(),
+                        self.units,
+                        self.columns.clone(),
+                    )
+                {
+                    self.preview = None;
+                }
+                ui.separator();
+                ui.label(RichText::new("Preview").strong());
+                if self.preview.is_none() {
+                    self.preview = Some(match self.render_preview(df, units) {
+                        Ok(preview) => preview,
+                        Err(e) => format!("Cannot export: {}", e),
+                    });
+                }
+                if let Some(preview) = &self.preview {
+                    egui::ScrollArea::both()
+                        .id_source("export_preview")
+                        .max_height(200.0)
+                        .show(ui, |ui| {
+                            ui.monospace(preview.as_str());
+                        });
+                }
+                if ui.button(RichText::new("Export").size(20.0)).clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .set_file_name(format!("{}.{}", file_stem, self.format.extension()))
+                        .add_filter(self.format.name(), &[self.format.extension()])
+                        .save_file()
+                    {
+                        result = Some(
+                            self.selected(df)
+                                .map_err(anyhow::Error::from)
+                                .and_then(|selected| {
+                                    let units_row = self.units_row(df, units)?;
+                                    export(
+                                        &selected,
+                                        &path,
+                                        self.format,
+                                        &self.csv_options,
+                                        &units_row,
+                                        self.units,
+                                    )
+                                })
+                                .map(|()| format!("Saved {}", path.display())),
+                        );
+                    }
+                }
+            });
+        self.open = open;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[inline(always)]
+    fn summary() -> DataFrame {
+        df!(
+            "Sonication" => &[1i64, 2, 3],
+            "Energy[J]" => &[Some(1000.5), None, Some(12.0)],
+            "Mode" => &["A", "B", "A, B"]
+        )
+        .unwrap()
+    }
+
+    #[inline(always)]
+    fn units() -> Vec<String> {
+        vec![String::new(), "J".to_string(), String::new()]
+    }
+
+    #[inline(always)]
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ejs-export-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[inline(always)]
+    fn records(text: &str, delimiter: u8) -> Vec<Vec<String>> {
+        csv::ReaderBuilder::new()
+            .has_headers(false)
+            .delimiter(delimiter)
+            .from_reader(text.as_bytes())
+            .records()
+            .map(|record| record.unwrap().iter().map(str::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn dialog_exports_the_checked_columns_in_its_order() {
+        let df = summary();
+        let mut dialog = ExportDialog::default();
+        dialog.open_for(&df);
+        assert_eq!(
+            dialog.columns,
+            vec![
+                ("Sonication".to_string(), true),
+                ("Energy[J]".to_string(), true),
+                ("Mode".to_string(), true),
+            ]
+        );
+        dialog.columns = vec![
+            ("Mode".to_string(), true),
+            ("Sonication".to_string(), false),
+            ("Energy[J]".to_string(), true),
+        ];
+        assert_eq!(
+            dialog.selected(&df).unwrap().get_column_names(),
+            vec!["Mode", "Energy[J]"]
+        );
+        let mut registry = UnitRegistry::default();
+        registry.fill(&df);
+        assert_eq!(dialog.units_row(&df, &registry).unwrap(), vec!["", "J"]);
+        dialog.units = UnitsPlacement::Row;
+        let preview = dialog.render_preview(&df, &registry).unwrap();
+        assert_eq!(
+            records(&preview, b','),
+            vec![
+                vec!["Mode", "Energy[J]"],
+                vec!["", "J"],
+                vec!["A", "1000.50"],
+                vec!["B", ""],
+                vec!["A, B", "12.00"],
+            ]
+        );
+    }
+
+    #[test]
+    fn dialog_keeps_choices_of_columns_that_still_exist() {
+        let mut dialog = ExportDialog {
+            columns: vec![
+                ("Mode".to_string(), true),
+                ("Gone".to_string(), true),
+                ("Sonication".to_string(), false),
+            ],
+            ..Default::default()
+        };
+        dialog.open_for(&summary());
+        assert_eq!(
+            dialog.columns,
+            vec![
+                ("Mode".to_string(), true),
+                ("Sonication".to_string(), false),
+                ("Energy[J]".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_round_trip_with_a_units_row() {
+        let dir = temp_dir("csv-row");
+        let path = dir.join("summary.csv");
+        let options = CsvOptions {
+            precision: 1,
+            delimiter: ';',
+            decimal: ',',
+        };
+        export(
+            &summary(),
+            &path,
+            ExportFormat::Csv,
+            &options,
+            &units(),
+            UnitsPlacement::Row,
+        )
+        .unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let sidecar = dir.join("summary.units.csv").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!sidecar);
+        assert_eq!(
+            records(&text, b';'),
+            vec![
+                vec!["Sonication", "Energy[J]", "Mode"],
+                vec!["", "J", ""],
+                vec!["1", "1000,5", "A"],
+                vec!["2", "", "B"],
+                vec!["3", "12,0", "A, B"],
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_units_go_to_the_sidecar() {
+        let dir = temp_dir("csv-sidecar");
+        let path = dir.join("summary.csv");
+        export(
+            &summary(),
+            &path,
+            ExportFormat::Csv,
+            &CsvOptions::default(),
+            &units(),
+            UnitsPlacement::Sidecar,
+        )
+        .unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let sidecar = std::fs::read_to_string(dir.join("summary.units.csv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let rows = records(&text, b',');
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[1], vec!["1", "1000.50", "A"]);
+        assert_eq!(
+            records(&sidecar, b','),
+            vec![
+                vec!["column", "unit"],
+                vec!["Sonication", ""],
+                vec!["Energy[J]", "J"],
+                vec!["Mode", ""],
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_formats_round_trip_with_units_in_the_sidecar() {
+        let dir = temp_dir("binary");
+        for format in [ExportFormat::Parquet, ExportFormat::Ipc] {
+            let path = dir.join("summary").with_extension(format.extension());
+            export(
+                &summary(),
+                &path,
+                format,
+                &CsvOptions::default(),
+                &units(),
+                UnitsPlacement::Row,
+            )
+            .unwrap();
+            let read = match format {
+                ExportFormat::Parquet => ParquetReader::new(File::open(&path).unwrap()).finish(),
+                _ => IpcReader::new(File::open(&path).unwrap()).finish(),
+            }
+            .unwrap();
+            assert!(read.frame_equal_missing(&summary()), "{}", format.name());
+            let sidecar = dir.join("summary.units.csv");
+            assert!(sidecar.is_file(), "{}", format.name());
+            std::fs::remove_file(sidecar).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delimiter_must_differ_from_the_decimal_separator() {
+        let options = CsvOptions {
+            delimiter: ',',
+            decimal: ',',
+            ..Default::default()
+        };
+        assert!(write_csv(&summary(), Vec::new(), &options).is_err());
+    }
+}
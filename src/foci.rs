@@ -0,0 +1,236 @@
+use crate::{steering::FOCAL_COLUMNS, summary};
+use eframe::egui::{self, Color32, RichText};
+use egui_plot::{Legend, MarkerShape, Plot, PlotPoint, Points};
+use polars::prelude::*;
+
+// Clicks further than this from every focus do not change the selection [px]
+const PICK_RADIUS: f32 = 8.0;
+// Colours are quantised to this many steps so each view draws a few point series
+const COLOR_STEPS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorBy {
+    Energy,
+    Order,
+}
+
+// Axis pairs (horizontal, vertical) of the RAS coordinates for each view
+const VIEWS: [(&str, usize, usize); 3] = [
+    ("Axial (R / A)", 0, 1),
+    ("Coronal (R / S)", 0, 2),
+    ("Sagittal (A / S)", 1, 2),
+];
+const AXIS_NAMES: [&str; 3] = ["R [mm]", "A [mm]", "S [mm]"];
+
+// Blue for the lowest value to red for the highest
+#[inline(always)]
+fn ramp(t: f64) -> Color32 {
+    let t = t.clamp(0.0, 1.0);
+    Color32::from_rgb((255.0 * t) as u8, 60, (255.0 * (1.0 - t)) as u8)
+}
+
+pub struct FociPlot {
+    pub color_by: ColorBy,
+    // Row and RAS position of every sonication with all three focal coordinates
+    foci: Vec<(usize, [f64; 3])>,
+    energy: Vec<Option<f64>>,
+    // Lowest and highest energy, None without any energy value
+    energy_range: Option<(f64, f64)>,
+}
+
+impl Default for FociPlot {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            color_by: ColorBy::Energy,
+            foci: Vec::new(),
+            energy: Vec::new(),
+            energy_range: None,
+        }
+    }
+}
+
+impl FociPlot {
+    #[inline(always)]
+    pub fn analyse(&mut self, df: &DataFrame) {
+        let [r, a, s] = FOCAL_COLUMNS.map(|name| summary::float_values(df, name));
+        self.foci = match (r, a, s) {
+            (Some(r), Some(a), Some(s)) => (0..df.height())
+                .filter_map(|row| Some((row, [r[row]?, a[row]?, s[row]?])))
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.energy = summary::float_values(df, "Act. Energy[J]")
+            .or_else(|| summary::float_values(df, "Energy[J]"))
+            .unwrap_or_default();
+        self.energy_range = self.energy.iter().flatten().fold(None, |range, &energy| {
+            let (min, max) = range.unwrap_or((energy, energy));
+            Some((min.min(energy), max.max(energy)))
+        });
+    }
+
+    // Colour step of the idx-th focus, None when it has no energy to colour by
+    #[inline(always)]
+    fn color_step(&self, idx: usize, row: usize) -> Option<usize> {
+        let t = match self.color_by {
+            ColorBy::Order => idx as f64 / (self.foci.len().max(2) - 1) as f64,
+            ColorBy::Energy => {
+                let energy = self.energy.get(row).copied().flatten()?;
+                match self.energy_range {
+                    Some((min, max)) if max > min => (energy - min) / (max - min),
+                    _ => 0.5,
+                }
+            }
+        };
+        Some((t.clamp(0.0, 1.0) * (COLOR_STEPS - 1) as f64).round() as usize)
+    }
+
+    #[inline(always)]
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        natural_focus: &[f64; 3],
+        target: &[f64],
+        selected_row: &mut Option<usize>,
+    ) {
+        if self.foci.is_empty() {
+            ui.label("This export has no focal RAS columns");
+            return;
+        }
+        ui.horizontal(|ui| {
+            ui.label("Colour by");
+            ui.radio_value(&mut self.color_by, ColorBy::Energy, "Energy");
+            ui.radio_value(&mut self.color_by, ColorBy::Order, "Sonication order");
+            ui.label(
+                RichText::new(match self.color_by {
+                    ColorBy::Energy => "blue = lowest, red = highest energy",
+                    ColorBy::Order => "blue = first, red = last sonication",
+                })
+                .weak(),
+            );
+        });
+        let steps: Vec<Option<usize>> = self
+            .foci
+            .iter()
+            .enumerate()
+            .map(|(idx, (row, _))| self.color_step(idx, *row))
+            .collect();
+        let width = (ui.available_width() / 3.0 - 10.0).max(150.0);
+        ui.horizontal_top(|ui| {
+            for (title, h, v) in VIEWS {
+                ui.vertical(|ui| {
+                    ui.label(RichText::new(title).strong());
+                    let clicked = Plot::new(title)
+                        .width(width)
+                        .height(width)
+                        .data_aspect(1.0)
+                        .legend(Legend::default())
+                        .x_axis_label(AXIS_NAMES[h])
+                        .y_axis_label(AXIS_NAMES[v])
+                        .show(ui, |plot_ui| {
+                            // One series per colour step, the last one for foci without energy
+                            let mut series = vec![Vec::new(); COLOR_STEPS + 1];
+                            let mut selected = None;
+                            for ((row, focus), step) in self.foci.iter().zip(&steps) {
+                                let point = [focus[h], focus[v]];
+                                if *selected_row == Some(*row) {
+                                    selected = Some(point);
+                                } else {
+                                    series[step.unwrap_or(COLOR_STEPS)].push(point);
+                                }
+                            }
+                            for (step, points) in series.into_iter().enumerate() {
+                                if points.is_empty() {
+                                    continue;
+                                }
+                                let color = if step == COLOR_STEPS {
+                                    Color32::GRAY
+                                } else {
+                                    ramp(step as f64 / (COLOR_STEPS - 1) as f64)
+                                };
+                                plot_ui.points(Points::new(points).radius(3.0).color(color));
+                            }
+                            if let Some(point) = selected {
+                                plot_ui
+                                    .points(Points::new(point).radius(6.0).color(Color32::WHITE));
+                            }
+                            plot_ui.points(
+                                Points::new([natural_focus[h], natural_focus[v]])
+                                    .shape(MarkerShape::Cross)
+                                    .radius(8.0)
+                                    .color(Color32::GREEN)
+                                    .name("Natural focus"),
+                            );
+                            if target.len() == 3 {
+                                plot_ui.points(
+                                    Points::new([target[h], target[v]])
+                                        .shape(MarkerShape::Diamond)
+                                        .radius(7.0)
+                                        .color(Color32::GOLD)
+                                        .name("Target"),
+                                );
+                            }
+                            // Nearest focus on screen to the click
+                            let pointer = plot_ui.pointer_coordinate()?;
+                            if !plot_ui.response().clicked() {
+                                return None;
+                            }
+                            let pointer =
+                                plot_ui.screen_from_plot(PlotPoint::new(pointer.x, pointer.y));
+                            self.foci
+                                .iter()
+                                .map(|(row, focus)| {
+                                    let screen = plot_ui
+                                        .screen_from_plot(PlotPoint::new(focus[h], focus[v]));
+                                    (*row, screen.distance(pointer))
+                                })
+                                .filter(|(_, distance)| *distance <= PICK_RADIUS)
+                                .min_by(|a, b| a.1.total_cmp(&b.1))
+                                .map(|(row, _)| row)
+                        })
+                        .inner;
+                    if let Some(row) = clicked {
+                        *selected_row = Some(row);
+                    }
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn plot(energy: &[Option<f64>]) -> FociPlot {
+        let rows = energy.len();
+        let df = df!(
+            "Focal RAS-R" => vec![1.0; rows],
+            "Focal RAS-A" => vec![2.0; rows],
+            "Focal RAS-S" => vec![3.0; rows],
+            "Act. Energy[J]" => energy
+        )
+        .unwrap();
+        let mut plot = FociPlot::default();
+        plot.analyse(&df);
+        plot
+    }
+
+    #[test]
+    fn energy_steps_span_the_energy_range() {
+        let plot = plot(&[Some(100.0), Some(400.0), None, Some(250.0)]);
+        assert_eq!(plot.energy_range, Some((100.0, 400.0)));
+        let steps: Vec<Option<usize>> = (0..4).map(|row| plot.color_step(row, row)).collect();
+        assert_eq!(steps, vec![Some(0), Some(15), None, Some(8)]);
+    }
+
+    #[test]
+    fn equal_energies_and_order_colours() {
+        let mut plot = plot(&[Some(100.0), Some(100.0), Some(100.0)]);
+        assert_eq!(plot.color_step(0, 0), Some(8));
+        plot.color_by = ColorBy::Order;
+        let steps: Vec<Option<usize>> = (0..3).map(|idx| plot.color_step(idx, idx)).collect();
+        assert_eq!(steps, vec![Some(0), Some(8), Some(15)]);
+    }
+}
@@ -0,0 +1,370 @@
+use crate::{archive::ExtractManifest, summary::to_utc};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use eframe::egui::{self, Color32, ColorImage, RichText, TextureHandle};
+use std::sync::Arc;
+
+const THUMBNAIL_SIZE: u32 = 160;
+// Height reserved per snapshot so only the visible thumbnails are decoded
+const ITEM_HEIGHT: f32 = THUMBNAIL_SIZE as f32 + 60.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeSource {
+    FileName,
+    Archive,
+}
+
+pub struct Snapshot {
+    pub archive_path: String,
+    // Shared with the manifest the snapshot was read from
+    pub data: Arc<[u8]>,
+    pub time: Option<NaiveDateTime>,
+    pub time_source: Option<TimeSource>,
+    // TreatSummary row whose time window contains the capture time
+    pub row: Option<usize>,
+    // Capture time in UTC, set when the gallery is linked to a summary
+    utc: Option<NaiveDateTime>,
+    // None until decoded, Some(None) when the image could not be decoded
+    thumbnail: Option<Option<TextureHandle>>,
+}
+
+pub struct Gallery {
+    pub snapshots: Vec<Snapshot>,
+    // Snapshots captured up to this long after a sonication ended still belong to it [s]
+    pub tolerance: f64,
+    // Start, end and row of every sonication, sorted by start
+    windows: Vec<(NaiveDateTime, NaiveDateTime, usize)>,
+    only_selected: bool,
+    preview: Option<(usize, TextureHandle)>,
+}
+
+impl Default for Gallery {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            snapshots: Vec::new(),
+            tolerance: 60.0,
+            windows: Vec::new(),
+            only_selected: false,
+            preview: None,
+        }
+    }
+}
+
+// Find a YYYYMMDDHHMMSS timestamp in the digits of a file name, separators are ignored
+#[inline(always)]
+pub fn parse_capture_time(name: &str) -> Option<NaiveDateTime> {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    let digits: String = file_name.chars().filter(char::is_ascii_digit).collect();
+    (0..digits.len().saturating_sub(13))
+        .filter_map(|start| {
+            NaiveDateTime::parse_from_str(&digits[start..start + 14], "%Y%m%d%H%M%S").ok()
+        })
+        .find(|time| (2000..2100).contains(&time.year()))
+}
+
+#[inline(always)]
+fn archive_time(time: &zip::DateTime) -> Option<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(time.year() as i32, time.month() as u32, time.day() as u32)?
+        .and_hms_opt(
+            time.hour() as u32,
+            time.minute() as u32,
+            time.second() as u32,
+        )
+}
+
+#[inline(always)]
+fn load_texture(
+    ctx: &egui::Context,
+    name: &str,
+    data: &[u8],
+    max_size: Option<u32>,
+) -> Option<TextureHandle> {
+    let mut image = image::load_from_memory(data).ok()?;
+    if let Some(max_size) = max_size {
+        image = image.thumbnail(max_size, max_size);
+    }
+    let image = image.to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    let image = ColorImage::from_rgba_unmultiplied(size, image.as_flat_samples().as_slice());
+    Some(ctx.load_texture(name, image, egui::TextureOptions::default()))
+}
+
+#[inline(always)]
+fn is_image(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    [".bmp", ".png", ".jpg", ".jpeg"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+impl Snapshot {
+    #[inline(always)]
+    fn thumbnail(&mut self, ctx: &egui::Context) -> Option<&TextureHandle> {
+        self.thumbnail
+            .get_or_insert_with(|| {
+                load_texture(ctx, &self.archive_path, &self.data, Some(THUMBNAIL_SIZE))
+            })
+            .as_ref()
+    }
+
+    #[inline(always)]
+    fn label(&self) -> String {
+        let time = self
+            .time
+            .map(|time| time.format("%H:%M:%S").to_string())
+            .unwrap_or_else(|| "no time".to_string());
+        match self.row {
+            Some(row) => format!("Son. {} - {}", row + 1, time),
+            None => time,
+        }
+    }
+}
+
+impl Gallery {
+    #[inline(always)]
+    pub fn from_manifest(manifest: &ExtractManifest) -> Self {
+        let mut snapshots: Vec<Snapshot> = manifest
+            .extracted
+            .iter()
+            .filter(|file| is_image(&file.archive_path))
+            .map(|file| {
+                let (time, time_source) = match parse_capture_time(&file.archive_path) {
+                    Some(time) => (Some(time), Some(TimeSource::FileName)),
+                    None => match file.modified.as_ref().and_then(archive_time) {
+                        Some(time) => (Some(time), Some(TimeSource::Archive)),
+                        None => (None, None),
+                    },
+                };
+                Snapshot {
+                    archive_path: file.archive_path.clone(),
+                    data: file.data.clone(),
+                    time,
+                    time_source,
+                    row: None,
+                    utc: None,
+                    thumbnail: None,
+                }
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+        Self {
+            snapshots,
+            ..Default::default()
+        }
+    }
+
+    // Sonication starts are UTC, capture times are console wall-clock times in source_tz.
+    // durations are the on times [s], a sonication without one only covers its start.
+    #[inline(always)]
+    pub fn link(
+        &mut self,
+        starts: &[Option<NaiveDateTime>],
+        durations: &[Option<f64>],
+        source_tz: &Tz,
+    ) {
+        self.windows = starts
+            .iter()
+            .enumerate()
+            .filter_map(|(row, start)| {
+                let start = (*start)?;
+                let duration = durations.get(row).copied().flatten().unwrap_or(0.0);
+                let end = start + Duration::milliseconds((duration * 1000.0) as i64);
+                Some((start, end, row))
+            })
+            .collect();
+        self.windows.sort();
+        for snapshot in &mut self.snapshots {
+            snapshot.utc = snapshot.time.and_then(|time| to_utc(time, source_tz));
+        }
+        self.assign_rows();
+    }
+
+    // A snapshot belongs to the last sonication that started before it was captured,
+    // unless it was captured more than the tolerance after that sonication ended
+    #[inline(always)]
+    fn assign_rows(&mut self) {
+        let tolerance = Duration::milliseconds((self.tolerance * 1000.0) as i64);
+        for snapshot in &mut self.snapshots {
+            snapshot.row = snapshot.utc.and_then(|time| {
+                let idx = self.windows.partition_point(|(start, _, _)| *start <= time);
+                let (_, end, row) = self.windows[idx.checked_sub(1)?];
+                (time <= end + tolerance).then_some(row)
+            });
+        }
+    }
+
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui, selected_row: &mut Option<usize>) {
+        ui.label(RichText::new(format!("Snapshots ({})", self.snapshots.len())).size(20.0));
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.only_selected, false, "All");
+            ui.radio_value(&mut self.only_selected, true, "Selected sonication");
+        });
+        if ui
+            .add(
+                egui::DragValue::new(&mut self.tolerance)
+                    .clamp_range(0.0..=3600.0)
+                    .prefix("Link up to ")
+                    .suffix(" s after a sonication"),
+            )
+            .changed()
+        {
+            self.assign_rows();
+        }
+        let only_selected = self.only_selected;
+        let shown: Vec<usize> = (0..self.snapshots.len())
+            .filter(|&idx| {
+                !only_selected
+                    || (selected_row.is_some() && self.snapshots[idx].row == *selected_row)
+            })
+            .collect();
+        let ctx = ui.ctx().clone();
+        egui::ScrollArea::vertical().show_rows(ui, ITEM_HEIGHT, shown.len(), |ui, range| {
+            for &idx in &shown[range] {
+                let snapshot = &mut self.snapshots[idx];
+                let label = snapshot.label();
+                let linked = snapshot.row.is_some() && snapshot.row == *selected_row;
+                let size = egui::vec2(ui.available_width(), ITEM_HEIGHT);
+                ui.allocate_ui(size, |ui| {
+                    ui.set_min_height(ITEM_HEIGHT);
+                    ui.group(|ui| {
+                        if let Some(texture) = snapshot.thumbnail(&ctx).cloned() {
+                            let response =
+                                ui.add(egui::ImageButton::new(&texture).selected(linked));
+                            if response.clicked() {
+                                self.preview =
+                                    load_texture(&ctx, "snapshot_preview", &snapshot.data, None)
+                                        .map(|texture| (idx, texture));
+                                if snapshot.row.is_some() {
+                                    *selected_row = snapshot.row;
+                                }
+                            }
+                            response.on_hover_text(&snapshot.archive_path);
+                        } else {
+                            ui.colored_label(Color32::RED, "Unreadable image");
+                        }
+                        let color = if linked {
+                            Color32::LIGHT_BLUE
+                        } else {
+                            Color32::GRAY
+                        };
+                        ui.label(RichText::new(label).color(color));
+                        if snapshot.time_source == Some(TimeSource::Archive) {
+                            ui.small("time from archive entry");
+                        }
+                    });
+                });
+            }
+        });
+
+        let mut open = self.preview.is_some();
+        if let Some((idx, texture)) = &self.preview {
+            egui::Window::new(self.snapshots[*idx].archive_path.as_str())
+                .open(&mut open)
+                .show(&ctx, |ui| {
+                    egui::ScrollArea::both().show(ui, |ui| ui.image(texture));
+                });
+        }
+        if !open {
+            self.preview = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[inline(always)]
+    fn gallery(times: &[Option<&str>]) -> Gallery {
+        let snapshots = times
+            .iter()
+            .enumerate()
+            .map(|(idx, text)| Snapshot {
+                archive_path: format!("shot{}.bmp", idx),
+                data: Arc::from(Vec::new()),
+                time: text.map(time),
+                time_source: text.map(|_| TimeSource::FileName),
+                row: None,
+                utc: None,
+                thumbnail: None,
+            })
+            .collect();
+        Gallery {
+            snapshots,
+            ..Default::default()
+        }
+    }
+
+    #[inline(always)]
+    fn rows(gallery: &Gallery) -> Vec<Option<usize>> {
+        gallery
+            .snapshots
+            .iter()
+            .map(|snapshot| snapshot.row)
+            .collect()
+    }
+
+    #[test]
+    fn capture_time_from_separated_digits() {
+        assert_eq!(
+            parse_capture_time("snaps/IMG_2023-05-04_13-22-05.bmp"),
+            Some(time("2023-05-04 13:22:05"))
+        );
+        // A leading counter is skipped until the digits form a valid time
+        assert_eq!(
+            parse_capture_time("0012_20230504132205.bmp"),
+            Some(time("2023-05-04 13:22:05"))
+        );
+    }
+
+    #[test]
+    fn capture_time_needs_a_plausible_timestamp_in_the_file_name() {
+        assert_eq!(parse_capture_time("20230504132205/shot.bmp"), None);
+        assert_eq!(parse_capture_time("19991231235959.bmp"), None);
+        assert_eq!(parse_capture_time("2023-05-04.bmp"), None);
+        assert_eq!(parse_capture_time("image.png"), None);
+    }
+
+    #[test]
+    fn snapshots_link_within_the_sonication_and_tolerance() {
+        let mut gallery = gallery(&[
+            Some("2023-05-04 09:59:59"),
+            Some("2023-05-04 10:00:10"),
+            Some("2023-05-04 10:01:20"),
+            Some("2023-05-04 10:01:21"),
+            Some("2023-05-04 10:05:30"),
+            None,
+        ]);
+        let starts = [
+            Some(time("2023-05-04 10:00:00")),
+            None,
+            Some(time("2023-05-04 10:05:00")),
+        ];
+        gallery.link(&starts, &[Some(20.0), Some(5.0), None], &Tz::UTC);
+        // The first sonication ends at 10:00:20, the last one only covers its start
+        assert_eq!(
+            rows(&gallery),
+            vec![None, Some(0), Some(0), None, Some(2), None]
+        );
+        gallery.tolerance = 0.0;
+        gallery.assign_rows();
+        assert_eq!(rows(&gallery), vec![None, Some(0), None, None, None, None]);
+    }
+
+    #[test]
+    fn capture_times_are_converted_from_the_console_zone() {
+        let mut gallery = gallery(&[Some("2023-05-04 12:00:10")]);
+        let starts = [Some(time("2023-05-04 10:00:00"))];
+        gallery.link(&starts, &[Some(20.0)], &chrono_tz::Europe::Berlin);
+        assert_eq!(rows(&gallery), vec![Some(0)]);
+        gallery.link(&starts, &[Some(20.0)], &Tz::UTC);
+        assert_eq!(rows(&gallery), vec![None]);
+    }
+}
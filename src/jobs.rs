@@ -0,0 +1,277 @@
+use anyhow::{bail, Result};
+use eframe::egui::{self, Color32, RichText};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// Shared between the worker thread and the UI
+#[derive(Default)]
+pub struct JobProgress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+    cancel: AtomicBool,
+}
+
+impl JobProgress {
+    #[inline(always)]
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn inc(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    // Workers call this between files so a cancel request stops them early
+    #[inline(always)]
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!("cancelled");
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn fraction(&self) -> (usize, usize) {
+        (
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+}
+
+pub enum JobState {
+    Running,
+    Finished(String),
+    Failed(String),
+    Cancelled,
+}
+
+pub struct Job {
+    pub name: String,
+    pub state: JobState,
+    progress: Arc<JobProgress>,
+    receiver: Receiver<Result<String>>,
+    started: Instant,
+    elapsed: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct JobRunner {
+    pub jobs: Vec<Job>,
+    pub show: bool,
+}
+
+impl JobRunner {
+    // Run work on a worker thread; it returns a short summary shown when it finishes
+    #[inline(always)]
+    pub fn spawn<F>(&mut self, ctx: &egui::Context, name: impl Into<String>, work: F)
+    where
+        F: FnOnce(&JobProgress) -> Result<String> + Send + 'static,
+    {
+        let progress = Arc::new(JobProgress::default());
+        let (sender, receiver) = channel();
+        let worker_progress = progress.clone();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let _ = sender.send(work(&worker_progress));
+            ctx.request_repaint();
+        });
+        self.jobs.push(Job {
+            name: name.into(),
+            state: JobState::Running,
+            progress,
+            receiver,
+            started: Instant::now(),
+            elapsed: None,
+        });
+        self.show = true;
+    }
+
+    #[inline(always)]
+    pub fn running(&self) -> usize {
+        self.jobs
+            .iter()
+            .filter(|job| matches!(job.state, JobState::Running))
+            .count()
+    }
+
+    #[inline(always)]
+    pub fn is_running(&self, name: &str) -> bool {
+        self.jobs
+            .iter()
+            .any(|job| job.name == name && matches!(job.state, JobState::Running))
+    }
+
+    // Collect results of finished workers, returns the jobs that finished this frame.
+    // Keeps repainting while jobs run so progress and results show without other input.
+    #[inline(always)]
+    pub fn poll(&mut self, ctx: &egui::Context) -> Vec<&Job> {
+        let mut finished = Vec::new();
+        for (idx, job) in self.jobs.iter_mut().enumerate() {
+            if !matches!(job.state, JobState::Running) {
+                continue;
+            }
+            let state = match job.receiver.try_recv() {
+                Ok(Ok(summary)) => JobState::Finished(summary),
+                Ok(Err(_)) if job.progress.is_cancelled() => JobState::Cancelled,
+                Ok(Err(e)) => JobState::Failed(format!("{:#}", e)),
+                Err(TryRecvError::Empty) => continue,
+                Err(TryRecvError::Disconnected) => {
+                    JobState::Failed("worker stopped unexpectedly".to_string())
+                }
+            };
+            job.state = state;
+            job.elapsed = Some(job.started.elapsed());
+            finished.push(idx);
+        }
+        if self.running() > 0 {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+        finished.into_iter().map(|idx| &self.jobs[idx]).collect()
+    }
+
+    #[inline(always)]
+    pub fn show_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show;
+        egui::Window::new("Jobs").open(&mut open).show(ctx, |ui| {
+            if self.jobs.is_empty() {
+                ui.label("No jobs yet");
+            }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for job in self.jobs.iter().rev() {
+                    ui.group(|ui| {
+                        ui.label(RichText::new(&job.name).strong());
+                        let (done, total) = job.progress.fraction();
+                        match &job.state {
+                            JobState::Running => {
+                                ui.horizontal(|ui| {
+                                    let fraction = if total > 0 {
+                                        done as f32 / total as f32
+                                    } else {
+                                        0.0
+                                    };
+                                    ui.add(
+                                        egui::ProgressBar::new(fraction)
+                                            .text(format!("{} / {}", done, total))
+                                            .desired_width(250.0),
+                                    );
+                                    if ui
+                                        .add_enabled(
+                                            !job.progress.is_cancelled(),
+                                            egui::Button::new("Cancel"),
+                                        )
+                                        .clicked()
+                                    {
+                                        job.progress.cancel.store(true, Ordering::Relaxed);
+                                    }
+                                });
+                            }
+                            JobState::Finished(summary) => {
+                                ui.colored_label(Color32::GREEN, summary);
+                            }
+                            JobState::Failed(error) => {
+                                ui.colored_label(Color32::RED, error);
+                            }
+                            JobState::Cancelled => {
+                                ui.colored_label(
+                                    Color32::GRAY,
+                                    format!("Cancelled after {} / {}", done, total),
+                                );
+                            }
+                        }
+                        if let Some(elapsed) = job.elapsed {
+                            ui.small(format!("{:.1} s", elapsed.as_secs_f32()));
+                        }
+                    });
+                }
+            });
+            if ui.button("Clear finished").clicked() {
+                self.jobs
+                    .retain(|job| matches!(job.state, JobState::Running));
+            }
+        });
+        self.show = open;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Poll until the only job has finished, the worker threads are short
+    #[inline(always)]
+    fn wait(runner: &mut JobRunner, ctx: &egui::Context) {
+        let started = Instant::now();
+        while runner.poll(ctx).is_empty() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "job did not finish"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn check_fails_once_cancelled() {
+        let progress = JobProgress::default();
+        progress.set_total(2);
+        progress.inc();
+        assert!(progress.check().is_ok());
+        progress.cancel.store(true, Ordering::Relaxed);
+        assert!(progress.is_cancelled());
+        assert_eq!(progress.check().unwrap_err().to_string(), "cancelled");
+        assert_eq!(progress.fraction(), (1, 2));
+    }
+
+    #[test]
+    fn results_are_handed_over_once() {
+        let ctx = egui::Context::default();
+        let mut runner = JobRunner::default();
+        runner.spawn(&ctx, "Extract", |progress| {
+            progress.set_total(1);
+            progress.inc();
+            Ok("done".to_string())
+        });
+        assert!(runner.is_running("Extract"));
+        wait(&mut runner, &ctx);
+        assert!(matches!(&runner.jobs[0].state, JobState::Finished(summary) if summary == "done"));
+        assert!(runner.jobs[0].elapsed.is_some());
+        assert_eq!(runner.running(), 0);
+        assert!(runner.poll(&ctx).is_empty());
+    }
+
+    #[test]
+    fn errors_after_a_cancel_are_reported_as_cancelled() {
+        let ctx = egui::Context::default();
+        let mut runner = JobRunner::default();
+        runner.spawn(&ctx, "Convert", |_| bail!("disk full"));
+        wait(&mut runner, &ctx);
+        assert!(matches!(&runner.jobs[0].state, JobState::Failed(error) if error == "disk full"));
+
+        let (release, released) = channel::<()>();
+        runner.spawn(&ctx, "Anonymize", move |progress| {
+            released.recv()?;
+            progress.check()?;
+            Ok("not cancelled".to_string())
+        });
+        runner.jobs[1]
+            .progress
+            .cancel
+            .store(true, Ordering::Relaxed);
+        release.send(()).unwrap();
+        wait(&mut runner, &ctx);
+        assert!(matches!(runner.jobs[1].state, JobState::Cancelled));
+    }
+}
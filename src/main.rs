@@ -1,72 +1,71 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 #[global_allocator]
 static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
-use anyhow::Result;
+mod archive;
+mod batch;
+mod calibration;
+mod cavitation;
+mod cine;
+mod compare;
+mod convert;
+mod db;
+mod deviation;
+mod dialect;
+mod dicom_tools;
+mod export;
+mod foci;
+mod gallery;
+mod jobs;
+mod query;
+mod redaction;
+mod safety;
+mod steering;
+mod summary;
+mod table;
+mod timeline;
+mod units;
+mod watch;
+mod zip_browser;
+
+use anyhow::{Context, Result};
+use archive::{ExtractLayout, ExtractManifest, ExtractOptions, FILTER_PRESETS};
+use batch::BatchOptions;
+use calibration::{Calibration, CalibrationAction};
+use cavitation::CavitationAnalysis;
 use chrono::prelude::*;
+use cine::CineDialog;
+use compare::{Comparison, Treatment};
+use convert::{ConvertDialog, ConvertSource};
 use csv::Writer;
 use dashmap::{DashMap, DashSet};
-use dicom::{
-    dictionary_std,
-    object::{open_file, FileDicomObject, InMemDicomObject, OpenFileOptions, Tag},
-};
+use db::{BrowserAction, DbBrowser, TreatmentDb};
+use deviation::DeviationCheck;
+use dialect::{TextEncoding, DELIMITERS};
 use dicom_pixeldata::PixelDecoder;
+use dicom_tools::DicomSeries;
 use eframe::egui::{self, menu, Color32, ColorImage, Grid, RichText, SliderOrientation};
-use jwalk::{DirEntry, WalkDirGeneric};
+use egui_notify::Toasts;
+use export::ExportDialog;
+use foci::FociPlot;
+use gallery::Gallery;
+use jobs::{JobRunner, JobState};
 use polars::prelude::*;
-use rayon::prelude::*;
-use splines::{Interpolation, Key, Spline};
-use std::collections::HashSet;
+use query::QueryConsole;
+use redaction::RedactionPanel;
+use safety::SafetyReview;
 use std::{
-    cmp::Ordering,
-    error::Error,
     fs::File,
-    io::{Cursor, Read},
     path::{Path, PathBuf},
+    sync::Arc,
 };
+use steering::{distance, efficiency, EfficiencyProfile, SteeringPanel};
+use summary::{DisplayZone, LoadInfo, TimeOptions, TIME_FORMATS};
+use table::DataTable;
+use timeline::Timeline;
+use units::UnitRegistry;
+use watch::FolderWatcher;
+use zip_browser::ZipBrowser;
 
-use ::zip::read::ZipArchive;
-#[inline(always)]
-fn read_csv_from_zip(path: &Path, csv_file_name: &str) -> Option<String> {
-    let file = File::open(path).ok()?;
-    let mut archive = ZipArchive::new(file).ok()?;
-
-    let mut file = archive.by_name(csv_file_name).ok()?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).ok()?;
-    Some(contents)
-}
-#[inline(always)]
-fn get_image_position(obj: &InMemDicomObject) -> Option<f64> {
-    obj.element(Tag(0x0020, 0x0032))
-        .ok()
-        .and_then(|e| e.to_multi_float64().ok())
-        .map(|v| v[2])
-}
-//Define distance function from Natural Focus
-#[inline(always)]
-fn distance(vec1: &[f64], vec2: &[f64]) -> f64 {
-    vec1.iter()
-        .zip(vec2.iter())
-        .map(|(&a, &b)| (a - b).powi(2))
-        .sum::<f64>()
-        .sqrt()
-}
-// Define the Gaussian function
-#[inline(always)]
-fn efficiency(distance: f64) -> f64 {
-    let keys = vec![
-        Key::new(0.0, 100.0, Interpolation::default()),
-        Key::new(30.0, 90.0, Interpolation::default()),
-        Key::new(55.0, 70.0, Interpolation::default()),
-        Key::new(75.0, 50.0, Interpolation::default()),
-    ];
-
-    let spline = Spline::from_vec(keys);
-
-    let efficiency = spline.clamped_sample(distance).unwrap_or(50.0);
-
-    efficiency
-}
 #[inline(always)]
 fn main() -> Result<(), eframe::Error> {
     let options = eframe::NativeOptions {
@@ -93,134 +92,33 @@ fn main() -> Result<(), eframe::Error> {
         Box::new(|cc| Box::new(MyEguiApp::new(cc))),
     )
 }
+// Jobs that lock the DICOM series map
+const DICOM_JOBS: [&str; 3] = [
+    "Index DICOM folder",
+    "Move sorted dicoms",
+    "Anonymize dicoms",
+];
+// Job that writes an opened treatment to the database
+const STORE_JOB: &str = "Store treatment";
+
 #[inline(always)]
-fn process_dicom<WalkDirError>(
-    entry: Result<DirEntry<(u32, bool)>, WalkDirError>,
-) -> Option<(FileDicomObject<InMemDicomObject>, PathBuf)> {
-    if let Ok(entry) = entry {
-        if entry.file_type().is_file() {
-            let path = entry.path().to_path_buf();
-            if let Ok(object) = open_file(&path) {
-                return Some((object, path));
-            }
-        }
-    }
-    None
+fn format_duration(seconds: i64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
 }
 
 #[inline(always)]
-fn read_csv_file(path: &Path) -> PolarsResult<DataFrame> {
-    let mut df = LazyCsvReader::new(path)
-        .has_header(true)
-        .with_encoding(CsvEncoding::Utf8)
-        .with_try_parse_dates(true)
-        .finish()
-        .expect("Not a csv")
-        .with_columns([col("Time").str().to_datetime(
-            Some(TimeUnit::Milliseconds),
-            None,
-            StrptimeOptions {
-                format: Some("%Y%m%d%H%M%S ".to_string()),
-                ..Default::default()
-            },
-            lit("raise"),
-        )])
-        .with_columns([
-            col("Time").dt().year().alias("Year"),
-            col("Time").dt().month().alias("Month"),
-            col("Time").dt().day().alias("Day"),
-            col("Time").dt().hour().alias("Hour"),
-            col("Time").dt().minute().alias("Minute"),
-            col("Time").dt().second().alias("Second"),
-            (col("Energy[J]") / col("Num. of SubSonic")).alias("Energy per subspot"),
-            (col("Num. of Pulses") * col("Pulse Duration")).alias("CumPulseDurperRep"),
-            (col("Act. Energy[J]") / col("Num. of SubSonic")).alias("Act. Energy per subspot"),
-            col("Target Volume [cc]").cumsum(false).alias("cum_vol"),
-        ])
-        .drop_nulls(Some(Vec::<Expr>::new()))
-        .drop_columns(vec![
-            "Energy[J]",
-            "Protocol Name ",
-            "Act. Energy[J]",
-            "Frequency[Hz]",
-            "Time",
-            "Mode",
-            "Treated Dose[cc]",
-            "Stopped",
-            "Target Cav.Dose",
-            "Focal RAS-R",
-            "Focal RAS-A",
-            "Focal RAS-S",
-            "Acoustic mode(1-disabled/2-Stop sonication/3-Modulated power)",
-            "Protocol Name",
-        ])
+fn split_patterns(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
         .collect()
-        .expect("failed to modify");
-    // Prepend "simplify" to the file name
-    let folder_name = path.parent().expect("There is no parent");
-    let folder_name = folder_name.file_name().unwrap_or(path.file_name().unwrap());
-    let parent_dir = path.parent().unwrap();
-    let new_path = parent_dir.join(folder_name.to_str().unwrap().to_owned() + "_simplify.csv");
-
-    // Create a new file with the new name
-    let mut file = File::create(new_path).expect("could not create file");
-
-    // Write the DataFrame to the csv file with header and comma separator
-    CsvWriter::new(&mut file)
-        .has_header(true)
-        .with_float_precision(Some(2))
-        .finish(&mut df)?;
-
-    Ok(df)
 }
 
-#[inline(always)]
-fn extract_zip(path: &Path, dir_path: &Path) -> Result<(), Box<dyn Error>> {
-    let reader = File::open(path)?;
-    let mut archive = ZipArchive::new(reader)?;
-
-    let map = DashMap::new();
-
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let outpath = dir_path.join(file.mangled_name().file_name().unwrap());
-
-        if file.name().ends_with(".zip") {
-            // If the file is a zip file, recursively extract it
-            let mut buffer = Vec::with_capacity(file.size() as usize);
-            file.read_to_end(&mut buffer)?;
-            let reader = Cursor::new(buffer);
-            let mut inner_archive = ZipArchive::new(reader)?;
-
-            for j in 0..inner_archive.len() {
-                let mut inner_file = inner_archive.by_index(j)?;
-                let inner_outpath = dir_path.join(inner_file.mangled_name().file_name().unwrap());
-
-                if inner_file.name().ends_with(".bmp") {
-                    // If the inner file is a BMP image, extract it
-                    let mut buffer = Vec::with_capacity(inner_file.size() as usize);
-                    inner_file.read_to_end(&mut buffer)?;
-                    map.insert(inner_outpath, buffer);
-                }
-            }
-        } else if file.name().ends_with(".bmp") {
-            // If the outer file is a BMP image, extract it
-            let mut buffer = Vec::with_capacity(file.size() as usize);
-            file.read_to_end(&mut buffer)?;
-            map.insert(outpath, buffer);
-        }
-    }
-
-    // Write all files at once
-    map.par_iter().for_each(|entry| {
-        let (outpath, buffer) = entry.pair();
-        std::fs::write(outpath, buffer)
-            .map_err(|e| Box::new(e) as Box<dyn Error>)
-            .expect("REASON")
-    });
-
-    Ok(())
-}
 #[derive(Default)]
 struct MyEguiApp {
     allowed_to_close: bool,
@@ -235,13 +133,47 @@ struct MyEguiApp {
     show_mode: Box<str>,
     filepath: Option<PathBuf>,
     df: Option<DataFrame>,
+    summary_bytes: Option<Vec<u8>>,
+    time_options: TimeOptions,
+    // Custom time format being typed, applied once it is valid and the field is left
+    time_format_draft: String,
+    load_info: LoadInfo,
     selected_uid: String,
     summaryname: String,
     selected_folder: Option<PathBuf>,
-    unique_ids: DashSet<String>,
-    presorted: DashMap<String, Vec<(FileDicomObject<InMemDicomObject>, PathBuf)>>,
+    unique_ids: Arc<DashSet<String>>,
+    presorted: Arc<DicomSeries>,
     current_image_index: usize,
     extract_images: bool,
+    extract_options: ExtractOptions,
+    batch_options: BatchOptions,
+    include_filter: String,
+    exclude_filter: String,
+    zip_manifest: ExtractManifest,
+    show_manifest: bool,
+    gallery: Gallery,
+    selected_row: Option<usize>,
+    table: DataTable,
+    export_dialog: ExportDialog,
+    console: QueryConsole,
+    zip_browser: ZipBrowser,
+    convert_dialog: ConvertDialog,
+    cine_dialog: CineDialog,
+    redaction: RedactionPanel,
+    toasts: Toasts,
+    jobs: JobRunner,
+    watcher: FolderWatcher,
+    db: Option<TreatmentDb>,
+    db_browser: DbBrowser,
+    comparison: Comparison,
+    safety: SafetyReview,
+    cavitation: CavitationAnalysis,
+    deviation: DeviationCheck,
+    steering: SteeringPanel,
+    calibration: Calibration,
+    foci: FociPlot,
+    timeline: Timeline,
+    units: UnitRegistry,
     grid_data: Vec<Vec<String>>,
     natural_focus: Vec<f64>,
     target: Vec<f64>,
@@ -252,6 +184,14 @@ struct MyEguiApp {
 impl MyEguiApp {
     #[inline(always)]
     fn new(cc: &eframe::CreationContext<'_>) -> Self {
+        let mut toasts = Toasts::default();
+        let db = match TreatmentDb::open(&TreatmentDb::default_path()) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                toasts.error(format!("Treatment database unavailable: {:#}", e));
+                None
+            }
+        };
         Self {
             allowed_to_close: false,
             show_confirmation_dialog: false,
@@ -265,13 +205,46 @@ impl MyEguiApp {
             show_mode: "parameters".into(),
             filepath: None,
             df: None,
+            summary_bytes: None,
+            time_options: TimeOptions::default(),
+            time_format_draft: String::new(),
+            load_info: LoadInfo::default(),
             selected_uid: String::new(),
             summaryname: "summary.csv".to_string(),
             selected_folder: None, // Add this line
-            unique_ids: DashSet::new(),
-            presorted: DashMap::new(), // Add this line
+            unique_ids: Arc::new(DashSet::new()),
+            presorted: Arc::new(DashMap::new()), // Add this line
             current_image_index: 1,
             extract_images: true,
+            extract_options: ExtractOptions::default(),
+            batch_options: BatchOptions::default(),
+            include_filter: ExtractOptions::default().include.join(", "),
+            exclude_filter: String::new(),
+            zip_manifest: ExtractManifest::default(),
+            show_manifest: false,
+            gallery: Gallery::default(),
+            selected_row: None,
+            table: DataTable::default(),
+            export_dialog: ExportDialog::default(),
+            console: QueryConsole::default(),
+            zip_browser: ZipBrowser::default(),
+            convert_dialog: ConvertDialog::default(),
+            cine_dialog: CineDialog::default(),
+            redaction: RedactionPanel::default(),
+            toasts,
+            jobs: JobRunner::default(),
+            watcher: FolderWatcher::default(),
+            db,
+            db_browser: DbBrowser::default(),
+            comparison: Comparison::default(),
+            safety: SafetyReview::default(),
+            cavitation: CavitationAnalysis::default(),
+            deviation: DeviationCheck::default(),
+            steering: SteeringPanel::default(),
+            calibration: Calibration::default(),
+            foci: FociPlot::default(),
+            timeline: Timeline::default(),
+            units: UnitRegistry::default(),
             grid_data: vec![vec![
                 "Son.\n (#)".to_string(),
                 "Time".to_string(),
@@ -304,18 +277,269 @@ impl MyEguiApp {
         }
     }
 
+    #[inline(always)]
+    fn link_gallery(&mut self) {
+        if let (Some(df), Ok(source_tz)) =
+            (&self.df, summary::parse_tz(&self.time_options.source_tz))
+        {
+            let (durations, _) = timeline::sonication_durations(df);
+            self.gallery
+                .link(&summary::sonication_times(df), &durations, &source_tz);
+        }
+    }
+
+    // Parse a TreatSummary with the current settings, the bytes are kept for reloading
+    #[inline(always)]
+    fn load_summary(&mut self, bytes: Vec<u8>) -> bool {
+        match summary::read_csv_bytes(bytes.clone(), &self.time_options) {
+            Ok((mut df, info)) => {
+                if let Err(e) = steering::add_steering_columns(
+                    &mut df,
+                    &self.steering.natural_focus,
+                    &self.steering.profile,
+                ) {
+                    self.toasts
+                        .error(format!("Could not compute steering distances: {}", e));
+                }
+                if let Err(e) = deviation::add_deviation_columns(&mut df) {
+                    self.toasts
+                        .error(format!("Could not compute deviations: {}", e));
+                }
+                self.safety.analyse(&df);
+                self.table
+                    .set_highlights("safety", self.safety.highlights());
+                self.cavitation.analyse(&df);
+                self.table
+                    .set_highlights("cavitation", self.cavitation.highlights());
+                self.deviation.analyse(&df);
+                self.table
+                    .set_highlights("deviation", self.deviation.highlights());
+                self.foci.analyse(&df);
+                self.timeline.analyse(&df);
+                self.table
+                    .set_highlights("timeline", self.timeline.highlights());
+                self.units.fill(&df);
+                self.table.set_units(self.units.table_units());
+                self.df = Some(df);
+                self.table.invalidate();
+                self.load_info = info;
+                self.summary_bytes = Some(bytes);
+                self.link_gallery();
+                true
+            }
+            Err(e) => {
+                self.toasts.error(format!("Could not read summary: {}", e));
+                false
+            }
+        }
+    }
+
+    // Load a summary the user opened from a file and store it in the database
+    #[inline(always)]
+    fn open_summary(&mut self, ctx: &egui::Context, bytes: Vec<u8>) {
+        if !self.load_summary(bytes.clone()) || self.db.is_none() {
+            return;
+        }
+        let Some(df) = self.df.clone() else {
+            return;
+        };
+        let source = self
+            .filepath
+            .as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+        // Rewriting every sonication value takes a while on long treatments
+        self.jobs.spawn(ctx, STORE_JOB, move |_progress| {
+            TreatmentDb::open(&TreatmentDb::default_path())?
+                .record_treatment(&source, &bytes, &df)
+                .context("could not store treatment")?;
+            Ok(format!("Stored {}", source))
+        });
+    }
+
+    // Open the snapshots of an archive and load the summary read from it
+    #[inline(always)]
+    fn load_zip(&mut self, ctx: &egui::Context, path: PathBuf, summary: Vec<u8>) {
+        // Everything is read from the archive in memory, nothing is
+        // written next to it
+        // Keep the extracted files in memory until they are exported
+        self.zip_manifest = ExtractManifest::default();
+        if self.extract_images {
+            match archive::read_zip(path.as_path(), &self.extract_options) {
+                Ok(manifest) => self.zip_manifest = manifest,
+                Err(e) => {
+                    self.toasts.error(format!("Could not read images: {}", e));
+                }
+            }
+        };
+        self.gallery = Gallery::from_manifest(&self.zip_manifest);
+        self.selected_row = None;
+        self.filepath = Some(path);
+        self.open_summary(ctx, summary);
+    }
+
+    #[inline(always)]
+    fn reload_summary(&mut self) {
+        // The bytes stay around when the new settings cannot read the file
+        if let Some(bytes) = self.summary_bytes.clone() {
+            self.load_summary(bytes);
+        }
+    }
+
+    #[inline(always)]
+    fn show_time_settings(&mut self, ui: &mut egui::Ui) {
+        let before = self.time_options.clone();
+        ui.horizontal(|ui| {
+            ui.label("Time format:");
+            let selected = self
+                .time_options
+                .format
+                .clone()
+                .unwrap_or_else(|| "Auto".to_string());
+            egui::ComboBox::from_id_source("time_format")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.time_options.format, None, "Auto");
+                    for format in TIME_FORMATS {
+                        ui.selectable_value(
+                            &mut self.time_options.format,
+                            Some(format.to_string()),
+                            format,
+                        );
+                    }
+                });
+            if self.time_options.format != before.format {
+                self.time_format_draft = self.time_options.format.clone().unwrap_or_default();
+            }
+            if self.time_options.format.is_some() {
+                let response = ui.text_edit_singleline(&mut self.time_format_draft);
+                if !summary::is_valid_format(&self.time_format_draft) {
+                    ui.colored_label(Color32::RED, "Invalid format");
+                } else if response.lost_focus() {
+                    self.time_options.format = Some(self.time_format_draft.clone());
+                }
+            }
+            ui.label(format!("Detected: {}", self.load_info.time_format));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Source time zone:");
+            ui.text_edit_singleline(&mut self.time_options.source_tz);
+            ui.label("Show as:");
+            ui.radio_value(&mut self.time_options.display, DisplayZone::Utc, "UTC");
+            ui.radio_value(&mut self.time_options.display, DisplayZone::Local, "Local");
+        });
+        let csv = &mut self.time_options.csv;
+        ui.horizontal(|ui| {
+            ui.label("Encoding:");
+            egui::ComboBox::from_id_source("csv_encoding")
+                .selected_text(csv.encoding.map_or("Auto", TextEncoding::label))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut csv.encoding, None, "Auto");
+                    for encoding in TextEncoding::ALL {
+                        ui.selectable_value(&mut csv.encoding, Some(encoding), encoding.label());
+                    }
+                });
+            ui.label("Delimiter:");
+            ui.radio_value(&mut csv.delimiter, None, "Auto");
+            for (delimiter, label) in DELIMITERS {
+                ui.radio_value(&mut csv.delimiter, Some(delimiter), label);
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Decimal:");
+            ui.radio_value(&mut csv.decimal_comma, None, "Auto");
+            ui.radio_value(&mut csv.decimal_comma, Some(false), "Point");
+            ui.radio_value(&mut csv.decimal_comma, Some(true), "Comma");
+            let mut auto_header = csv.header_row.is_none();
+            if ui.checkbox(&mut auto_header, "Auto header line").changed() {
+                csv.header_row = (!auto_header).then_some(self.load_info.dialect.header_row);
+            }
+            if let Some(row) = csv.header_row.as_mut() {
+                let mut line = *row + 1;
+                ui.add(
+                    egui::DragValue::new(&mut line)
+                        .clamp_range(1..=100)
+                        .prefix("Header on line "),
+                );
+                *row = line - 1;
+            }
+        });
+        ui.label(format!("Detected: {}", self.load_info.dialect.describe()));
+        if summary::parse_tz(&self.time_options.source_tz).is_err() {
+            ui.colored_label(
+                Color32::RED,
+                "Unknown time zone, use an IANA name like Europe/Berlin",
+            );
+        } else if self.time_options != before {
+            self.reload_summary();
+        }
+        if let Some((start, end)) = self.df.as_ref().and_then(summary::treatment_span) {
+            ui.label(format!(
+                "Treatment duration {} ({} to {} UTC)",
+                format_duration((end - start).num_seconds()),
+                start.format("%Y-%m-%d %H:%M:%S"),
+                end.format("%H:%M:%S")
+            ));
+        }
+    }
+
+    #[inline(always)]
+    fn show_convert_dialog(&mut self, ctx: &egui::Context) {
+        let count = self
+            .gallery
+            .snapshots
+            .iter()
+            .filter(|snapshot| convert::is_bmp(&snapshot.archive_path))
+            .count();
+        let Some((source, out_dir)) = self
+            .convert_dialog
+            .show(ctx, if self.filepath.is_some() { count } else { 0 })
+        else {
+            return;
+        };
+        let loaded = match (&source, &self.filepath) {
+            (ConvertSource::Loaded, Some(path)) => {
+                convert::from_gallery(&self.gallery.snapshots, path)
+            }
+            _ => Vec::new(),
+        };
+        let mut options = self.convert_dialog.options.clone();
+        options.redaction = self.redaction.active();
+        self.jobs.spawn(ctx, "Convert snapshots", move |progress| {
+            let images = match source {
+                ConvertSource::Loaded => loaded,
+                ConvertSource::Folder(dir) => convert::collect_folder(&dir)?,
+            };
+            convert::convert_all(images, &out_dir, &options, progress)
+        });
+    }
+
     #[inline(always)]
     fn show_summary_ui(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if !self.gallery.snapshots.is_empty() {
+            egui::SidePanel::right("snapshot_gallery").show(ctx, |ui| {
+                self.gallery.show(ui, &mut self.selected_row);
+            });
+        }
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.with_layout(
                 egui::Layout::top_down_justified(egui::Align::Center),
                 |ui| {
                     if ui.button(RichText::new("From CSV").size(25.0)).clicked() {
                         if let Some(path) = rfd::FileDialog::new().pick_file() {
-                            self.filepath = Some(path);
-                            self.df = Some(
-                                read_csv_file(self.filepath.as_ref().unwrap().as_path()).unwrap(),
-                            );
+                            match std::fs::read(&path) {
+                                // The loaded treatment stays untouched when the file cannot be read
+                                Ok(bytes) => {
+                                    self.zip_manifest = ExtractManifest::default();
+                                    self.gallery = Gallery::default();
+                                    self.selected_row = None;
+                                    self.filepath = Some(path);
+                                    self.open_summary(ctx, bytes);
+                                }
+                                Err(e) => {
+                                    self.toasts.error(format!("Could not read summary: {}", e));
+                                }
+                            }
                         }
                     };
                 },
@@ -325,28 +549,27 @@ impl MyEguiApp {
                 |ui| {
                     if ui.button(RichText::new("From ZIP").size(25.0)).clicked() {
                         if let Some(path) = rfd::FileDialog::new().pick_file() {
-                            let csv_contents =
-                                read_csv_from_zip(path.as_path(), "TreatSummary.csv")
-                                    .expect("could not find summary");
-                            // Write the CSV contents to a temporary file
-                            // Create a new directory with the same name as the zip file
-                            let dir_path = path.with_extension("");
-                            std::fs::create_dir_all(&dir_path).expect("Failed to create directory");
-                            self.filepath = Some(dir_path.join("TreatSummary.csv"));
-                            std::fs::write(self.filepath.as_ref().unwrap().as_path(), csv_contents)
-                                .expect("No File");
-
-                            // Load the CSV file into the data frame
-                            self.df = Some(
-                                read_csv_file(self.filepath.as_ref().unwrap().as_path())
-                                    .expect("could not read"),
-                            );
-                            // Extract BMP images from zip files in the same directory
-                            if self.extract_images {
-                                extract_zip(path.as_path(), &dir_path).unwrap();
-                            };
+                            match archive::find_summary(&path) {
+                                Ok(Some((_, bytes))) => self.load_zip(ctx, path, bytes),
+                                // Let the user pick the table from the archive
+                                Ok(None) => {
+                                    self.toasts.warning("No treatment summary found in the ZIP");
+                                    self.zip_browser.open_archive(&path);
+                                }
+                                Err(e) => {
+                                    self.toasts.error(format!("Could not read ZIP: {:#}", e));
+                                }
+                            }
                         }
                     };
+                    if ui.button(RichText::new("Browse ZIP").size(20.0)).clicked() {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("ZIP", &["zip"])
+                            .pick_file()
+                        {
+                            self.zip_browser.open_archive(&path);
+                        }
+                    }
                 },
             );
             ui.with_layout(
@@ -358,6 +581,106 @@ impl MyEguiApp {
                     );
                 },
             );
+            egui::CollapsingHeader::new(RichText::new("Extraction settings").size(20.0))
+                .show(ui, |ui| self.show_extract_settings(ui));
+            egui::CollapsingHeader::new(RichText::new("Time settings").size(20.0))
+                .show(ui, |ui| self.show_time_settings(ui));
+            egui::CollapsingHeader::new(RichText::new("Redaction").size(20.0)).show(ui, |ui| {
+                if let Some(error) = self.redaction.show(ui, &self.gallery.snapshots) {
+                    self.toasts.error(error);
+                }
+                // Batch and watch folder extraction take the regions from the options
+                self.extract_options.redaction = self.redaction.active();
+            });
+            if let Some(df) = &self.df {
+                let changed = egui::CollapsingHeader::new(RichText::new("Steering").size(20.0))
+                    .show(ui, |ui| self.steering.show(ui, df, &self.natural_focus))
+                    .body_returned
+                    .unwrap_or(false);
+                if changed {
+                    self.reload_summary();
+                }
+            }
+            if self.df.is_some() {
+                let header = if self.safety.flagged() {
+                    RichText::new("Safety review (flagged)")
+                        .size(20.0)
+                        .color(Color32::RED)
+                } else {
+                    RichText::new("Safety review").size(20.0)
+                };
+                egui::CollapsingHeader::new(header)
+                    .id_source("safety_review")
+                    .show(ui, |ui| self.safety.show(ui, &mut self.selected_row));
+            }
+            if self.df.is_some() {
+                egui::CollapsingHeader::new(RichText::new("Cavitation dose").size(20.0)).show(
+                    ui,
+                    |ui| {
+                        if self.cavitation.show(ui) {
+                            self.table
+                                .set_highlights("cavitation", self.cavitation.highlights());
+                        }
+                    },
+                );
+            }
+            if self.df.is_some() {
+                let header = if self.deviation.passed() {
+                    RichText::new("Planned vs actual").size(20.0)
+                } else {
+                    RichText::new("Planned vs actual (QA fail)")
+                        .size(20.0)
+                        .color(Color32::RED)
+                };
+                egui::CollapsingHeader::new(header)
+                    .id_source("deviation_check")
+                    .show(ui, |ui| {
+                        if self.deviation.show(ui) {
+                            self.table
+                                .set_highlights("deviation", self.deviation.highlights());
+                        }
+                    });
+            }
+            if self.df.is_some() {
+                egui::CollapsingHeader::new(RichText::new("Foci positions").size(20.0)).show(
+                    ui,
+                    |ui| {
+                        self.foci.show(
+                            ui,
+                            &self.steering.natural_focus,
+                            &self.target,
+                            &mut self.selected_row,
+                        )
+                    },
+                );
+            }
+            if self.df.is_some() {
+                egui::CollapsingHeader::new(RichText::new("Units").size(20.0)).show(ui, |ui| {
+                    match self.units.show(ui) {
+                        Ok(true) => self.table.set_units(self.units.table_units()),
+                        Ok(false) => {}
+                        Err(e) => {
+                            self.toasts.error(format!("Units: {:#}", e));
+                        }
+                    }
+                });
+            }
+            if self.df.is_some() {
+                egui::CollapsingHeader::new(RichText::new("Timeline").size(20.0)).show(ui, |ui| {
+                    if self.timeline.show(ui, &mut self.selected_row) {
+                        self.table
+                            .set_highlights("timeline", self.timeline.highlights());
+                    }
+                });
+            }
+            egui::CollapsingHeader::new(RichText::new("Watch folders").size(20.0)).show(ui, |ui| {
+                if let Some(error) =
+                    self.watcher
+                        .show(ui, &self.time_options, &self.extract_options)
+                {
+                    self.toasts.error(error);
+                }
+            });
             ui.with_layout(
                 egui::Layout::top_down_justified(egui::Align::Center),
                 |ui| {
@@ -366,65 +689,169 @@ impl MyEguiApp {
                         .clicked()
                     {
                         if let Some(path) = rfd::FileDialog::new().pick_folder() {
-                            let dir_path = &path;
-                            let new_dir_path = dir_path.join(format!(
-                                "{}_extracted",
-                                dir_path.to_str().unwrap_or_default()
-                            ));
-                            if std::fs::create_dir_all(&new_dir_path).is_err() {
-                                eprintln!("Failed to create directory");
-                            } else {
-                                match std::fs::read_dir(&dir_path) {
-                                    Ok(entries) => {
-                                        for entry in entries {
-                                            match entry {
-                                                Ok(entry) => {
-                                                    if let Some(extension) =
-                                                        entry.path().extension()
-                                                    {
-                                                        if extension == "zip" {
-                                                            extract_zip(
-                                                                entry.path().as_path(),
-                                                                &new_dir_path,
-                                                            );
-                                                        }
-                                                    }
-                                                }
-                                                Err(_) => eprintln!("Failed to read entry"),
-                                            }
-                                        }
-                                    }
-                                    Err(_) => eprintln!("Failed to read directory"),
-                                }
-                            }
+                            let options = self.extract_options.clone();
+                            let batch = self.batch_options.clone();
+                            let time_options = self.time_options.clone();
+                            self.jobs.spawn(ctx, "From Snapshots", move |progress| {
+                                batch::extract_folder(
+                                    &path,
+                                    &options,
+                                    &batch,
+                                    &time_options,
+                                    progress,
+                                )
+                            });
                         }
                     };
+                    self.batch_options.show(ui);
+                    if ui
+                        .button(RichText::new("Convert snapshots").size(20.0))
+                        .clicked()
+                    {
+                        self.convert_dialog.open = true;
+                    }
+                    if !self.gallery.snapshots.is_empty()
+                        && ui.button(RichText::new("Export cine").size(20.0)).clicked()
+                    {
+                        self.cine_dialog.open = true;
+                    }
                 },
             );
+            ui.horizontal(|ui| {
+                if let Some(df) = &self.df {
+                    if ui
+                        .button(RichText::new("Export summary").size(20.0))
+                        .clicked()
+                    {
+                        self.export_dialog.open_for(df);
+                    }
+                    if ui
+                        .button(RichText::new("Query console").size(20.0))
+                        .clicked()
+                    {
+                        self.console.open = true;
+                    }
+                }
+                if !self.zip_manifest.extracted.is_empty()
+                    && ui
+                        .button(
+                            RichText::new(format!(
+                                "Export {} files",
+                                self.zip_manifest.extracted.len()
+                            ))
+                            .size(20.0),
+                        )
+                        .clicked()
+                {
+                    if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                        match archive::write_manifest(
+                            &self.zip_manifest,
+                            &path,
+                            self.redaction.active().as_ref(),
+                        ) {
+                            Ok(()) => {
+                                self.toasts.success(format!(
+                                    "Saved {} files to {}",
+                                    self.zip_manifest.extracted.len(),
+                                    path.display()
+                                ));
+                            }
+                            Err(e) => {
+                                self.toasts.error(format!("Export failed: {}", e));
+                            }
+                        }
+                    }
+                }
+                if !self.zip_manifest.extracted.is_empty() || !self.zip_manifest.skipped.is_empty()
+                {
+                    ui.toggle_value(
+                        &mut self.show_manifest,
+                        RichText::new("Manifest").size(20.0),
+                    );
+                }
+            });
 
-            if self.df.is_some() {
-                let df = self.df.as_ref().unwrap();
-                egui::ScrollArea::both().show(ui, |ui| {
-                    Grid::new("dataframe_grid").show(ui, |ui| {
-                        // Add column names to the grid
-                        df.get_column_names().iter().for_each(|name| {
-                            ui.label(*name);
-                        });
-                        ui.end_row();
+            if let Some(df) = &self.df {
+                self.table.show(ui, df, &mut self.selected_row);
+            }
+        });
+    }
+    #[inline(always)]
+    fn show_extract_settings(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            for (name, patterns) in FILTER_PRESETS {
+                let mut enabled = patterns
+                    .iter()
+                    .all(|p| self.extract_options.include.iter().any(|i| i == p));
+                if ui.checkbox(&mut enabled, name).changed() {
+                    if enabled {
+                        self.extract_options
+                            .include
+                            .extend(patterns.iter().map(|p| p.to_string()));
+                    } else {
+                        self.extract_options
+                            .include
+                            .retain(|i| !patterns.contains(&i.as_str()));
+                    }
+                    self.include_filter = self.extract_options.include.join(", ");
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Include:");
+            if ui.text_edit_singleline(&mut self.include_filter).changed() {
+                self.extract_options.include = split_patterns(&self.include_filter);
+            }
+            ui.label("Exclude:");
+            if ui.text_edit_singleline(&mut self.exclude_filter).changed() {
+                self.extract_options.exclude = split_patterns(&self.exclude_filter);
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.radio_value(
+                &mut self.extract_options.layout,
+                ExtractLayout::Preserve,
+                "Keep archive folders",
+            );
+            ui.radio_value(
+                &mut self.extract_options.layout,
+                ExtractLayout::Prefix,
+                "Flatten with archive prefix",
+            );
+        });
+    }
 
-                        // Add values to the grid
-                        (0..df.height()).for_each(|row_idx| {
-                            df.get_column_names().iter().for_each(|name| {
-                                let value = df.column(name).unwrap().get(row_idx).unwrap();
-                                ui.label(format!("{:.2}", value));
-                            });
+    #[inline(always)]
+    fn show_manifest_window(&mut self, ctx: &egui::Context) {
+        let manifest = &self.zip_manifest;
+        egui::Window::new("Extraction manifest")
+            .open(&mut self.show_manifest)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} extracted ({:.1} MB), {} skipped",
+                    manifest.extracted.len(),
+                    manifest.total_bytes() as f64 / 1e6,
+                    manifest.skipped.len()
+                ));
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    Grid::new("manifest_grid").striped(true).show(ui, |ui| {
+                        for file in &manifest.extracted {
+                            ui.label(&file.archive_path);
+                            ui.label(file.path.display().to_string());
+                            ui.label(format!("{} B", file.data.len()));
                             ui.end_row();
-                        });
+                        }
+                        for entry in &manifest.skipped {
+                            ui.label(&entry.archive_path);
+                            ui.colored_label(Color32::GRAY, "skipped");
+                            ui.label(&entry.reason);
+                            ui.end_row();
+                        }
                     });
                 });
-            }
-        });
+            });
     }
+
     #[inline(always)]
     fn show_parameter_ui(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -498,8 +925,34 @@ impl MyEguiApp {
                                     .underline(),
                             );
                         });
+                        ui.horizontal(|ui| {
+                            ui.label(
+                                RichText::new(format!(
+                                    "Efficiency profile: {}",
+                                    self.steering.profile.name
+                                ))
+                                .size(20.0),
+                            );
+                            if ui.button("Load profile").clicked() {
+                                if let Some(path) = rfd::FileDialog::new()
+                                    .add_filter("Efficiency profile", &["csv"])
+                                    .pick_file()
+                                {
+                                    match EfficiencyProfile::load(&path) {
+                                        Ok(profile) => self.set_profile(profile),
+                                        Err(e) => {
+                                            self.toasts
+                                                .error(format!("Could not load profile: {:#}", e));
+                                        }
+                                    }
+                                }
+                            }
+                            if ui.button("Default profile").clicked() {
+                                self.set_profile(EfficiencyProfile::default());
+                            }
+                        });
                         self.distance = distance(&self.natural_focus, &self.target);
-                        self.efficiency = efficiency(self.distance);
+                        self.efficiency = efficiency(&self.steering.profile, self.distance);
                         ui.add(
                             egui::Slider::new(&mut self.power, 0.0..=100.0)
                                 .text(RichText::new("Power (W)").size(20.0))
@@ -667,16 +1120,154 @@ impl MyEguiApp {
                                 wtr.flush().expect("failed to close");
                             }
                         }
+                        if let Some(db) = &self.db {
+                            if ui.button("Save session").clicked() {
+                                match db.save_session(&self.summaryname, &self.grid_data) {
+                                    Ok(_) => {
+                                        self.db_browser.refresh();
+                                        self.toasts.success("Session saved to the database");
+                                    }
+                                    Err(e) => {
+                                        self.toasts
+                                            .error(format!("Could not save session: {:#}", e));
+                                    }
+                                }
+                            }
+                        }
                     },
                 );
             });
         });
     }
 
+    #[inline(always)]
+    fn show_compare_ui(&mut self, ctx: &egui::Context) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if let Some(df) = &self.df {
+                ui.horizontal(|ui| {
+                    for (slot_b, label) in [
+                        (false, "Current summary as A"),
+                        (true, "Current summary as B"),
+                    ] {
+                        if ui.button(label).clicked() {
+                            let name = self
+                                .filepath
+                                .as_ref()
+                                .and_then(|path| path.file_name())
+                                .map(|name| name.to_string_lossy().into_owned())
+                                .unwrap_or_else(|| "current summary".to_string());
+                            self.comparison.set(
+                                slot_b,
+                                Treatment {
+                                    name,
+                                    df: df.clone(),
+                                },
+                            );
+                        }
+                    }
+                });
+            }
+            if let Some(error) = self.comparison.show(ui, &self.time_options) {
+                self.toasts.error(error);
+            }
+        });
+    }
+
+    #[inline(always)]
+    fn set_profile(&mut self, profile: EfficiencyProfile) {
+        self.toasts
+            .info(format!("Using efficiency profile {}", profile.name));
+        self.steering.profile = profile;
+        if self.df.is_some() {
+            self.reload_summary();
+        }
+    }
+
+    #[inline(always)]
+    fn show_calibration_ui(&mut self, ctx: &egui::Context) {
+        let action = egui::CentralPanel::default()
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical()
+                    .show(ui, |ui| {
+                        self.calibration.show(
+                            ui,
+                            &self.time_options,
+                            self.steering.natural_focus,
+                            &self.steering.profile,
+                        )
+                    })
+                    .inner
+            })
+            .inner;
+        match action {
+            Some(CalibrationAction::UseProfile(profile)) => self.set_profile(profile),
+            Some(CalibrationAction::Error(e)) => {
+                self.toasts.error(e);
+            }
+            None => {}
+        }
+    }
+
+    #[inline(always)]
+    fn show_database_ui(&mut self, ctx: &egui::Context) {
+        let action = egui::CentralPanel::default()
+            .show(ctx, |ui| match &self.db {
+                Some(db) => self.db_browser.show(ui, db),
+                None => {
+                    ui.label("The treatment database could not be opened");
+                    None
+                }
+            })
+            .inner;
+        let Some(db) = &self.db else {
+            return;
+        };
+        match action {
+            Some(BrowserAction::Treatment(id)) => match db.load_treatment(id) {
+                Ok((source, bytes)) => {
+                    self.zip_manifest = ExtractManifest::default();
+                    self.gallery = Gallery::default();
+                    self.selected_row = None;
+                    self.filepath = Some(PathBuf::from(source));
+                    self.show_mode = "summary".into();
+                    self.load_summary(bytes);
+                }
+                Err(e) => {
+                    self.toasts.error(format!("{:#}", e));
+                }
+            },
+            Some(BrowserAction::Session(id)) => match db.load_session(id) {
+                Ok(rows) => {
+                    self.grid_data = rows;
+                    self.show_mode = "parameters".into();
+                }
+                Err(e) => {
+                    self.toasts.error(format!("{:#}", e));
+                }
+            },
+            Some(BrowserAction::DicomFolder(path)) => {
+                if DICOM_JOBS.iter().any(|name| self.jobs.is_running(name)) {
+                    self.toasts.info("Wait for the running DICOM job to finish");
+                } else {
+                    self.selected_folder = Some(path.clone());
+                    self.show_mode = "dicom".into();
+                    let presorted = self.presorted.clone();
+                    let unique_ids = self.unique_ids.clone();
+                    self.jobs.spawn(ctx, DICOM_JOBS[0], move |progress| {
+                        dicom_tools::index_folder(&path, &presorted, &unique_ids, progress)
+                    });
+                }
+            }
+            None => {}
+        }
+    }
+
     #[inline(always)]
     fn show_dicom_ui(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // The series map is locked by the worker while a DICOM job runs
+        let busy = DICOM_JOBS.iter().any(|name| self.jobs.is_running(name));
         // Print the series and number of images
-        if let Some(ref _folder) = self.selected_folder {
+        if let (Some(ref _folder), false) = (&self.selected_folder, busy) {
             egui::SidePanel::right("side_panel").show(ctx, |ui| {
                 ui.label(RichText::new("Series and number of images:").size(25.0));
                 let mut selected_series = None;
@@ -684,7 +1275,7 @@ impl MyEguiApp {
                     .selected_text(&self.selected_uid)
                     .width(ui.available_width()) // Set the width here
                     .show_ui(ui, |ui| {
-                        for entry in &self.presorted {
+                        for entry in self.presorted.iter() {
                             let series = entry.key();
                             let count = entry.value().len();
                             ui.selectable_value(
@@ -704,7 +1295,11 @@ impl MyEguiApp {
                         // Display the image from dicom_object
                         let pixel_data = &dicom_object.decode_pixel_data().unwrap();
                         let size = [pixel_data.rows() as _, pixel_data.columns() as _];
-                        let dynamic_image = pixel_data.to_dynamic_image(0).unwrap().to_rgba8();
+                        let mut dynamic_image = pixel_data.to_dynamic_image(0).unwrap().to_rgba8();
+                        // Show what the anonymized export will look like
+                        if let Some(redaction) = self.redaction.active() {
+                            redaction.mask(&mut dynamic_image);
+                        }
                         let pixels = dynamic_image.as_flat_samples();
                         let image = ColorImage::from_rgba_unmultiplied(size, pixels.as_slice());
                         let texture_options = egui::TextureOptions::default(); // or any other options you want to set
@@ -725,155 +1320,70 @@ impl MyEguiApp {
         };
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.with_layout(egui::Layout::top_down_justified(egui::Align::Min), |ui| {
+                if busy {
+                    ui.label(RichText::new("Waiting for DICOM jobs to finish").size(20.0));
+                }
                 if ui
-                    .button(RichText::new("Select folder").size(25.0))
+                    .add_enabled(
+                        !busy,
+                        egui::Button::new(RichText::new("Select folder").size(25.0)),
+                    )
                     .clicked()
                 {
                     if let Some(path) = rfd::FileDialog::new()
                         .set_file_name("Select a folder")
                         .pick_folder()
                     {
-                        self.selected_folder = Some(path);
-                    };
-
-                    if let Some(ref folder) = self.selected_folder {
-                        let path = Path::new(folder);
-
-                        let objects: Vec<_> = WalkDirGeneric::<(u32, bool)>::new(path)
-                            .process_read_dir(|_depth, _path, read_dir_state, _children| {
-                                *read_dir_state += 1;
-                            })
-                            .try_into_iter()
-                            .expect("Cannot Make Iterator")
-                            .filter_map(process_dicom)
-                            .collect();
-
-                        objects.into_par_iter().for_each(|(object, file)| {
-                            if let Ok(series_instance_uid) = object.element(Tag(0x0020, 0x000E)) {
-                                if let Ok(uid) = series_instance_uid.to_str() {
-                                    let mut series =
-                                        self.presorted.entry(uid.to_string()).or_default();
-                                    // Assuming `sop_instance_uid` is a unique identifier for each DICOM object
-                                    if let Ok(sop_instance_uid) =
-                                        object.element(Tag(0x0008, 0x0018))
-                                    {
-                                        if let Ok(sop_id) = sop_instance_uid.to_str() {
-                                            if self.unique_ids.insert(sop_id.to_string()) {
-                                                series.push((object, file));
-                                                series.par_sort_by(|(a, _), (b, _)| {
-                                                    let image_position_patient_a =
-                                                        get_image_position(a);
-                                                    let image_position_patient_b =
-                                                        get_image_position(b);
-                                                    image_position_patient_a
-                                                        .partial_cmp(&image_position_patient_b)
-                                                        .unwrap_or(Ordering::Equal)
-                                                });
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                        self.selected_folder = Some(path.clone());
+                        let presorted = self.presorted.clone();
+                        let unique_ids = self.unique_ids.clone();
+                        self.jobs.spawn(ctx, DICOM_JOBS[0], move |progress| {
+                            dicom_tools::index_folder(&path, &presorted, &unique_ids, progress)
                         });
                     };
                 }
 
                 if let Some(ref folder) = self.selected_folder {
-                    let path = Path::new(folder);
                     if ui
-                        .button(RichText::new("Move sorted dicoms").size(25.0))
+                        .add_enabled(
+                            !busy,
+                            egui::Button::new(RichText::new("Move sorted dicoms").size(25.0)),
+                        )
                         .clicked()
                     {
-                        if let Some(objects) = self.presorted.get(&self.selected_uid) {
-                            let new_dir =
-                                path.join(format!("processed/sorted/{}", &self.selected_uid));
-                            std::fs::create_dir_all(&new_dir).expect("Failed to create directory");
-
-                            for (object, file) in objects.value() {
-                                let new_path = new_dir
-                                    .join(file.file_name().expect("Failed to get file name"));
-                                object
-                                    .write_to_file(new_path)
-                                    .expect("Unable to write DICOM object");
-                            }
-                        } else {
-                            // Process each object and its path in parallel using rayon
-                            self.presorted.par_iter().for_each(|entry| {
-                                let series = entry.key();
-                                let objects = entry.value();
-                                let new_dir = path.join(format!("processed/sorted/{}", series));
-                                std::fs::create_dir_all(&new_dir)
-                                    .expect("Failed to create directory");
-
-                                for (object, file) in objects {
-                                    let new_path = new_dir
-                                        .join(file.file_name().expect("Failed to get file name"));
-                                    object
-                                        .write_to_file(new_path)
-                                        .expect("Unable to write DICOM object");
-                                }
-                            });
-                        }
+                        let path = folder.clone();
+                        let presorted = self.presorted.clone();
+                        let selected_uid = self.selected_uid.clone();
+                        self.jobs.spawn(ctx, DICOM_JOBS[1], move |progress| {
+                            dicom_tools::move_sorted(&path, &presorted, &selected_uid, progress)
+                        });
                     };
                     if ui
-                        .button(RichText::new("Anonymize and save dicoms").size(25.0))
+                        .add_enabled(
+                            !busy,
+                            egui::Button::new(
+                                RichText::new("Anonymize and save dicoms").size(25.0),
+                            ),
+                        )
                         .clicked()
                     {
-                        // Anonymize each object in parallel using rayon
-                        let tags_to_anonymize: HashSet<Tag> = [
-                            Tag(0x0008, 0x0014), // Instance Creator UID
-                            Tag(0x0008, 0x0018), // SOP Instance UID
-                            Tag(0x0008, 0x0050), // Accession Number
-                            Tag(0x0008, 0x0080), // Institution Name
-                            Tag(0x0008, 0x0081), // Institution Address
-                            Tag(0x0008, 0x0090), // Referring Physician's Name
-                            Tag(0x0008, 0x0092), // Referring Physician's Address
-                            Tag(0x0008, 0x0094), // Referring Physician's Telephone numbers
-                            Tag(0x0008, 0x1010), // Station Name
-                            Tag(0x0008, 0x1030), // Study Description
-                            Tag(0x0008, 0x103E), // Series Description
-                            Tag(0x0008, 0x1040), // Institutional Department name
-                            Tag(0x0008, 0x1048), // Physician(s) of Record
-                            Tag(0x0008, 0x1050), // Performing Physicians' Name
-                            Tag(0x0008, 0x1060), // Name of Physician(s) Reading study
-                            Tag(0x0008, 0x1070), // Operator's Name
-                            Tag(0x0008, 0x1080), // Admitting Diagnoses Description
-                            Tag(0x0008, 0x1155), // Referenced SOP Instance UID
-                            Tag(0x0008, 0x2111), // Derivation Description
-                            Tag(0x0010, 0x0010), // Patient's Name
-                            Tag(0x0010, 0x0020), // Patient ID
-                            Tag(0x0010, 0x0030), // Patient Birth Day
-                            Tag(0x0010, 0x0032), // Patient's Birth Time
-                            Tag(0x0010, 0x0040), // Patient's Sex
-                            Tag(0x0010, 0x1010), // Patient's Age
-                            Tag(0x0014, 1001),   // Custom Tag
-                        ]
-                        .iter()
-                        .cloned()
-                        .collect();
-                        self.presorted.par_iter_mut().for_each(|mut entry| {
-                            let series = entry.key().clone();
-                            let objects = entry.value_mut();
-                            let new_dir = path.join(format!("processed/anonymized/{}", series));
-                            std::fs::create_dir_all(&new_dir).expect("Failed to create directory");
-
-                            for (object, file) in objects {
-                                // List of tags to anonymize
-                                for tag in &tags_to_anonymize {
-                                    object.remove_element(*tag);
-                                }
-                                // Save the anonymized DICOM object back to disk with a new name
-                                let new_file_name = format!(
-                                    "{}_anonymized.dcm",
-                                    file.file_stem().unwrap().to_str().unwrap()
-                                );
-                                let new_path = new_dir.join(new_file_name);
-                                object
-                                    .write_to_file(new_path)
-                                    .expect("Unable to write DICOM object");
-                            }
+                        let path = folder.clone();
+                        let presorted = self.presorted.clone();
+                        let redaction = self.redaction.active();
+                        self.jobs.spawn(ctx, DICOM_JOBS[2], move |progress| {
+                            dicom_tools::anonymize(&path, &presorted, redaction.as_ref(), progress)
                         });
                     }
+                    if ui
+                        .checkbox(
+                            &mut self.redaction.enabled,
+                            RichText::new("Black out burned-in patient information").size(20.0),
+                        )
+                        .on_hover_text("Regions are set up under Redaction in the summary view")
+                        .changed()
+                    {
+                        self.extract_options.redaction = self.redaction.active();
+                    }
                 };
             });
         });
@@ -888,6 +1398,61 @@ impl eframe::App for MyEguiApp {
     }
     #[inline(always)]
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        for job in self.jobs.poll(ctx) {
+            if job.name == STORE_JOB
+                || (job.name == "From Snapshots" && self.batch_options.load_summaries)
+            {
+                self.db_browser.refresh();
+            }
+            match &job.state {
+                JobState::Finished(summary) => {
+                    self.toasts.success(format!("{}: {}", job.name, summary));
+                    if let (true, Some(db), Some(folder)) = (
+                        job.name == DICOM_JOBS[0],
+                        self.db.as_mut(),
+                        &self.selected_folder,
+                    ) {
+                        let series: Vec<(String, usize)> = self
+                            .presorted
+                            .iter()
+                            .map(|entry| (entry.key().clone(), entry.value().len()))
+                            .collect();
+                        match db.record_dicom_series(folder, &series) {
+                            Ok(()) => self.db_browser.refresh(),
+                            Err(e) => {
+                                self.toasts
+                                    .error(format!("Could not store DICOM index: {:#}", e));
+                            }
+                        }
+                    }
+                }
+                JobState::Failed(error) => {
+                    self.toasts.error(format!("{} failed: {}", job.name, error));
+                }
+                JobState::Cancelled => {
+                    self.toasts.info(format!("{} cancelled", job.name));
+                }
+                JobState::Running => {}
+            }
+        }
+        for event in self.watcher.poll() {
+            match &event.result {
+                Ok(dest) => {
+                    self.toasts
+                        .success(format!("Ingested treatment into {}", dest.display()));
+                    self.db_browser.refresh();
+                }
+                Err(e) => match &event.source {
+                    Some(source) => {
+                        self.toasts
+                            .error(format!("Could not ingest {}: {}", source.display(), e));
+                    }
+                    None => {
+                        self.toasts.error(e);
+                    }
+                },
+            }
+        }
         egui::TopBottomPanel::top("my_top_panel").show(ctx, |ui| {
             menu::bar(ui, |ui| {
                 ui.horizontal(|ui| {
@@ -916,15 +1481,111 @@ impl eframe::App for MyEguiApp {
                             if ui.button(RichText::new("dicom tools").size(15.0)).clicked() {
                                 self.show_mode = "dicom".into()
                             };
+                            if ui
+                                .button(RichText::new("Compare Treatments").size(15.0))
+                                .clicked()
+                            {
+                                self.show_mode = "compare".into()
+                            };
+                            if ui
+                                .button(RichText::new("Treatment Database").size(15.0))
+                                .clicked()
+                            {
+                                self.show_mode = "database".into()
+                            };
+                            if ui
+                                .button(RichText::new("Efficiency Calibration").size(15.0))
+                                .clicked()
+                            {
+                                self.show_mode = "calibration".into()
+                            };
                         });
                     });
+                    ui.separator();
+                    let jobs_label = format!("Jobs ({})", self.jobs.running());
+                    ui.toggle_value(&mut self.jobs.show, RichText::new(jobs_label).size(15.0));
                 });
             });
         });
         match &*self.show_mode {
             "parameters" => self.show_parameter_ui(ctx, frame),
-            "summary" => self.show_summary_ui(ctx, frame),
+            "summary" => {
+                self.show_summary_ui(ctx, frame);
+                if let (true, Some(df)) = (self.export_dialog.open, &self.df) {
+                    let stem = self
+                        .filepath
+                        .as_ref()
+                        .and_then(|path| path.file_stem())
+                        .and_then(|stem| stem.to_str())
+                        .unwrap_or("TreatSummary");
+                    match self.export_dialog.show(
+                        ctx,
+                        df,
+                        &format!("{}_simplify", stem),
+                        &self.units,
+                    ) {
+                        Some(Ok(message)) => {
+                            self.toasts.success(message);
+                        }
+                        Some(Err(e)) => {
+                            self.toasts.error(format!("Export failed: {}", e));
+                        }
+                        None => {}
+                    }
+                }
+                if let (true, Some(df)) = (self.console.open, &self.df) {
+                    let mut tables = vec![("summary", df)];
+                    for (name, treatment) in [
+                        ("compare_a", &self.comparison.a),
+                        ("compare_b", &self.comparison.b),
+                    ] {
+                        if let Some(treatment) = treatment {
+                            tables.push((name, &treatment.df));
+                        }
+                    }
+                    match self.console.show(ctx, &tables, self.db.as_ref()) {
+                        Some(Ok(message)) => {
+                            self.toasts.success(message);
+                        }
+                        Some(Err(e)) => {
+                            self.toasts.error(format!("Query console: {:#}", e));
+                        }
+                        None => {}
+                    }
+                }
+                if self.show_manifest {
+                    self.show_manifest_window(ctx);
+                }
+                if self.zip_browser.open {
+                    if let Some((path, bytes)) = self.zip_browser.show(ctx) {
+                        self.load_zip(ctx, path, bytes);
+                    }
+                }
+                if self.convert_dialog.open {
+                    self.show_convert_dialog(ctx);
+                }
+                if self.cine_dialog.open {
+                    if let Some(path) =
+                        self.cine_dialog
+                            .show(ctx, &self.gallery.snapshots, self.selected_row)
+                    {
+                        let mut options = self.cine_dialog.options.clone();
+                        options.redaction = self.redaction.active();
+                        let frames = cine::select_frames(
+                            &self.gallery.snapshots,
+                            options.scope,
+                            self.selected_row,
+                        );
+                        self.jobs.spawn(ctx, "Export cine", move |progress| {
+                            cine::export_cine(frames, &path, &options, progress)
+                        });
+                    }
+                }
+            }
             "dicom" => self.show_dicom_ui(ctx, frame),
+            "database" => self.show_database_ui(ctx),
+            "compare" => self.show_compare_ui(ctx),
+            "calibration" => self.show_calibration_ui(ctx),
             _ => (), // handle other cases
         };
         if self.show_confirmation_dialog {
@@ -945,7 +1606,10 @@ impl eframe::App for MyEguiApp {
                     });
                 });
         };
-
+        if self.jobs.show {
+            self.jobs.show_window(ctx);
+        }
+        self.toasts.show(ctx);
     }
     
 }
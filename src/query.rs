@@ -0,0 +1,232 @@
+use crate::{
+    db::{SavedQuery, TreatmentDb},
+    export,
+    table::DataTable,
+};
+use anyhow::Result;
+use eframe::egui::{self, Color32, RichText};
+use polars::{prelude::*, sql::SQLContext};
+use std::{fs::File, io::BufWriter};
+
+const EXAMPLE: &str =
+    "SELECT AVG(\"Act. Energy per subspot\") FROM summary WHERE \"Num. of SubSonic\" = 32";
+
+// Run a query against the given tables, each registered under its name
+#[inline(always)]
+pub fn run_query(query: &str, tables: &[(&str, &DataFrame)]) -> PolarsResult<DataFrame> {
+    let mut context = SQLContext::new();
+    for (name, df) in tables {
+        context.register(name, (*df).clone().lazy());
+    }
+    context.execute(query)?.collect()
+}
+
+pub struct QueryConsole {
+    pub open: bool,
+    query: String,
+    result: Option<DataFrame>,
+    error: Option<String>,
+    table: DataTable,
+    selected_row: Option<usize>,
+    save_name: String,
+    // Loaded from the database when the window opens
+    saved: Option<Vec<SavedQuery>>,
+}
+
+impl Default for QueryConsole {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            open: false,
+            query: EXAMPLE.to_string(),
+            result: None,
+            error: None,
+            table: DataTable::default(),
+            selected_row: None,
+            save_name: String::new(),
+            saved: None,
+        }
+    }
+}
+
+impl QueryConsole {
+    #[inline(always)]
+    fn run(&mut self, tables: &[(&str, &DataFrame)]) {
+        match run_query(&self.query, tables) {
+            Ok(df) => {
+                self.result = Some(df);
+                self.error = None;
+                self.selected_row = None;
+                self.table.invalidate();
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    #[inline(always)]
+    fn export(&self) -> Result<Option<String>> {
+        let Some(df) = &self.result else {
+            return Ok(None);
+        };
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("CSV", &["csv"])
+            .set_file_name("query.csv")
+            .save_file()
+        else {
+            return Ok(None);
+        };
+        export::write_csv(
+            df,
+            BufWriter::new(File::create(&path)?),
+            &export::CsvOptions::default(),
+        )?;
+        Ok(Some(format!("Saved {}", path.display())))
+    }
+
+    #[inline(always)]
+    fn show_saved(&mut self, ui: &mut egui::Ui, db: &TreatmentDb) -> Result<()> {
+        if self.saved.is_none() {
+            self.saved = Some(db.saved_queries()?);
+        }
+        ui.horizontal(|ui| -> Result<()> {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut self.save_name);
+            if ui
+                .add_enabled(
+                    !self.save_name.trim().is_empty(),
+                    egui::Button::new("Save query"),
+                )
+                .clicked()
+            {
+                db.save_query(self.save_name.trim(), &self.query)?;
+                self.saved = None;
+            }
+            Ok(())
+        })
+        .inner?;
+        let mut delete = None;
+        for saved in self.saved.iter().flatten() {
+            ui.horizontal(|ui| {
+                if ui.small_button("✖").clicked() {
+                    delete = Some(saved.id);
+                }
+                if ui.link(&saved.name).on_hover_text(&saved.query).clicked() {
+                    self.query = saved.query.clone();
+                    self.save_name = saved.name.clone();
+                }
+            });
+        }
+        if let Some(id) = delete {
+            db.delete_query(id)?;
+            self.saved = None;
+        }
+        Ok(())
+    }
+
+    // Returns a message for the toasts, errors of the query itself are shown in the window
+    #[inline(always)]
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        tables: &[(&str, &DataFrame)],
+        db: Option<&TreatmentDb>,
+    ) -> Option<Result<String>> {
+        let mut message = None;
+        let mut open = self.open;
+        egui::Window::new("Query console")
+            .open(&mut open)
+            .default_width(700.0)
+            .show(ctx, |ui| {
+                ui.label(
+                    RichText::new(format!(
+                        "Tables: {}",
+                        tables
+                            .iter()
+                            .map(|(name, df)| format!("{} ({} rows)", name, df.height()))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                    .weak(),
+                );
+                ui.add(
+                    egui::TextEdit::multiline(&mut self.query)
+                        .code_editor()
+                        .desired_rows(4)
+                        .desired_width(f32::INFINITY),
+                );
+                ui.horizontal(|ui| {
+                    let run = ui.button(RichText::new("Run").strong()).clicked()
+                        || ui.input(|i| i.modifiers.ctrl && i.key_pressed(egui::Key::Enter));
+                    if run {
+                        self.run(tables);
+                    }
+                    if ui
+                        .add_enabled(self.result.is_some(), egui::Button::new("Export result"))
+                        .clicked()
+                    {
+                        message = self.export().transpose();
+                    }
+                    ui.label(RichText::new("Ctrl+Enter runs the query").weak());
+                });
+                if let Some(db) = db {
+                    egui::CollapsingHeader::new("Saved queries").show(ui, |ui| {
+                        if let Err(e) = self.show_saved(ui, db) {
+                            message = Some(Err(e));
+                        }
+                    });
+                }
+                if let Some(error) = &self.error {
+                    ui.colored_label(Color32::RED, error);
+                }
+                if let Some(df) = &self.result {
+                    ui.label(format!("{} rows × {} columns", df.height(), df.width()));
+                    self.table.show(ui, df, &mut self.selected_row);
+                }
+            });
+        self.open = open;
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn summary() -> DataFrame {
+        df!(
+            "Num. of SubSonic" => &[32i64, 32, 16],
+            "Act. Energy per subspot" => &[10.0, 20.0, 100.0]
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn example_runs_against_the_summary_table() {
+        let df = summary();
+        let result = run_query(EXAMPLE, &[("summary", &df)]).unwrap();
+        assert_eq!(result.shape(), (1, 1));
+        let mean = result.get_columns()[0].f64().unwrap().get(0);
+        assert_eq!(mean, Some(15.0));
+    }
+
+    #[test]
+    fn every_table_is_registered_under_its_name() {
+        let (a, b) = (summary(), summary().head(Some(1)));
+        let tables = [("summary", &a), ("compare_a", &a), ("compare_b", &b)];
+        let result = run_query("SELECT * FROM compare_b", &tables).unwrap();
+        assert_eq!(result.height(), 1);
+        let result = run_query("SELECT * FROM compare_a", &tables).unwrap();
+        assert_eq!(result.height(), 3);
+    }
+
+    #[test]
+    fn bad_queries_are_errors() {
+        let df = summary();
+        let tables = [("summary", &df)];
+        assert!(run_query("SELEC * FROM summary", &tables).is_err());
+        assert!(run_query("SELECT * FROM compare_a", &tables).is_err());
+        assert!(run_query("SELECT \"No such column\" FROM summary", &tables).is_err());
+        assert!(run_query("", &tables).is_err());
+    }
+}
@@ -0,0 +1,728 @@
+use crate::gallery::Snapshot;
+use anyhow::{bail, Context, Result};
+use dicom::core::{DataElement, PrimitiveValue};
+use dicom::object::{file::ReadPreamble, FileDicomObject, InMemDicomObject, OpenFileOptions, Tag};
+use eframe::egui::{self, Color32, ColorImage, Grid, RichText, TextureHandle};
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use std::{io::Cursor, path::Path};
+
+const PIXEL_DATA: Tag = Tag(0x7FE0, 0x0010);
+const EXPLICIT_VR_BIG_ENDIAN: &str = "1.2.840.10008.1.2.2";
+
+// Rectangle in percent of the image, so one template covers screenshots and their thumbnails
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Region {
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Region {
+    // Pixel bounds as (x0, y0, x1, y1), clipped to the image
+    #[inline(always)]
+    fn pixels(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let scale = |percent: f32, size: u32| {
+            ((percent.clamp(0.0, 100.0) / 100.0 * size as f32).round() as u32).min(size)
+        };
+        (
+            scale(self.left, width),
+            scale(self.top, height),
+            scale(self.left + self.width, width),
+            scale(self.top + self.height, height),
+        )
+    }
+}
+
+// Regions of one screen layout, the size picks the template for an image
+#[derive(Clone, PartialEq, Debug)]
+pub struct LayoutTemplate {
+    pub name: String,
+    // None matches images of any size without a more specific template
+    pub size: Option<(u32, u32)>,
+    pub regions: Vec<Region>,
+}
+
+impl Default for LayoutTemplate {
+    #[inline(always)]
+    fn default() -> Self {
+        // The console prints the patient name and ID in the screen header
+        Self {
+            name: "Screen header".to_string(),
+            size: None,
+            regions: vec![Region {
+                left: 0.0,
+                top: 0.0,
+                width: 100.0,
+                height: 8.0,
+            }],
+        }
+    }
+}
+
+pub enum DicomRedaction {
+    Redacted,
+    // Nothing can be burned in, e.g. a DICOMDIR or a structured report
+    NoPixelData,
+    NoTemplate,
+    Skipped(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Redaction {
+    pub templates: Vec<LayoutTemplate>,
+}
+
+#[inline(always)]
+fn element_u32(object: &InMemDicomObject, tag: Tag) -> Option<u32> {
+    object.element(tag).ok()?.to_int::<u32>().ok()
+}
+
+impl Redaction {
+    #[inline(always)]
+    pub fn template_for(&self, width: u32, height: u32) -> Option<&LayoutTemplate> {
+        self.templates
+            .iter()
+            .find(|template| template.size == Some((width, height)))
+            .or_else(|| {
+                self.templates
+                    .iter()
+                    .find(|template| template.size.is_none())
+            })
+    }
+
+    // Black out the regions of the matching template, false when no template applies
+    #[inline(always)]
+    pub fn mask(&self, image: &mut RgbaImage) -> bool {
+        let (width, height) = image.dimensions();
+        let Some(template) = self.template_for(width, height) else {
+            return false;
+        };
+        for region in &template.regions {
+            let (x0, y0, x1, y1) = region.pixels(width, height);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    image.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+                }
+            }
+        }
+        true
+    }
+
+    // Redacted copy of a snapshot, written back as BMP
+    #[inline(always)]
+    pub fn redact_bmp(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut image = image::load_from_memory(data)?.to_rgba8();
+        if !self.mask(&mut image) {
+            bail!(
+                "no template for {}x{} images",
+                image.width(),
+                image.height()
+            );
+        }
+        let mut out = Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .write_to(&mut out, ImageOutputFormat::Bmp)?;
+        Ok(out.into_inner())
+    }
+
+    // Overwrite the regions in native pixel data, compressed transfer syntaxes are left alone
+    #[inline(always)]
+    pub fn redact_dicom(
+        &self,
+        object: &mut FileDicomObject<InMemDicomObject>,
+    ) -> Result<DicomRedaction> {
+        let Ok(pixel_data) = object.element(PIXEL_DATA) else {
+            return Ok(DicomRedaction::NoPixelData);
+        };
+        if pixel_data.value().fragments().is_some() {
+            return Ok(DicomRedaction::Skipped(
+                "encapsulated pixel data".to_string(),
+            ));
+        }
+        if object.meta().transfer_syntax().trim_end_matches('\0') == EXPLICIT_VR_BIG_ENDIAN {
+            return Ok(DicomRedaction::Skipped("big endian pixel data".to_string()));
+        }
+        let rows = element_u32(object, Tag(0x0028, 0x0010)).context("no Rows")?;
+        let columns = element_u32(object, Tag(0x0028, 0x0011)).context("no Columns")?;
+        let Some(template) = self.template_for(columns, rows) else {
+            return Ok(DicomRedaction::NoTemplate);
+        };
+        let samples = element_u32(object, Tag(0x0028, 0x0002)).unwrap_or(1) as usize;
+        let planar = element_u32(object, Tag(0x0028, 0x0006)).unwrap_or(0) == 1;
+        let frames = element_u32(object, Tag(0x0028, 0x0008)).unwrap_or(1) as usize;
+        let bits_allocated = element_u32(object, Tag(0x0028, 0x0100)).unwrap_or(8);
+        let bits_stored = element_u32(object, Tag(0x0028, 0x0101)).unwrap_or(bits_allocated);
+        let photometric = object
+            .element(Tag(0x0028, 0x0004))
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|p| p.trim().to_string())
+            .unwrap_or_else(|| "MONOCHROME2".to_string());
+        let black: u32 = match photometric.as_str() {
+            "MONOCHROME2" | "RGB" => 0,
+            // Inverted grey scale, the highest value is black
+            "MONOCHROME1" => ((1u64 << bits_stored.min(32)) - 1) as u32,
+            other => {
+                return Ok(DicomRedaction::Skipped(format!(
+                    "{} photometric interpretation",
+                    other
+                )))
+            }
+        };
+        if ![8, 16, 32].contains(&bits_allocated) {
+            return Ok(DicomRedaction::Skipped(format!(
+                "{} bits allocated",
+                bits_allocated
+            )));
+        }
+        let bytes_per_sample = bits_allocated as usize / 8;
+        let fill = &black.to_le_bytes()[..bytes_per_sample];
+        let vr = pixel_data.vr();
+        let mut data = pixel_data.to_bytes()?.into_owned();
+        let (rows, columns) = (rows as usize, columns as usize);
+        let frame_len = rows * columns * samples * bytes_per_sample;
+        if data.len() < frame_len * frames {
+            bail!(
+                "pixel data holds {} bytes, {} expected",
+                data.len(),
+                frame_len * frames
+            );
+        }
+        for frame in 0..frames {
+            for region in &template.regions {
+                let (x0, y0, x1, y1) = region.pixels(columns as u32, rows as u32);
+                for y in y0 as usize..y1 as usize {
+                    for x in x0 as usize..x1 as usize {
+                        for sample in 0..samples {
+                            let idx = if planar {
+                                sample * rows * columns + y * columns + x
+                            } else {
+                                (y * columns + x) * samples + sample
+                            };
+                            let offset = frame * frame_len + idx * bytes_per_sample;
+                            data[offset..offset + bytes_per_sample].copy_from_slice(fill);
+                        }
+                    }
+                }
+            }
+        }
+        object.put(DataElement::new(PIXEL_DATA, vr, PrimitiveValue::from(data)));
+        Ok(DicomRedaction::Redacted)
+    }
+
+    // Redact a DICOM file held in memory, fails when its pixels cannot be blacked out
+    #[inline(always)]
+    pub fn redact_dicom_bytes(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut object = OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Auto)
+            .from_reader(Cursor::new(data))?;
+        match self.redact_dicom(&mut object)? {
+            DicomRedaction::Redacted => {
+                let mut out = Vec::new();
+                object.write_all(&mut out)?;
+                Ok(out)
+            }
+            DicomRedaction::NoPixelData => Ok(data.to_vec()),
+            DicomRedaction::NoTemplate => bail!("no template for the image size"),
+            DicomRedaction::Skipped(reason) => bail!("{}", reason),
+        }
+    }
+}
+
+#[inline(always)]
+pub fn save_templates(path: &Path, templates: &[LayoutTemplate]) -> Result<()> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    wtr.write_record([
+        "template",
+        "image_width",
+        "image_height",
+        "left_pct",
+        "top_pct",
+        "width_pct",
+        "height_pct",
+    ])?;
+    for template in templates {
+        let (width, height) = template
+            .size
+            .map(|(w, h)| (w.to_string(), h.to_string()))
+            .unwrap_or_default();
+        for region in &template.regions {
+            wtr.write_record([
+                template.name.clone(),
+                width.clone(),
+                height.clone(),
+                region.left.to_string(),
+                region.top.to_string(),
+                region.width.to_string(),
+                region.height.to_string(),
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// One row per region, rows of the same template are grouped by name
+#[inline(always)]
+pub fn load_templates(path: &Path) -> Result<Vec<LayoutTemplate>> {
+    let mut templates: Vec<LayoutTemplate> = Vec::new();
+    for record in csv::Reader::from_path(path)?.records() {
+        let record = record?;
+        let text = |idx: usize| -> Result<&str> {
+            Ok(record.get(idx).context("expected seven columns")?.trim())
+        };
+        let value = |idx: usize| -> Result<f32> {
+            let text = text(idx)?;
+            text.parse()
+                .with_context(|| format!("{} is not a number", text))
+        };
+        let size = match (text(1)?, text(2)?) {
+            ("", "") => None,
+            (width, height) => Some((
+                width
+                    .parse()
+                    .with_context(|| format!("{} is not an image width", width))?,
+                height
+                    .parse()
+                    .with_context(|| format!("{} is not an image height", height))?,
+            )),
+        };
+        let region = Region {
+            left: value(3)?,
+            top: value(4)?,
+            width: value(5)?,
+            height: value(6)?,
+        };
+        let name = text(0)?;
+        match templates.iter_mut().find(|template| template.name == name) {
+            Some(template) => template.regions.push(region),
+            None => templates.push(LayoutTemplate {
+                name: name.to_string(),
+                size,
+                regions: vec![region],
+            }),
+        }
+    }
+    if templates.is_empty() {
+        bail!("the file has no redaction regions");
+    }
+    Ok(templates)
+}
+
+pub struct RedactionPanel {
+    pub enabled: bool,
+    pub templates: Vec<LayoutTemplate>,
+    selected: usize,
+    preview_index: usize,
+    // Masked snapshot, cleared whenever a region changes
+    preview: Option<TextureHandle>,
+}
+
+impl Default for RedactionPanel {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            templates: vec![LayoutTemplate::default()],
+            selected: 0,
+            preview_index: 0,
+            preview: None,
+        }
+    }
+}
+
+impl RedactionPanel {
+    // The regions to apply to exports, None when redaction is off
+    #[inline(always)]
+    pub fn active(&self) -> Option<Redaction> {
+        self.enabled.then(|| Redaction {
+            templates: self.templates.clone(),
+        })
+    }
+
+    #[inline(always)]
+    fn show_template(&mut self, ui: &mut egui::Ui, preview_size: Option<(u32, u32)>) -> bool {
+        let mut changed = false;
+        let Some(template) = self.templates.get_mut(self.selected) else {
+            return false;
+        };
+        ui.horizontal(|ui| {
+            ui.label("Name:");
+            ui.text_edit_singleline(&mut template.name);
+        });
+        ui.horizontal(|ui| {
+            let mut any_size = template.size.is_none();
+            if ui.checkbox(&mut any_size, "Any image size").changed() {
+                template.size = if any_size {
+                    None
+                } else {
+                    Some(preview_size.unwrap_or((1280, 1024)))
+                };
+                changed = true;
+            }
+            if let Some((width, height)) = &mut template.size {
+                changed |= ui
+                    .add(egui::DragValue::new(width).clamp_range(1..=8192))
+                    .changed();
+                ui.label("x");
+                changed |= ui
+                    .add(egui::DragValue::new(height).clamp_range(1..=8192))
+                    .changed();
+                if let Some(size) = preview_size {
+                    if ui.button("Use preview size").clicked() {
+                        template.size = Some(size);
+                        changed = true;
+                    }
+                }
+            }
+        });
+        let mut remove = None;
+        Grid::new("redaction_regions").striped(true).show(ui, |ui| {
+            ui.strong("Left %");
+            ui.strong("Top %");
+            ui.strong("Width %");
+            ui.strong("Height %");
+            ui.end_row();
+            for (idx, region) in template.regions.iter_mut().enumerate() {
+                for value in [
+                    &mut region.left,
+                    &mut region.top,
+                    &mut region.width,
+                    &mut region.height,
+                ] {
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(value)
+                                .clamp_range(0.0..=100.0)
+                                .speed(0.1),
+                        )
+                        .changed();
+                }
+                if ui.button("Remove").clicked() {
+                    remove = Some(idx);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(idx) = remove {
+            template.regions.remove(idx);
+            changed = true;
+        }
+        if ui.button("Add region").clicked() {
+            template.regions.push(Region {
+                left: 0.0,
+                top: 0.0,
+                width: 20.0,
+                height: 5.0,
+            });
+            changed = true;
+        }
+        changed
+    }
+
+    // Returns an error message when loading or saving the templates failed
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui, snapshots: &[Snapshot]) -> Option<String> {
+        let mut error = None;
+        ui.checkbox(
+            &mut self.enabled,
+            "Black out these regions in exported snapshots and anonymized DICOM",
+        );
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("redaction_template")
+                .selected_text(
+                    self.templates
+                        .get(self.selected)
+                        .map(|template| template.name.as_str())
+                        .unwrap_or("no template"),
+                )
+                .show_ui(ui, |ui| {
+                    for (idx, template) in self.templates.iter().enumerate() {
+                        changed |= ui
+                            .selectable_value(&mut self.selected, idx, &template.name)
+                            .changed();
+                    }
+                });
+            if ui.button("New template").clicked() {
+                self.templates.push(LayoutTemplate {
+                    name: format!("Layout {}", self.templates.len() + 1),
+                    ..LayoutTemplate::default()
+                });
+                self.selected = self.templates.len() - 1;
+                changed = true;
+            }
+            if ui
+                .add_enabled(self.templates.len() > 1, egui::Button::new("Delete"))
+                .clicked()
+            {
+                self.templates.remove(self.selected);
+                self.selected = self.selected.min(self.templates.len() - 1);
+                changed = true;
+            }
+            if ui.button("Load templates").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("CSV", &["csv"])
+                    .pick_file()
+                {
+                    match load_templates(&path) {
+                        Ok(templates) => {
+                            self.templates = templates;
+                            self.selected = 0;
+                            changed = true;
+                        }
+                        Err(e) => error = Some(format!("Could not load templates: {:#}", e)),
+                    }
+                }
+            }
+            if ui.button("Save templates").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("CSV", &["csv"])
+                    .set_file_name("redaction_templates.csv")
+                    .save_file()
+                {
+                    if let Err(e) = save_templates(&path, &self.templates) {
+                        error = Some(format!("Could not save templates: {:#}", e));
+                    }
+                }
+            }
+        });
+
+        let preview_size = self
+            .preview
+            .as_ref()
+            .map(|texture| (texture.size()[0] as u32, texture.size()[1] as u32));
+        changed |= self.show_template(ui, preview_size);
+        if changed {
+            self.preview = None;
+        }
+
+        if snapshots.is_empty() {
+            ui.label(RichText::new("Load a ZIP with snapshots to preview the mask").weak());
+            return error;
+        }
+        if ui
+            .add(
+                egui::Slider::new(&mut self.preview_index, 0..=snapshots.len() - 1)
+                    .text("Preview snapshot"),
+            )
+            .changed()
+        {
+            self.preview = None;
+        }
+        let Some(snapshot) = snapshots.get(self.preview_index) else {
+            return error;
+        };
+        if self.preview.is_none() {
+            let redaction = Redaction {
+                templates: self.templates.clone(),
+            };
+            self.preview = image::load_from_memory(&snapshot.data).ok().map(|image| {
+                let mut image = image.to_rgba8();
+                if !redaction.mask(&mut image) {
+                    error = Some(format!(
+                        "No template matches {}x{} images",
+                        image.width(),
+                        image.height()
+                    ));
+                }
+                let size = [image.width() as usize, image.height() as usize];
+                let image =
+                    ColorImage::from_rgba_unmultiplied(size, image.as_flat_samples().as_slice());
+                ui.ctx()
+                    .load_texture("redaction_preview", image, egui::TextureOptions::default())
+            });
+        }
+        match &self.preview {
+            Some(texture) => {
+                ui.label(&snapshot.archive_path);
+                ui.add(egui::Image::new(texture).max_width(ui.available_width()));
+            }
+            None => {
+                ui.colored_label(Color32::RED, "Unreadable image");
+            }
+        }
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::VR;
+    use dicom::object::FileMetaTableBuilder;
+
+    const HEADER: Region = Region {
+        left: 0.0,
+        top: 0.0,
+        width: 50.0,
+        height: 50.0,
+    };
+
+    #[inline(always)]
+    fn with_templates(templates: Vec<(Option<(u32, u32)>, Region)>) -> Redaction {
+        Redaction {
+            templates: templates
+                .into_iter()
+                .enumerate()
+                .map(|(idx, (size, region))| LayoutTemplate {
+                    name: format!("Layout {}", idx + 1),
+                    size,
+                    regions: vec![region],
+                })
+                .collect(),
+        }
+    }
+
+    #[inline(always)]
+    fn dicom(
+        (columns, rows): (u16, u16),
+        samples: u16,
+        planar: bool,
+        bits: (u16, u16),
+        photometric: &str,
+        frames: &str,
+        data: Vec<u8>,
+    ) -> FileDicomObject<InMemDicomObject> {
+        let us = |group, element, value: u16| {
+            DataElement::new(Tag(group, element), VR::US, PrimitiveValue::from(value))
+        };
+        let pixel_vr = if bits.0 == 8 { VR::OB } else { VR::OW };
+        InMemDicomObject::from_element_iter([
+            us(0x0028, 0x0002, samples),
+            DataElement::new(
+                Tag(0x0028, 0x0004),
+                VR::CS,
+                PrimitiveValue::from(photometric),
+            ),
+            us(0x0028, 0x0006, planar as u16),
+            DataElement::new(Tag(0x0028, 0x0008), VR::IS, PrimitiveValue::from(frames)),
+            us(0x0028, 0x0010, rows),
+            us(0x0028, 0x0011, columns),
+            us(0x0028, 0x0100, bits.0),
+            us(0x0028, 0x0101, bits.1),
+            DataElement::new(PIXEL_DATA, pixel_vr, PrimitiveValue::from(data)),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .transfer_syntax("1.2.840.10008.1.2.1")
+                .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+                .media_storage_sop_instance_uid("1.2.3.4"),
+        )
+        .unwrap()
+    }
+
+    #[inline(always)]
+    fn pixel_bytes(object: &FileDicomObject<InMemDicomObject>) -> Vec<u8> {
+        object
+            .element(PIXEL_DATA)
+            .unwrap()
+            .to_bytes()
+            .unwrap()
+            .into_owned()
+    }
+
+    #[test]
+    fn region_pixels_are_scaled_and_clipped() {
+        let region = Region {
+            left: 10.0,
+            top: 20.0,
+            width: 50.0,
+            height: 200.0,
+        };
+        assert_eq!(region.pixels(200, 100), (20, 20, 120, 100));
+        let outside = Region {
+            left: -10.0,
+            top: 90.0,
+            width: 5.0,
+            height: 5.0,
+        };
+        assert_eq!(outside.pixels(100, 100), (0, 90, 0, 95));
+    }
+
+    #[test]
+    fn exact_size_templates_win_over_any_size() {
+        let redaction = with_templates(vec![(None, HEADER), (Some((640, 480)), HEADER)]);
+        assert_eq!(redaction.template_for(640, 480).unwrap().name, "Layout 2");
+        assert_eq!(redaction.template_for(800, 600).unwrap().name, "Layout 1");
+        let sized_only = with_templates(vec![(Some((640, 480)), HEADER)]);
+        assert!(sized_only.template_for(800, 600).is_none());
+    }
+
+    #[test]
+    fn mask_blacks_out_the_regions() {
+        let redaction = with_templates(vec![(Some((10, 10)), HEADER)]);
+        let mut image = RgbaImage::from_pixel(10, 10, Rgba([255, 255, 255, 255]));
+        assert!(redaction.mask(&mut image));
+        assert_eq!(image.get_pixel(4, 4), &Rgba([0, 0, 0, 255]));
+        assert_eq!(image.get_pixel(5, 4), &Rgba([255, 255, 255, 255]));
+        assert_eq!(image.get_pixel(4, 5), &Rgba([255, 255, 255, 255]));
+        let mut other = RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255]));
+        assert!(!redaction.mask(&mut other));
+        assert!(other.pixels().all(|pixel| pixel.0 == [255; 4]));
+    }
+
+    #[test]
+    fn interleaved_and_planar_rgb_offsets() {
+        let redaction = with_templates(vec![(None, HEADER)]);
+        // 4x2 RGB, the region covers pixels (0, 0) and (1, 0)
+        let mut interleaved = dicom((4, 2), 3, false, (8, 8), "RGB", "1", vec![255; 24]);
+        assert!(matches!(
+            redaction.redact_dicom(&mut interleaved).unwrap(),
+            DicomRedaction::Redacted
+        ));
+        let mut expected = vec![255; 24];
+        expected[..6].fill(0);
+        assert_eq!(pixel_bytes(&interleaved), expected);
+
+        let mut planar = dicom((4, 2), 3, true, (8, 8), "RGB", "1", vec![255; 24]);
+        redaction.redact_dicom(&mut planar).unwrap();
+        let mut expected = vec![255; 24];
+        for plane in 0..3 {
+            expected[plane * 8..plane * 8 + 2].fill(0);
+        }
+        assert_eq!(pixel_bytes(&planar), expected);
+    }
+
+    #[test]
+    fn monochrome1_is_filled_with_the_highest_value_in_every_frame() {
+        let redaction = with_templates(vec![(
+            None,
+            Region {
+                left: 50.0,
+                top: 0.0,
+                width: 50.0,
+                height: 100.0,
+            },
+        )]);
+        // Two 2x2 frames of 16 bit samples with 12 bits stored, the right column is redacted
+        let mut object = dicom((2, 2), 1, false, (16, 12), "MONOCHROME1", "2", vec![0; 16]);
+        redaction.redact_dicom(&mut object).unwrap();
+        let white = [0, 0];
+        let black = [0xFF, 0x0F];
+        let frame = [white, black, white, black].concat();
+        assert_eq!(pixel_bytes(&object), [frame.clone(), frame].concat());
+    }
+
+    #[test]
+    fn unredactable_dicom_is_reported() {
+        let sized = with_templates(vec![(Some((640, 480)), HEADER)]);
+        let mut object = dicom((4, 2), 1, false, (8, 8), "MONOCHROME2", "1", vec![7; 8]);
+        assert!(matches!(
+            sized.redact_dicom(&mut object).unwrap(),
+            DicomRedaction::NoTemplate
+        ));
+        assert_eq!(pixel_bytes(&object), vec![7; 8]);
+
+        let any = with_templates(vec![(None, HEADER)]);
+        let mut palette = dicom((4, 2), 1, false, (8, 8), "PALETTE COLOR", "1", vec![7; 8]);
+        assert!(matches!(
+            any.redact_dicom(&mut palette).unwrap(),
+            DicomRedaction::Skipped(_)
+        ));
+        let mut short = dicom((4, 2), 1, false, (8, 8), "MONOCHROME2", "2", vec![7; 8]);
+        assert!(any.redact_dicom(&mut short).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use crate::summary;
+use chrono::NaiveDateTime;
+use eframe::egui::{self, Color32, Grid, RichText};
+use polars::prelude::*;
+use std::collections::HashMap;
+
+pub const STOPPED_COLUMN: &str = "Stopped";
+pub const STOP_REASON_COLUMN: &str = "Stop reason";
+pub const ACOUSTIC_COLUMN: &str = "Acoustic mode";
+// Name of the acoustic mode column in the console export
+pub const RAW_ACOUSTIC_COLUMN: &str =
+    "Acoustic mode(1-disabled/2-Stop sonication/3-Modulated power)";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AcousticMode {
+    Disabled,
+    StopSonication,
+    ModulatedPower,
+}
+
+impl AcousticMode {
+    // Accepts the console codes 1-3 as well as the labels written by label()
+    #[inline(always)]
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_lowercase();
+        if let Ok(code) = value.parse::<f64>() {
+            if code.fract() != 0.0 {
+                return None;
+            }
+            return match code as i64 {
+                1 => Some(AcousticMode::Disabled),
+                2 => Some(AcousticMode::StopSonication),
+                3 => Some(AcousticMode::ModulatedPower),
+                _ => None,
+            };
+        }
+        if value.contains("disab") {
+            Some(AcousticMode::Disabled)
+        } else if value.contains("stop") {
+            Some(AcousticMode::StopSonication)
+        } else if value.contains("modul") {
+            Some(AcousticMode::ModulatedPower)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn label(self) -> &'static str {
+        match self {
+            AcousticMode::Disabled => "Disabled",
+            AcousticMode::StopSonication => "Stop sonication",
+            AcousticMode::ModulatedPower => "Modulated power",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Stopped {
+    No,
+    // Free text the console wrote instead of a flag, if any
+    Yes(Option<String>),
+}
+
+impl Stopped {
+    #[inline(always)]
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim();
+        match value.to_lowercase().as_str() {
+            "" | "0" | "0.0" | "no" | "false" | "n" => Stopped::No,
+            "1" | "1.0" | "yes" | "true" | "y" => Stopped::Yes(None),
+            _ => Stopped::Yes(Some(value.to_string())),
+        }
+    }
+
+    #[inline(always)]
+    pub fn reason(&self, mode: Option<AcousticMode>) -> Option<String> {
+        match (self, mode) {
+            (Stopped::No, _) => None,
+            (Stopped::Yes(Some(reason)), _) => Some(reason.clone()),
+            (Stopped::Yes(None), Some(AcousticMode::StopSonication)) => {
+                Some("Acoustic feedback stop".to_string())
+            }
+            (Stopped::Yes(None), _) => Some("Stopped".to_string()),
+        }
+    }
+}
+
+#[inline(always)]
+fn text_values(df: &DataFrame, name: &str) -> Option<Vec<Option<String>>> {
+    let series = df.column(name).ok()?.cast(&DataType::Utf8).ok()?;
+    let values = series
+        .utf8()
+        .ok()?
+        .into_iter()
+        .map(|v| v.map(str::to_string))
+        .collect();
+    Some(values)
+}
+
+// Replace the raw console columns with a flag, a reason and a readable acoustic mode
+#[inline(always)]
+pub fn normalize_columns(df: &mut DataFrame) -> PolarsResult<()> {
+    let modes: Option<Vec<Option<AcousticMode>>> = text_values(df, RAW_ACOUSTIC_COLUMN).map(|v| {
+        v.iter()
+            .map(|value| value.as_deref().and_then(AcousticMode::parse))
+            .collect()
+    });
+    if let Some(modes) = &modes {
+        let labels: Utf8Chunked = modes
+            .iter()
+            .map(|mode| mode.map(AcousticMode::label))
+            .collect();
+        let _ = df.drop_in_place(RAW_ACOUSTIC_COLUMN)?;
+        df.with_column(labels.into_series().with_name(ACOUSTIC_COLUMN))?;
+    }
+    if let Some(values) = text_values(df, STOPPED_COLUMN) {
+        let stopped: Vec<Stopped> = values
+            .iter()
+            .map(|value| Stopped::parse(value.as_deref().unwrap_or_default()))
+            .collect();
+        let flags: BooleanChunked = stopped
+            .iter()
+            .map(|stopped| Some(*stopped != Stopped::No))
+            .collect();
+        let reasons: Utf8Chunked = stopped
+            .iter()
+            .enumerate()
+            .map(|(row, stopped)| stopped.reason(modes.as_ref().and_then(|modes| modes[row])))
+            .collect();
+        df.with_column(flags.into_series().with_name(STOPPED_COLUMN))?;
+        df.with_column(reasons.into_series().with_name(STOP_REASON_COLUMN))?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct StoppedSonication {
+    pub row: usize,
+    pub time: Option<NaiveDateTime>,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SafetyReport {
+    pub stopped: Vec<StoppedSonication>,
+    pub modulated: Vec<usize>,
+    pub disabled: usize,
+    // False for exports without the stop and acoustic mode columns
+    pub available: bool,
+}
+
+impl SafetyReport {
+    #[inline(always)]
+    pub fn from_df(df: &DataFrame) -> Self {
+        let mut report = SafetyReport::default();
+        let times = summary::sonication_times(df);
+        if let Some(reasons) = text_values(df, STOP_REASON_COLUMN) {
+            report.available = true;
+            report.stopped = reasons
+                .into_iter()
+                .enumerate()
+                .filter_map(|(row, reason)| {
+                    Some(StoppedSonication {
+                        row,
+                        time: times.get(row).copied().flatten(),
+                        reason: reason?,
+                    })
+                })
+                .collect();
+        }
+        if let Some(modes) = text_values(df, ACOUSTIC_COLUMN) {
+            report.available = true;
+            for (row, mode) in modes.iter().enumerate() {
+                match mode.as_deref().and_then(AcousticMode::parse) {
+                    Some(AcousticMode::ModulatedPower) => report.modulated.push(row),
+                    Some(AcousticMode::Disabled) => report.disabled += 1,
+                    _ => {}
+                }
+            }
+        }
+        report
+    }
+}
+
+pub struct SafetyReview {
+    // A treatment with more stopped sonications than this is flagged
+    pub max_stops: usize,
+    pub report: SafetyReport,
+}
+
+impl Default for SafetyReview {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            max_stops: 2,
+            report: SafetyReport::default(),
+        }
+    }
+}
+
+impl SafetyReview {
+    #[inline(always)]
+    pub fn analyse(&mut self, df: &DataFrame) {
+        self.report = SafetyReport::from_df(df);
+    }
+
+    #[inline(always)]
+    pub fn flagged(&self) -> bool {
+        self.report.stopped.len() > self.max_stops
+    }
+
+    #[inline(always)]
+    pub fn highlights(&self) -> HashMap<usize, (Color32, String)> {
+        let mut rows: HashMap<usize, (Color32, String)> = self
+            .report
+            .modulated
+            .iter()
+            .map(|&row| {
+                (
+                    row,
+                    (
+                        Color32::from_rgb(230, 140, 0),
+                        "Modulated power".to_string(),
+                    ),
+                )
+            })
+            .collect();
+        for stopped in &self.report.stopped {
+            rows.insert(
+                stopped.row,
+                (Color32::RED, format!("Stopped: {}", stopped.reason)),
+            );
+        }
+        rows
+    }
+
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui, selected_row: &mut Option<usize>) {
+        let report = &self.report;
+        if !report.available {
+            ui.label("This export has no stop or acoustic mode columns");
+            return;
+        }
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "{} stopped sonications, {} modulated-power events, acoustic feedback disabled for {}",
+                report.stopped.len(),
+                report.modulated.len(),
+                report.disabled
+            ));
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut self.max_stops)
+                    .clamp_range(0..=100)
+                    .prefix("Flag treatments with more than ")
+                    .suffix(" stops"),
+            );
+        });
+        if self.flagged() {
+            ui.colored_label(
+                Color32::RED,
+                RichText::new(format!(
+                    "Treatment flagged: {} stopped sonications exceed the limit of {}",
+                    self.report.stopped.len(),
+                    self.max_stops
+                ))
+                .strong(),
+            );
+        }
+        if self.report.stopped.is_empty() {
+            return;
+        }
+        Grid::new("stopped_sonications")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.strong("Sonication");
+                ui.strong("Time (UTC)");
+                ui.strong("Reason");
+                ui.end_row();
+                for stopped in &self.report.stopped {
+                    if ui
+                        .selectable_label(
+                            *selected_row == Some(stopped.row),
+                            format!("{}", stopped.row + 1),
+                        )
+                        .clicked()
+                    {
+                        *selected_row = Some(stopped.row);
+                    }
+                    ui.label(
+                        stopped
+                            .time
+                            .map(|time| time.format("%H:%M:%S").to_string())
+                            .unwrap_or_default(),
+                    );
+                    ui.label(&stopped.reason);
+                    ui.end_row();
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acoustic_modes_from_codes_and_labels() {
+        assert_eq!(AcousticMode::parse("1"), Some(AcousticMode::Disabled));
+        assert_eq!(
+            AcousticMode::parse(" 2.0 "),
+            Some(AcousticMode::StopSonication)
+        );
+        assert_eq!(AcousticMode::parse("3"), Some(AcousticMode::ModulatedPower));
+        assert_eq!(AcousticMode::parse("2.5"), None);
+        assert_eq!(AcousticMode::parse("4"), None);
+        for mode in [
+            AcousticMode::Disabled,
+            AcousticMode::StopSonication,
+            AcousticMode::ModulatedPower,
+        ] {
+            assert_eq!(AcousticMode::parse(mode.label()), Some(mode));
+        }
+        assert_eq!(
+            AcousticMode::parse("MODULATED"),
+            Some(AcousticMode::ModulatedPower)
+        );
+        assert_eq!(AcousticMode::parse("unknown"), None);
+    }
+
+    #[test]
+    fn stop_flags_and_free_text() {
+        for value in ["", "0", "0.0", "No", "false", " n "] {
+            assert_eq!(Stopped::parse(value), Stopped::No, "{:?}", value);
+        }
+        for value in ["1", "1.0", "YES", "true", "y"] {
+            assert_eq!(Stopped::parse(value), Stopped::Yes(None), "{:?}", value);
+        }
+        assert_eq!(
+            Stopped::parse(" Temperature limit "),
+            Stopped::Yes(Some("Temperature limit".to_string()))
+        );
+    }
+
+    #[test]
+    fn stop_reasons() {
+        let feedback = Some(AcousticMode::StopSonication);
+        assert_eq!(Stopped::No.reason(feedback), None);
+        assert_eq!(
+            Stopped::Yes(None).reason(feedback).as_deref(),
+            Some("Acoustic feedback stop")
+        );
+        assert_eq!(
+            Stopped::Yes(None)
+                .reason(Some(AcousticMode::ModulatedPower))
+                .as_deref(),
+            Some("Stopped")
+        );
+        assert_eq!(Stopped::Yes(None).reason(None).as_deref(), Some("Stopped"));
+        // Text written by the console wins over the acoustic mode
+        assert_eq!(
+            Stopped::Yes(Some("Patient button".to_string()))
+                .reason(feedback)
+                .as_deref(),
+            Some("Patient button")
+        );
+    }
+
+    #[test]
+    fn raw_columns_are_replaced_row_by_row() {
+        let mut df = df!(
+            "Energy[J]" => &[100.0, 200.0, 300.0, 400.0],
+            RAW_ACOUSTIC_COLUMN => &[Some(2i64), Some(2), None, Some(3)],
+            STOPPED_COLUMN => &["1", "0", "Patient button", "yes"]
+        )
+        .unwrap();
+        normalize_columns(&mut df).unwrap();
+        assert!(df.column(RAW_ACOUSTIC_COLUMN).is_err());
+        let text = |name: &str| -> Vec<Option<String>> { text_values(&df, name).unwrap() };
+        let some = |values: &[&str]| -> Vec<Option<String>> {
+            values
+                .iter()
+                .map(|value| (!value.is_empty()).then(|| value.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            text(ACOUSTIC_COLUMN),
+            some(&["Stop sonication", "Stop sonication", "", "Modulated power"])
+        );
+        let stopped: Vec<Option<bool>> = df
+            .column(STOPPED_COLUMN)
+            .unwrap()
+            .bool()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            stopped,
+            vec![Some(true), Some(false), Some(true), Some(true)]
+        );
+        assert_eq!(
+            text(STOP_REASON_COLUMN),
+            some(&["Acoustic feedback stop", "", "Patient button", "Stopped"])
+        );
+        assert_eq!(
+            summary::float_values(&df, "Energy[J]").unwrap(),
+            vec![Some(100.0), Some(200.0), Some(300.0), Some(400.0)]
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use crate::summary;
+use anyhow::{bail, Context, Result};
+use eframe::egui::{self, RichText};
+use polars::prelude::*;
+use splines::{Interpolation, Key, Spline};
+use std::{cmp::Ordering, path::Path};
+
+pub const FOCAL_COLUMNS: [&str; 3] = ["Focal RAS-R", "Focal RAS-A", "Focal RAS-S"];
+pub const DISTANCE_COLUMN: &str = "Steering distance [mm]";
+pub const EFFICIENCY_COLUMN: &str = "Predicted efficiency [%]";
+pub const PREDICTED_COLUMN: &str = "Predicted adj. energy [J]";
+pub const DEVIATION_COLUMN: &str = "Act. - predicted energy [J]";
+
+//Define distance function from Natural Focus
+#[inline(always)]
+pub fn distance(vec1: &[f64], vec2: &[f64]) -> f64 {
+    vec1.iter()
+        .zip(vec2.iter())
+        .map(|(&a, &b)| (a - b).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+// Efficiency of the transducer in % over the steering distance in mm
+#[derive(Clone, Debug, PartialEq)]
+pub struct EfficiencyProfile {
+    pub name: String,
+    // Knots (distance, efficiency) sorted by distance
+    pub knots: Vec<(f64, f64)>,
+}
+
+impl Default for EfficiencyProfile {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            name: "Default".to_string(),
+            knots: vec![(0.0, 100.0), (30.0, 90.0), (55.0, 70.0), (75.0, 50.0)],
+        }
+    }
+}
+
+impl EfficiencyProfile {
+    #[inline(always)]
+    pub fn sample(&self, distance: f64) -> f64 {
+        let keys = self
+            .knots
+            .iter()
+            .map(|&(d, e)| Key::new(d, e, Interpolation::Linear))
+            .collect();
+        let spline = Spline::from_vec(keys);
+        spline.clamped_sample(distance).unwrap_or(50.0)
+    }
+
+    // Two columns, distance in mm and efficiency in %, the name comes from the file name
+    #[inline(always)]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut wtr = csv::Writer::from_path(path)?;
+        wtr.write_record(["distance_mm", "efficiency_pct"])?;
+        for (distance, efficiency) in &self.knots {
+            wtr.write_record([distance.to_string(), efficiency.to_string()])?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut knots = Vec::new();
+        for record in csv::Reader::from_path(path)?.records() {
+            let record = record?;
+            let value = |idx: usize| -> Result<f64> {
+                let text = record.get(idx).context("expected two columns")?;
+                text.trim()
+                    .parse()
+                    .with_context(|| format!("{} is not a number", text))
+            };
+            knots.push((value(0)?, value(1)?));
+        }
+        if knots.len() < 2 {
+            bail!("a profile needs at least two knots");
+        }
+        knots.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        Ok(Self {
+            name: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            knots,
+        })
+    }
+}
+
+#[inline(always)]
+pub fn efficiency(profile: &EfficiencyProfile, distance: f64) -> f64 {
+    profile.sample(distance)
+}
+
+// Steering distance of every sonication, None when a focal coordinate is missing
+#[inline(always)]
+pub fn steering_distances(df: &DataFrame, natural_focus: &[f64; 3]) -> Option<Vec<Option<f64>>> {
+    let [r, a, s] = FOCAL_COLUMNS.map(|name| summary::float_values(df, name));
+    let (r, a, s) = (r?, a?, s?);
+    let distances = (0..df.height())
+        .map(|row| Some(distance(natural_focus, &[r[row]?, a[row]?, s[row]?])))
+        .collect();
+    Some(distances)
+}
+
+// Add the steering distance, the predicted efficiency and how the delivered energy compares
+#[inline(always)]
+pub fn add_steering_columns(
+    df: &mut DataFrame,
+    natural_focus: &[f64; 3],
+    profile: &EfficiencyProfile,
+) -> PolarsResult<()> {
+    let Some(distances) = steering_distances(df, natural_focus) else {
+        return Ok(());
+    };
+    let efficiencies: Vec<Option<f64>> = distances
+        .iter()
+        .map(|d| d.map(|d| efficiency(profile, d)))
+        .collect();
+    df.with_column(Series::new(DISTANCE_COLUMN, &distances))?;
+    df.with_column(Series::new(EFFICIENCY_COLUMN, &efficiencies))?;
+    if let (Some(planned), Some(actual)) = (
+        summary::float_values(df, "Energy[J]"),
+        summary::float_values(df, "Act. Energy[J]"),
+    ) {
+        let predicted: Vec<Option<f64>> = planned
+            .iter()
+            .zip(&efficiencies)
+            .map(|(energy, efficiency)| Some((*energy)? * (*efficiency)? / 100.0))
+            .collect();
+        let deviation: Vec<Option<f64>> = actual
+            .iter()
+            .zip(&predicted)
+            .map(|(actual, predicted)| Some((*actual)? - (*predicted)?))
+            .collect();
+        df.with_column(Series::new(PREDICTED_COLUMN, &predicted))?;
+        df.with_column(Series::new(DEVIATION_COLUMN, &deviation))?;
+    }
+    Ok(())
+}
+
+// Natural focus of the loaded treatment and the comparison of predicted and delivered energy
+#[derive(Default)]
+pub struct SteeringPanel {
+    pub natural_focus: [f64; 3],
+    // Shared with the calculator
+    pub profile: EfficiencyProfile,
+}
+
+impl SteeringPanel {
+    // Returns true when the natural focus changed and the summary needs to be recomputed
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui, df: &DataFrame, calculator_focus: &[f64]) -> bool {
+        let before = self.natural_focus;
+        ui.horizontal(|ui| {
+            ui.label("Natural focus R:");
+            ui.add(egui::DragValue::new(&mut self.natural_focus[0]).speed(0.1));
+            ui.label("A:");
+            ui.add(egui::DragValue::new(&mut self.natural_focus[1]).speed(0.1));
+            ui.label("S:");
+            ui.add(egui::DragValue::new(&mut self.natural_focus[2]).speed(0.1));
+            if ui.button("From calculator").clicked() {
+                for (focus, calculator) in self.natural_focus.iter_mut().zip(calculator_focus) {
+                    *focus = *calculator;
+                }
+            }
+        });
+        ui.label(format!("Efficiency profile: {}", self.profile.name));
+        if df.column(DISTANCE_COLUMN).is_err() {
+            ui.label("This export has no focal RAS columns");
+            return before != self.natural_focus;
+        }
+        let mean = |name: &str| {
+            let values: Vec<f64> = summary::float_values(df, name)
+                .unwrap_or_default()
+                .into_iter()
+                .flatten()
+                .collect();
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        };
+        let format = |value: Option<f64>| value.map(|v| format!("{:.2}", v)).unwrap_or_default();
+        ui.label(format!(
+            "Mean steering distance {} mm, mean predicted efficiency {} %",
+            format(mean(DISTANCE_COLUMN)),
+            format(mean(EFFICIENCY_COLUMN))
+        ));
+        if let (Some(predicted), Some(actual)) = (mean(PREDICTED_COLUMN), mean("Act. Energy[J]")) {
+            // No relative deviation when nothing was predicted, e.g. planned energies of zero
+            let relative = if predicted != 0.0 {
+                format!(" ({:+.1} %)", (actual / predicted - 1.0) * 100.0)
+            } else {
+                String::new()
+            };
+            ui.label(
+                RichText::new(format!(
+                    "Mean predicted adjusted energy {:.2} J, mean delivered {:.2} J{}",
+                    predicted, actual, relative
+                ))
+                .strong(),
+            );
+        }
+        before != self.natural_focus
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn treatment() -> DataFrame {
+        df!(
+            "Focal RAS-R" => &[Some(0.0), Some(30.0), None],
+            "Focal RAS-A" => &[Some(0.0), Some(0.0), Some(1.0)],
+            "Focal RAS-S" => &[Some(0.0), Some(40.0), Some(2.0)],
+            "Energy[J]" => &[Some(1000.0), Some(2000.0), Some(1000.0)],
+            "Act. Energy[J]" => &[Some(950.0), None, Some(1000.0)]
+        )
+        .unwrap()
+    }
+
+    #[inline(always)]
+    fn profile_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ejs-steering-{}-{}.csv", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn distances_are_none_without_a_focal_coordinate() {
+        let distances = steering_distances(&treatment(), &[0.0, 0.0, 0.0]).unwrap();
+        assert_eq!(distances, vec![Some(0.0), Some(50.0), None]);
+        let df = treatment().drop("Focal RAS-S").unwrap();
+        assert!(steering_distances(&df, &[0.0, 0.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn predicted_energy_is_scaled_by_the_efficiency() {
+        let mut df = treatment();
+        add_steering_columns(&mut df, &[0.0, 0.0, 0.0], &EfficiencyProfile::default()).unwrap();
+        let efficiency = summary::float_values(&df, EFFICIENCY_COLUMN).unwrap();
+        assert_eq!(efficiency[0], Some(100.0));
+        // 50 mm lies between the 30 mm and 55 mm knots
+        assert!((efficiency[1].unwrap() - 74.0).abs() < 1e-9);
+        assert_eq!(efficiency[2], None);
+        let predicted = summary::float_values(&df, PREDICTED_COLUMN).unwrap();
+        assert_eq!(predicted[0], Some(1000.0));
+        assert!((predicted[1].unwrap() - 1480.0).abs() < 1e-9);
+        assert_eq!(predicted[2], None);
+        let deviation = summary::float_values(&df, DEVIATION_COLUMN).unwrap();
+        assert_eq!(deviation, vec![Some(-50.0), None, None]);
+    }
+
+    #[test]
+    fn no_columns_without_focal_coordinates() {
+        let mut df = treatment().drop("Focal RAS-R").unwrap();
+        add_steering_columns(&mut df, &[0.0, 0.0, 0.0], &EfficiencyProfile::default()).unwrap();
+        assert!(df.column(DISTANCE_COLUMN).is_err());
+        assert!(df.column(PREDICTED_COLUMN).is_err());
+    }
+
+    #[test]
+    fn load_sorts_the_knots() {
+        let path = profile_file(
+            "sorted",
+            "distance_mm,efficiency_pct\n40,60\n0,100\n20, 80\n",
+        );
+        let profile = EfficiencyProfile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            profile.knots,
+            vec![(0.0, 100.0), (20.0, 80.0), (40.0, 60.0)]
+        );
+        assert!(profile.name.starts_with("ejs-steering-"));
+    }
+
+    #[test]
+    fn load_rejects_a_single_knot() {
+        let path = profile_file("single", "distance_mm,efficiency_pct\n0,100\n");
+        let result = EfficiencyProfile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn load_rejects_non_numeric_values() {
+        let path = profile_file("text", "distance_mm,efficiency_pct\n0,100\n30,high\n");
+        let result = EfficiencyProfile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("high is not a number"));
+    }
+
+    #[test]
+    fn saved_profiles_load_unchanged() {
+        let path = profile_file("saved", "");
+        let profile = EfficiencyProfile::default();
+        profile.save(&path).unwrap();
+        let loaded = EfficiencyProfile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.knots, profile.knots);
+    }
+}
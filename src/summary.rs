@@ -0,0 +1,418 @@
+use crate::{
+    dialect::{self, Dialect, DialectOverrides},
+    safety,
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    NaiveDateTime, TimeZone,
+};
+use chrono_tz::Tz;
+use polars::prelude::*;
+use std::io::Cursor;
+
+// Formats tried in order when the timestamp format is detected automatically
+pub const TIME_FORMATS: [&str; 8] = [
+    "%Y%m%d%H%M%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y/%m/%d %H:%M:%S",
+    "%d.%m.%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M:%S",
+    "%m/%d/%Y %H:%M:%S",
+    "%d-%m-%Y %H:%M:%S",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DisplayZone {
+    #[default]
+    Utc,
+    Local,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TimeOptions {
+    // None detects the format from the first timestamps
+    pub format: Option<String>,
+    // IANA name of the zone the console clock runs in
+    pub source_tz: String,
+    pub display: DisplayZone,
+    // Encoding, delimiter, decimal separator and header row of the csv
+    pub csv: DialectOverrides,
+}
+
+impl Default for TimeOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            format: None,
+            source_tz: "UTC".to_string(),
+            display: DisplayZone::Utc,
+            csv: DialectOverrides::default(),
+        }
+    }
+}
+
+impl TimeOptions {
+    #[inline(always)]
+    pub fn display_tz(&self) -> String {
+        match self.display {
+            DisplayZone::Utc => "UTC".to_string(),
+            DisplayZone::Local => {
+                iana_time_zone::get_timezone().unwrap_or_else(|_| "UTC".to_string())
+            }
+        }
+    }
+}
+
+// What the loader found out about a summary file
+#[derive(Clone, Debug, Default)]
+pub struct LoadInfo {
+    pub time_format: String,
+    pub dialect: Dialect,
+}
+
+#[inline(always)]
+pub fn parse_tz(name: &str) -> PolarsResult<Tz> {
+    name.trim()
+        .parse()
+        .map_err(|_| PolarsError::ComputeError(format!("unknown time zone {}", name).into()))
+}
+
+// Interpret a console wall-clock time in the source zone and return it as UTC
+#[inline(always)]
+pub fn to_utc(time: NaiveDateTime, tz: &Tz) -> Option<NaiveDateTime> {
+    tz.from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.naive_utc())
+}
+
+// A format has to read every timestamp, so a change of format inside the file is not missed
+#[inline(always)]
+fn detect_time_format(values: &[&str]) -> Option<&'static str> {
+    TIME_FORMATS.into_iter().find(|format| {
+        !values.is_empty()
+            && values
+                .iter()
+                .all(|value| NaiveDateTime::parse_from_str(value, format).is_ok())
+    })
+}
+
+#[inline(always)]
+pub fn is_valid_format(format: &str) -> bool {
+    !format.trim().is_empty() && !StrftimeItems::new(format).any(|item| item == Item::Error)
+}
+
+#[inline(always)]
+fn unreadable_times(count: usize, example: &str, why: &str) -> PolarsError {
+    PolarsError::ComputeError(
+        format!(
+            "{} in {} row{}, e.g. {:?}",
+            why,
+            count,
+            if count == 1 { "" } else { "s" },
+            example
+        )
+        .into(),
+    )
+}
+
+// Turn the raw "Time" column into a UTC based datetime shown in the display zone
+#[inline(always)]
+fn parse_time_column(time: &Series, options: &TimeOptions) -> PolarsResult<(Series, String)> {
+    let source_tz = parse_tz(&options.source_tz)?;
+    let naive: Vec<Option<NaiveDateTime>>;
+    let format;
+    if let DataType::Datetime(_, _) = time.dtype() {
+        // Already parsed by the csv reader, only the zone is missing
+        let millis = time.cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?;
+        let millis = millis.cast(&DataType::Int64)?;
+        naive = millis
+            .i64()?
+            .into_iter()
+            .map(|ms| ms.and_then(NaiveDateTime::from_timestamp_millis))
+            .collect();
+        format = "parsed by csv reader".to_string();
+    } else {
+        let text = time.cast(&DataType::Utf8)?;
+        let values: Vec<Option<&str>> =
+            text.utf8()?.into_iter().map(|v| v.map(str::trim)).collect();
+        let present: Vec<&str> = values.iter().flatten().copied().collect();
+        let detected = match &options.format {
+            Some(format) => format.clone(),
+            None => detect_time_format(&present)
+                .ok_or_else(|| {
+                    PolarsError::ComputeError(
+                        format!(
+                            "could not detect the time format of {:?}",
+                            present.first().unwrap_or(&"")
+                        )
+                        .into(),
+                    )
+                })?
+                .to_string(),
+        };
+        naive = values
+            .iter()
+            .map(|value| {
+                value.and_then(|value| NaiveDateTime::parse_from_str(value, &detected).ok())
+            })
+            .collect();
+        // Rows with an unreadable time would be dropped later, so refuse them here
+        let unparsed: Vec<&str> = values
+            .iter()
+            .zip(&naive)
+            .filter_map(|(value, time)| time.is_none().then_some(*value).flatten())
+            .collect();
+        if let Some(example) = unparsed.first() {
+            return Err(unreadable_times(
+                unparsed.len(),
+                example,
+                &format!("time does not match the format {}", detected),
+            ));
+        }
+        format = detected;
+    }
+
+    let utc: Vec<Option<i64>> = naive
+        .iter()
+        .map(|time| {
+            time.and_then(|time| to_utc(time, &source_tz))
+                .map(|time| time.timestamp_millis())
+        })
+        .collect();
+    // Wall-clock times skipped by a daylight saving change do not exist in the source zone
+    let skipped: Vec<NaiveDateTime> = naive
+        .iter()
+        .zip(&utc)
+        .filter_map(|(time, utc)| utc.is_none().then_some(*time).flatten())
+        .collect();
+    if let Some(example) = skipped.first() {
+        return Err(unreadable_times(
+            skipped.len(),
+            &example.to_string(),
+            &format!("time does not exist in {}", source_tz.name()),
+        ));
+    }
+    let utc: Int64Chunked = utc.into_iter().collect();
+    let series = utc
+        .into_datetime(TimeUnit::Milliseconds, Some(options.display_tz()))
+        .into_series()
+        .with_name("Time");
+    Ok((series, format))
+}
+
+#[inline(always)]
+pub fn read_csv_bytes(
+    bytes: Vec<u8>,
+    options: &TimeOptions,
+) -> PolarsResult<(DataFrame, LoadInfo)> {
+    let (bytes, dialect) = dialect::normalize(&bytes, &options.csv)
+        .map_err(|e| PolarsError::ComputeError(format!("{:#}", e).into()))?;
+    let mut df = CsvReader::new(Cursor::new(bytes))
+        .has_header(true)
+        .with_encoding(CsvEncoding::Utf8)
+        .with_try_parse_dates(true)
+        .finish()?;
+    let (time, time_format) = parse_time_column(df.column("Time")?, options)?;
+    df.with_column(time)?;
+
+    let mut df = df
+        .lazy()
+        .with_columns([
+            (col("Energy[J]") / col("Num. of SubSonic")).alias("Energy per subspot"),
+            (col("Num. of Pulses") * col("Pulse Duration")).alias("CumPulseDurperRep"),
+            (col("Act. Energy[J]") / col("Num. of SubSonic")).alias("Act. Energy per subspot"),
+            col("Target Volume [cc]").cumsum(false).alias("cum_vol"),
+        ])
+        .drop_nulls(Some(Vec::<Expr>::new()))
+        // Time since the previous sonication started
+        .with_columns([((col("Time") - col("Time").shift(1))
+            .cast(DataType::Int64)
+            .cast(DataType::Float64)
+            / lit(1000.0))
+        .alias("Interval [s]")])
+        .drop_columns(vec![
+            "Protocol Name ",
+            "Frequency[Hz]",
+            "Mode",
+            "Treated Dose[cc]",
+            "Protocol Name",
+        ])
+        .collect()?;
+    safety::normalize_columns(&mut df)?;
+
+    Ok((
+        df,
+        LoadInfo {
+            time_format,
+            dialect,
+        },
+    ))
+}
+
+// Start time of every sonication in UTC
+#[inline(always)]
+pub fn sonication_times(df: &DataFrame) -> Vec<Option<NaiveDateTime>> {
+    let millis = df
+        .column("Time")
+        .and_then(|time| time.cast(&DataType::Int64))
+        .ok();
+    match millis.as_ref().and_then(|millis| millis.i64().ok()) {
+        Some(millis) => millis
+            .into_iter()
+            .map(|ms| ms.and_then(NaiveDateTime::from_timestamp_millis))
+            .collect(),
+        None => vec![None; df.height()],
+    }
+}
+
+// First and last sonication start, used for the treatment duration
+#[inline(always)]
+pub fn treatment_span(df: &DataFrame) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    let times: Vec<NaiveDateTime> = sonication_times(df).into_iter().flatten().collect();
+    Some((*times.iter().min()?, *times.iter().max()?))
+}
+
+// A column as f64 values, None when it is missing or not numeric
+#[inline(always)]
+pub fn float_values(df: &DataFrame, name: &str) -> Option<Vec<Option<f64>>> {
+    let series = df.column(name).ok()?.cast(&DataType::Float64).ok()?;
+    let values = series.f64().ok()?.into_iter().collect();
+    Some(values)
+}
+
+// Energy delivered per sonication [J], the planned energy when the actual one was not logged.
+// The flag is set when the planned energy is returned.
+#[inline(always)]
+pub fn sonication_energy(df: &DataFrame) -> Option<(Vec<Option<f64>>, bool)> {
+    match float_values(df, "Act. Energy[J]") {
+        Some(actual) if actual.iter().any(Option::is_some) => Some((actual, false)),
+        _ => float_values(df, "Energy[J]").map(|planned| (planned, true)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn options(format: Option<&str>, source_tz: &str) -> TimeOptions {
+        TimeOptions {
+            format: format.map(str::to_string),
+            source_tz: source_tz.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // UTC milliseconds of the parsed column and the format that was used
+    #[inline(always)]
+    fn parse(
+        values: &[Option<&str>],
+        options: &TimeOptions,
+    ) -> PolarsResult<(Vec<Option<i64>>, String)> {
+        let (series, format) = parse_time_column(&Series::new("Time", values), options)?;
+        let millis = series.cast(&DataType::Int64)?.i64()?.into_iter().collect();
+        Ok((millis, format))
+    }
+
+    #[inline(always)]
+    fn millis(text: &str) -> Option<i64> {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S")
+            .ok()
+            .map(|time| time.timestamp_millis())
+    }
+
+    #[inline(always)]
+    fn error(result: PolarsResult<(Vec<Option<i64>>, String)>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn detects_the_console_format_and_converts_to_utc() {
+        let (times, format) = parse(
+            &[Some("20230615120000 "), Some("20230615121530")],
+            &options(None, "Europe/Berlin"),
+        )
+        .unwrap();
+        assert_eq!(format, "%Y%m%d%H%M%S");
+        assert_eq!(
+            times,
+            vec![millis("2023-06-15 10:00:00"), millis("2023-06-15 10:15:30")]
+        );
+    }
+
+    #[test]
+    fn detects_day_first_formats() {
+        let (times, format) = parse(
+            &[Some("15.06.2023 12:00:00"), Some("16.06.2023 08:30:00")],
+            &options(None, "UTC"),
+        )
+        .unwrap();
+        assert_eq!(format, "%d.%m.%Y %H:%M:%S");
+        assert_eq!(
+            times,
+            vec![millis("2023-06-15 12:00:00"), millis("2023-06-16 08:30:00")]
+        );
+    }
+
+    #[test]
+    fn empty_cells_stay_empty() {
+        let (times, _) =
+            parse(&[Some("2023-06-15 12:00:00"), None], &options(None, "UTC")).unwrap();
+        assert_eq!(times, vec![millis("2023-06-15 12:00:00"), None]);
+    }
+
+    #[test]
+    fn unparsed_times_are_an_error() {
+        let message = error(parse(
+            &[Some("20230615120000"), Some("garbage"), Some("later")],
+            &options(Some("%Y%m%d%H%M%S"), "UTC"),
+        ));
+        assert!(message.contains("in 2 rows"), "{}", message);
+        assert!(message.contains("garbage"), "{}", message);
+    }
+
+    #[test]
+    fn a_format_change_late_in_the_file_is_not_missed() {
+        let mut values = vec![Some("2023-06-15 12:00:00"); 30];
+        values.push(Some("15.06.2023 13:00:00"));
+        let message = error(parse(&values, &options(None, "UTC")));
+        assert!(message.contains("could not detect"), "{}", message);
+    }
+
+    #[test]
+    fn times_skipped_by_daylight_saving_are_an_error() {
+        let message = error(parse(
+            &[Some("2023-03-26 01:30:00"), Some("2023-03-26 02:30:00")],
+            &options(None, "Europe/Berlin"),
+        ));
+        assert!(
+            message.contains("does not exist in Europe/Berlin"),
+            "{}",
+            message
+        );
+        assert!(message.contains("in 1 row,"), "{}", message);
+    }
+
+    #[test]
+    fn repeated_hour_takes_the_earlier_time() {
+        let (times, _) = parse(
+            &[Some("2023-10-29 02:30:00")],
+            &options(None, "Europe/Berlin"),
+        )
+        .unwrap();
+        assert_eq!(times, vec![millis("2023-10-29 00:30:00")]);
+    }
+
+    #[test]
+    fn unknown_time_zone_is_an_error() {
+        assert!(parse(&[Some("2023-06-15 12:00:00")], &options(None, "Mars/Base")).is_err());
+    }
+
+    #[test]
+    fn validates_custom_formats() {
+        assert!(is_valid_format("%Y-%m-%d %H:%M:%S"));
+        assert!(!is_valid_format(""));
+        assert!(!is_valid_format("%Y-%m-%"));
+    }
+}
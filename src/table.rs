@@ -0,0 +1,579 @@
+use eframe::egui::{self, scroll_area::ScrollBarVisibility, Color32, RichText, ScrollArea};
+use polars::prelude::*;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+};
+
+const COLUMN_WIDTH: f32 = 90.0;
+const ROW_NUMBER_WIDTH: f32 = 40.0;
+
+#[derive(Clone, Default)]
+struct ColumnFilter {
+    min: String,
+    max: String,
+    text: String,
+}
+
+impl ColumnFilter {
+    #[inline(always)]
+    fn is_empty(&self) -> bool {
+        self.min.trim().is_empty() && self.max.trim().is_empty() && self.text.trim().is_empty()
+    }
+}
+
+// Sortable, filterable view on a DataFrame that only lays out the rows on screen
+#[derive(Default)]
+pub struct DataTable {
+    sort: Option<(String, bool)>,
+    filters: HashMap<String, ColumnFilter>,
+    hidden: HashSet<String>,
+    show_filters: bool,
+    // Original row indices in display order
+    view: Vec<usize>,
+    dirty: bool,
+    shape: (usize, usize),
+    selection: BTreeSet<usize>,
+    // Original row index a shift-click range starts from
+    anchor: Option<usize>,
+    last_selected: Option<usize>,
+    v_offset: f32,
+    h_offset: f32,
+    // Pointer was over the table last frame, keyboard copy is only taken then
+    hovered: bool,
+    // Row colours and the reason shown on hover, per analysis that set them
+    highlights: Vec<(&'static str, RowHighlights)>,
+    // Factor to the shown unit and the header naming it, per column with a unit
+    units: HashMap<String, (f64, String)>,
+}
+
+// Row colour and the reason shown on hover, per original row index
+pub type RowHighlights = HashMap<usize, (Color32, String)>;
+
+// Numeric (and temporal) columns as f64 for sorting and range filters
+#[inline(always)]
+fn numeric_values(series: &Series) -> Option<Vec<Option<f64>>> {
+    let dtype = series.dtype();
+    let series = if dtype.is_numeric() {
+        series.cast(&DataType::Float64).ok()?
+    } else if dtype.is_temporal() {
+        series
+            .cast(&DataType::Int64)
+            .ok()?
+            .cast(&DataType::Float64)
+            .ok()?
+    } else {
+        return None;
+    };
+    let values = series.f64().ok()?.into_iter().collect();
+    Some(values)
+}
+
+#[inline(always)]
+pub fn format_value(value: &AnyValue) -> String {
+    match value {
+        AnyValue::Null => String::new(),
+        AnyValue::Float64(v) => format!("{:.2}", v),
+        AnyValue::Float32(v) => format!("{:.2}", v),
+        AnyValue::Utf8(v) => v.to_string(),
+        other => format!("{}", other),
+    }
+}
+
+#[inline(always)]
+fn cell_text(df: &DataFrame, name: &str, row: usize, factor: f64) -> String {
+    let Some(value) = df.column(name).ok().and_then(|series| series.get(row).ok()) else {
+        return String::new();
+    };
+    match value.extract::<f64>() {
+        Some(v) if factor != 1.0 => format_value(&AnyValue::Float64(v * factor)),
+        _ => format_value(&value),
+    }
+}
+
+#[inline(always)]
+fn cell_rich_text(
+    df: &DataFrame,
+    name: &str,
+    row: usize,
+    factor: f64,
+    highlight: &Option<(Color32, String)>,
+) -> RichText {
+    let text = RichText::new(cell_text(df, name, row, factor));
+    match highlight {
+        Some((color, _)) => text.color(*color),
+        None => text,
+    }
+}
+
+impl DataTable {
+    // Call after the DataFrame was replaced so sorting and filters are reapplied
+    #[inline(always)]
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+        self.selection.clear();
+        self.anchor = None;
+    }
+
+    // Replace the highlighted rows of one analysis, the analysis set first wins the colour
+    #[inline(always)]
+    pub fn set_highlights(&mut self, source: &'static str, rows: RowHighlights) {
+        match self.highlights.iter_mut().find(|(name, _)| *name == source) {
+            Some((_, existing)) => *existing = rows,
+            None => self.highlights.push((source, rows)),
+        }
+    }
+
+    // Show the columns in these units, values stay as stored in the DataFrame
+    #[inline(always)]
+    pub fn set_units(&mut self, units: HashMap<String, (f64, String)>) {
+        self.units = units;
+        self.dirty = true;
+    }
+
+    #[inline(always)]
+    fn factor(&self, name: &str) -> f64 {
+        self.units.get(name).map_or(1.0, |(factor, _)| *factor)
+    }
+
+    #[inline(always)]
+    fn highlight(&self, row: usize) -> Option<(Color32, String)> {
+        let mut found = self
+            .highlights
+            .iter()
+            .filter_map(|(_, rows)| rows.get(&row));
+        let (color, first) = found.next()?;
+        let reasons = std::iter::once(first.as_str())
+            .chain(found.map(|(_, reason)| reason.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Some((*color, reasons))
+    }
+
+    #[inline(always)]
+    fn rebuild(&mut self, df: &DataFrame) {
+        let mut rows: Vec<usize> = (0..df.height()).collect();
+        for (name, filter) in &self.filters {
+            if filter.is_empty() {
+                continue;
+            }
+            let Ok(series) = df.column(name) else {
+                continue;
+            };
+            let factor = self.factor(name);
+            if let Some(values) = numeric_values(series) {
+                let min = filter.min.trim().parse::<f64>().ok();
+                let max = filter.max.trim().parse::<f64>().ok();
+                // The bounds are typed in the shown unit
+                rows.retain(|&row| match values[row].map(|v| v * factor) {
+                    Some(v) => min.iter().all(|&min| v >= min) && max.iter().all(|&max| v <= max),
+                    None => min.is_none() && max.is_none(),
+                });
+            }
+            let text = filter.text.trim().to_lowercase();
+            if !text.is_empty() {
+                rows.retain(|&row| {
+                    cell_text(df, name, row, factor)
+                        .to_lowercase()
+                        .contains(&text)
+                });
+            }
+        }
+        if let Some((name, descending)) = &self.sort {
+            if let Ok(series) = df.column(name) {
+                match numeric_values(series) {
+                    Some(values) => rows.sort_by(|&a, &b| {
+                        values[a].partial_cmp(&values[b]).unwrap_or(Ordering::Equal)
+                    }),
+                    None => rows.sort_by_cached_key(|&row| cell_text(df, name, row, 1.0)),
+                }
+                if *descending {
+                    rows.reverse();
+                }
+            }
+        }
+        self.view = rows;
+        self.shape = df.shape();
+        self.dirty = false;
+    }
+
+    #[inline(always)]
+    fn toggle_sort(&mut self, name: &str) {
+        self.sort = match self.sort.take() {
+            Some((current, false)) if current == name => Some((current, true)),
+            Some((current, true)) if current == name => None,
+            _ => Some((name.to_string(), false)),
+        };
+        self.dirty = true;
+    }
+
+    #[inline(always)]
+    fn click_row(&mut self, position: usize, modifiers: egui::Modifiers) {
+        let row = self.view[position];
+        if modifiers.shift {
+            // The anchor row may have moved or been filtered out since it was clicked
+            let anchor = self
+                .anchor
+                .and_then(|anchor| self.view.iter().position(|&r| r == anchor))
+                .unwrap_or(position);
+            let (from, to) = (anchor.min(position), anchor.max(position));
+            if !modifiers.command {
+                self.selection.clear();
+            }
+            self.selection.extend(self.view[from..=to].iter().copied());
+        } else if modifiers.command {
+            if !self.selection.remove(&row) {
+                self.selection.insert(row);
+            }
+            self.anchor = Some(row);
+        } else {
+            self.selection.clear();
+            self.selection.insert(row);
+            self.anchor = Some(row);
+        }
+    }
+
+    // Selected rows of the visible columns as tab separated text, ready to paste in a spreadsheet
+    #[inline(always)]
+    fn selection_text(&self, df: &DataFrame, columns: &[&str]) -> String {
+        let mut text = columns
+            .iter()
+            .map(|name| {
+                self.units
+                    .get(*name)
+                    .map_or(*name, |(_, label)| label.as_str())
+            })
+            .collect::<Vec<_>>()
+            .join("\t");
+        for &row in self.view.iter().filter(|row| self.selection.contains(row)) {
+            text.push('\n');
+            let cells: Vec<String> = columns
+                .iter()
+                .map(|name| cell_text(df, name, row, self.factor(name)))
+                .collect();
+            text.push_str(&cells.join("\t"));
+        }
+        text
+    }
+
+    #[inline(always)]
+    fn filter_cell(&mut self, ui: &mut egui::Ui, df: &DataFrame, name: &str, height: f32) {
+        let numeric = df
+            .column(name)
+            .map(|series| series.dtype().is_numeric())
+            .unwrap_or(false);
+        let filter = self.filters.entry(name.to_string()).or_default();
+        let changed = ui
+            .allocate_ui(egui::vec2(COLUMN_WIDTH, height), |ui| {
+                if numeric {
+                    ui.horizontal(|ui| {
+                        let min = ui.add(
+                            egui::TextEdit::singleline(&mut filter.min)
+                                .hint_text("min")
+                                .desired_width(COLUMN_WIDTH / 2.0 - 4.0),
+                        );
+                        let max = ui.add(
+                            egui::TextEdit::singleline(&mut filter.max)
+                                .hint_text("max")
+                                .desired_width(COLUMN_WIDTH / 2.0 - 4.0),
+                        );
+                        min.changed() || max.changed()
+                    })
+                    .inner
+                } else {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut filter.text)
+                            .hint_text("contains")
+                            .desired_width(COLUMN_WIDTH),
+                    )
+                    .changed()
+                }
+            })
+            .inner;
+        if changed {
+            self.dirty = true;
+        }
+    }
+
+    #[inline(always)]
+    fn header_cell(&mut self, ui: &mut egui::Ui, name: &str, height: f32) {
+        let arrow = match &self.sort {
+            Some((current, false)) if current == name => " ⏶",
+            Some((current, true)) if current == name => " ⏷",
+            _ => "",
+        };
+        let label = self
+            .units
+            .get(name)
+            .map_or(name, |(_, label)| label.as_str());
+        if ui
+            .add_sized(
+                [COLUMN_WIDTH, height],
+                egui::Button::new(RichText::new(format!("{}{}", label, arrow)).strong()).wrap(true),
+            )
+            .on_hover_text(name)
+            .clicked()
+        {
+            self.toggle_sort(name);
+        }
+    }
+
+    // Returns the original index of a row that was clicked this frame
+    #[inline(always)]
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        df: &DataFrame,
+        selected_row: &mut Option<usize>,
+    ) -> Option<usize> {
+        if self.dirty || self.shape != df.shape() {
+            self.rebuild(df);
+        }
+        // Follow selections made elsewhere, e.g. in the snapshot gallery
+        if *selected_row != self.last_selected {
+            if let Some(row) = *selected_row {
+                self.selection.clear();
+                self.selection.insert(row);
+            }
+            self.last_selected = *selected_row;
+        }
+
+        let names = df.get_column_names();
+        let columns: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| !self.hidden.contains(*name))
+            .collect();
+
+        ui.horizontal(|ui| {
+            ui.menu_button("Columns", |ui| {
+                for name in &names {
+                    let mut visible = !self.hidden.contains(*name);
+                    if ui.checkbox(&mut visible, *name).changed() {
+                        if visible {
+                            self.hidden.remove(*name);
+                        } else {
+                            self.hidden.insert(name.to_string());
+                        }
+                    }
+                }
+            });
+            ui.toggle_value(&mut self.show_filters, "Filters");
+            if ui.button("Clear filters").clicked() {
+                self.filters.clear();
+                self.dirty = true;
+            }
+            ui.label(format!("{} of {} rows", self.view.len(), df.height()));
+            // Ctrl+C only copies rows while the pointer is over the table and no text field has focus
+            let copy_requested = self.hovered
+                && !ui.ctx().wants_keyboard_input()
+                && ui.input(|i| i.events.iter().any(|e| matches!(e, egui::Event::Copy)));
+            if (ui
+                .add_enabled(
+                    !self.selection.is_empty(),
+                    egui::Button::new(format!("Copy {} rows", self.selection.len())),
+                )
+                .clicked()
+                || copy_requested)
+                && !self.selection.is_empty()
+            {
+                let text = self.selection_text(df, &columns);
+                ui.output_mut(|o| o.copied_text = text);
+            }
+        });
+
+        let Some((frozen, scrolling)) = columns.split_first() else {
+            ui.label("All columns are hidden");
+            return None;
+        };
+        let header_height = 3.0 * ui.text_style_height(&egui::TextStyle::Body);
+        let filter_height = ui.spacing().interact_size.y;
+        let row_height = ui.spacing().interact_size.y;
+        let total_rows = self.view.len();
+        let mut clicked = None;
+        let modifiers = ui.input(|i| i.modifiers);
+
+        let body = ui.horizontal_top(|ui| {
+            // Frozen row number and first column, scrolled together with the body
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.add_sized([ROW_NUMBER_WIDTH, header_height], egui::Label::new("#"));
+                    self.header_cell(ui, frozen, header_height);
+                });
+                if self.show_filters {
+                    ui.horizontal(|ui| {
+                        ui.add_space(ROW_NUMBER_WIDTH + ui.spacing().item_spacing.x);
+                        self.filter_cell(ui, df, frozen, filter_height);
+                    });
+                }
+                ScrollArea::vertical()
+                    .id_source("data_table_frozen")
+                    .vertical_scroll_offset(self.v_offset)
+                    .scroll_bar_visibility(ScrollBarVisibility::AlwaysHidden)
+                    .enable_scrolling(false)
+                    .auto_shrink([true, false])
+                    .show_rows(ui, row_height, total_rows, |ui, range| {
+                        for position in range {
+                            let row = self.view[position];
+                            let selected = self.selection.contains(&row);
+                            let highlight = self.highlight(row);
+                            ui.horizontal(|ui| {
+                                let number = ui.add_sized(
+                                    [ROW_NUMBER_WIDTH, row_height],
+                                    egui::Label::new(
+                                        RichText::new(format!("{}", row + 1)).color(
+                                            highlight
+                                                .as_ref()
+                                                .map_or(Color32::GRAY, |(color, _)| *color),
+                                        ),
+                                    ),
+                                );
+                                if let Some((_, reason)) = &highlight {
+                                    number.on_hover_text(reason);
+                                }
+                                if ui
+                                    .add_sized(
+                                        [COLUMN_WIDTH, row_height],
+                                        egui::SelectableLabel::new(
+                                            selected,
+                                            cell_rich_text(
+                                                df,
+                                                frozen,
+                                                row,
+                                                self.factor(frozen),
+                                                &highlight,
+                                            ),
+                                        ),
+                                    )
+                                    .clicked()
+                                {
+                                    clicked = Some(position);
+                                }
+                            });
+                        }
+                    });
+            });
+            ui.separator();
+            ui.vertical(|ui| {
+                ScrollArea::horizontal()
+                    .id_source("data_table_header")
+                    .horizontal_scroll_offset(self.h_offset)
+                    .scroll_bar_visibility(ScrollBarVisibility::AlwaysHidden)
+                    .enable_scrolling(false)
+                    .show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            for name in scrolling {
+                                self.header_cell(ui, name, header_height);
+                            }
+                        });
+                        if self.show_filters {
+                            ui.horizontal(|ui| {
+                                for name in scrolling {
+                                    self.filter_cell(ui, df, name, filter_height);
+                                }
+                            });
+                        }
+                    });
+                let output = ScrollArea::both()
+                    .id_source("data_table_body")
+                    .auto_shrink([false, false])
+                    .show_rows(ui, row_height, total_rows, |ui, range| {
+                        for position in range {
+                            let row = self.view[position];
+                            let selected = self.selection.contains(&row);
+                            let highlight = self.highlight(row);
+                            ui.horizontal(|ui| {
+                                for name in scrolling {
+                                    if ui
+                                        .add_sized(
+                                            [COLUMN_WIDTH, row_height],
+                                            egui::SelectableLabel::new(
+                                                selected,
+                                                cell_rich_text(
+                                                    df,
+                                                    name,
+                                                    row,
+                                                    self.factor(name),
+                                                    &highlight,
+                                                ),
+                                            ),
+                                        )
+                                        .clicked()
+                                    {
+                                        clicked = Some(position);
+                                    }
+                                }
+                            });
+                        }
+                    });
+                if output.state.offset != egui::vec2(self.h_offset, self.v_offset) {
+                    self.h_offset = output.state.offset.x;
+                    self.v_offset = output.state.offset.y;
+                    ui.ctx().request_repaint();
+                }
+            });
+        });
+        self.hovered = ui.rect_contains_pointer(body.response.rect);
+
+        let clicked = clicked.map(|position| {
+            self.click_row(position, modifiers);
+            self.view[position]
+        });
+        if let Some(row) = clicked {
+            *selected_row = Some(row);
+            self.last_selected = Some(row);
+        }
+        clicked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn table() -> (DataTable, DataFrame) {
+        let df = df!("Energy" => &[10.0, 20.0, 30.0, 40.0, 50.0, 60.0]).unwrap();
+        let mut table = DataTable::default();
+        table.rebuild(&df);
+        (table, df)
+    }
+
+    #[inline(always)]
+    fn set_min(table: &mut DataTable, df: &DataFrame, min: &str) {
+        table.filters.entry("Energy".to_string()).or_default().min = min.to_string();
+        table.rebuild(df);
+    }
+
+    #[test]
+    fn shift_click_after_the_view_shrinks() {
+        let (mut table, df) = table();
+        table.click_row(5, egui::Modifiers::NONE);
+        set_min(&mut table, &df, "35");
+        assert_eq!(table.view, vec![3, 4, 5]);
+        // The anchor row is now at position 2
+        table.click_row(0, egui::Modifiers::SHIFT);
+        assert_eq!(table.selection, BTreeSet::from([3, 4, 5]));
+    }
+
+    #[test]
+    fn shift_click_from_a_filtered_out_anchor() {
+        let (mut table, df) = table();
+        table.click_row(0, egui::Modifiers::NONE);
+        set_min(&mut table, &df, "35");
+        table.click_row(1, egui::Modifiers::SHIFT);
+        assert_eq!(table.selection, BTreeSet::from([4]));
+    }
+
+    #[test]
+    fn shift_click_follows_the_anchor_after_sorting() {
+        let (mut table, df) = table();
+        table.click_row(1, egui::Modifiers::NONE);
+        table.toggle_sort("Energy");
+        table.toggle_sort("Energy");
+        table.rebuild(&df);
+        assert_eq!(table.view, vec![5, 4, 3, 2, 1, 0]);
+        table.click_row(2, egui::Modifiers::SHIFT);
+        assert_eq!(table.selection, BTreeSet::from([1, 2, 3]));
+    }
+}
@@ -0,0 +1,417 @@
+use crate::summary;
+use eframe::egui::{self, Align2, Color32, FontId, Grid, Pos2, Rect, RichText, Sense, Stroke};
+use polars::prelude::*;
+use std::collections::HashMap;
+
+// Row height of one sonication in the chart [px]
+const ROW_HEIGHT: f32 = 8.0;
+const AXIS_HEIGHT: f32 = 18.0;
+const SHORT_GAP_COLOR: Color32 = Color32::from_rgb(220, 50, 50);
+// Names the console uses for the number of repetitions of a sonication
+const REPETITION_COLUMNS: [&str; 4] = [
+    "Num. of Repetitions",
+    "Num. of Reps",
+    "Repetitions",
+    "Cycles",
+];
+
+#[derive(Clone, Copy, Debug)]
+struct Bar {
+    row: usize,
+    // Seconds since the first sonication started
+    start: f64,
+    duration: f64,
+    // Time since the previous sonication ended, None for the first one
+    gap: Option<f64>,
+}
+
+#[inline(always)]
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as i64;
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+// On time of every sonication [s] and how it was obtained
+#[inline(always)]
+pub fn sonication_durations(df: &DataFrame) -> (Vec<Option<f64>>, String) {
+    // CumPulseDurperRep is the on time of one subspot in one repetition [ms]
+    let on_time = summary::float_values(df, "CumPulseDurperRep").unwrap_or_default();
+    let subspots = summary::float_values(df, "Num. of SubSonic").unwrap_or_default();
+    let repetitions = REPETITION_COLUMNS
+        .iter()
+        .find_map(|name| Some((*name, summary::float_values(df, name)?)));
+    let source = match &repetitions {
+        Some((name, _)) => format!("CumPulseDurperRep × Num. of SubSonic × {}", name),
+        None => "CumPulseDurperRep × Num. of SubSonic, one repetition".to_string(),
+    };
+    let durations = (0..df.height())
+        .map(|row| {
+            let per_rep = on_time.get(row).copied().flatten()?
+                * subspots.get(row).copied().flatten().unwrap_or(1.0);
+            let reps = match &repetitions {
+                Some((_, values)) => values.get(row).copied().flatten().unwrap_or(1.0),
+                None => 1.0,
+            };
+            Some(per_rep * reps / 1000.0)
+        })
+        .collect();
+    (durations, source)
+}
+
+pub struct Timeline {
+    // Gaps shorter than this are flagged [s]
+    pub min_cooldown: f64,
+    bars: Vec<Bar>,
+    // How the sonication durations were obtained
+    source: String,
+}
+
+impl Default for Timeline {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            min_cooldown: 30.0,
+            bars: Vec::new(),
+            source: String::new(),
+        }
+    }
+}
+
+impl Timeline {
+    #[inline(always)]
+    pub fn analyse(&mut self, df: &DataFrame) {
+        self.bars.clear();
+        let times = summary::sonication_times(df);
+        let Some(first) = times.iter().flatten().min().copied() else {
+            return;
+        };
+        let (durations, source) = sonication_durations(df);
+        self.source = source;
+        let mut rows: Vec<(usize, f64, f64)> = times
+            .iter()
+            .zip(durations)
+            .enumerate()
+            .filter_map(|(row, (time, duration))| {
+                let start =
+                    (*time)?.signed_duration_since(first).num_milliseconds() as f64 / 1000.0;
+                Some((row, start, duration?))
+            })
+            .collect();
+        rows.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let mut previous_end: Option<f64> = None;
+        for (row, start, duration) in rows {
+            self.bars.push(Bar {
+                row,
+                start,
+                duration,
+                gap: previous_end.map(|end| start - end),
+            });
+            previous_end =
+                Some(previous_end.map_or(start + duration, |end| end.max(start + duration)));
+        }
+    }
+
+    #[inline(always)]
+    fn is_short(&self, bar: &Bar) -> bool {
+        bar.gap.is_some_and(|gap| gap < self.min_cooldown)
+    }
+
+    // Sonications that started before the cool-down after the previous one was over
+    #[inline(always)]
+    pub fn highlights(&self) -> HashMap<usize, (Color32, String)> {
+        self.bars
+            .iter()
+            .filter(|bar| self.is_short(bar))
+            .map(|bar| {
+                (
+                    bar.row,
+                    (
+                        SHORT_GAP_COLOR,
+                        format!("Short cool-down: {:.1} s", bar.gap.unwrap_or_default()),
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    #[inline(always)]
+    fn show_statistics(&self, ui: &mut egui::Ui) {
+        let total = self
+            .bars
+            .iter()
+            .map(|bar| bar.start + bar.duration)
+            .fold(0.0, f64::max);
+        let sonicating: f64 = self.bars.iter().map(|bar| bar.duration).sum();
+        let gaps: Vec<f64> = self.bars.iter().filter_map(|bar| bar.gap).collect();
+        let idle: f64 = gaps.iter().filter(|gap| **gap > 0.0).sum();
+        let short = self.bars.iter().filter(|bar| self.is_short(bar)).count();
+        Grid::new("timeline_statistics")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("Treatment duration");
+                ui.label(format_seconds(total));
+                ui.end_row();
+                ui.label("Sonicating");
+                ui.label(format_seconds(sonicating));
+                ui.end_row();
+                ui.label("Idle");
+                ui.label(format!(
+                    "{} ({:.0} %)",
+                    format_seconds(idle),
+                    if total > 0.0 {
+                        idle / total * 100.0
+                    } else {
+                        0.0
+                    }
+                ));
+                ui.end_row();
+                ui.label("Shortest gap");
+                ui.label(
+                    gaps.iter()
+                        .copied()
+                        .reduce(f64::min)
+                        .map(|gap| format!("{:.1} s", gap))
+                        .unwrap_or_default(),
+                );
+                ui.end_row();
+                ui.label("Median gap");
+                let mut sorted = gaps.clone();
+                sorted.sort_by(f64::total_cmp);
+                ui.label(
+                    sorted
+                        .get(sorted.len() / 2)
+                        .map(|gap| format!("{:.1} s", gap))
+                        .unwrap_or_default(),
+                );
+                ui.end_row();
+                ui.colored_label(SHORT_GAP_COLOR, "Gaps below cool-down");
+                ui.label(short.to_string());
+                ui.end_row();
+            });
+    }
+
+    #[inline(always)]
+    fn show_chart(&self, ui: &mut egui::Ui, selected_row: &mut Option<usize>) {
+        let total = self
+            .bars
+            .iter()
+            .map(|bar| bar.start + bar.duration)
+            .fold(1.0, f64::max);
+        let size = egui::vec2(
+            ui.available_width(),
+            AXIS_HEIGHT + ROW_HEIGHT * self.bars.len() as f32,
+        );
+        let (response, painter) = ui.allocate_painter(size, Sense::click());
+        let rect = response.rect;
+        let x = |seconds: f64| rect.left() + (seconds / total) as f32 * rect.width();
+        let y = |idx: usize| rect.top() + AXIS_HEIGHT + idx as f32 * ROW_HEIGHT;
+        let visuals = ui.visuals();
+        painter.rect_filled(rect, 2.0, visuals.extreme_bg_color);
+
+        // Time axis with about eight ticks on whole minutes
+        let step = ((total / 8.0 / 60.0).ceil() * 60.0).max(60.0);
+        let mut tick = 0.0;
+        while tick <= total {
+            painter.line_segment(
+                [
+                    Pos2::new(x(tick), rect.top() + AXIS_HEIGHT - 4.0),
+                    Pos2::new(x(tick), rect.bottom()),
+                ],
+                Stroke::new(0.5, visuals.weak_text_color()),
+            );
+            painter.text(
+                Pos2::new(x(tick) + 2.0, rect.top() + 2.0),
+                Align2::LEFT_TOP,
+                format_seconds(tick),
+                FontId::proportional(11.0),
+                visuals.text_color(),
+            );
+            tick += step;
+        }
+
+        for (idx, bar) in self.bars.iter().enumerate() {
+            let top = y(idx) + 1.0;
+            let bottom = y(idx + 1) - 1.0;
+            if let Some(gap) = bar.gap.filter(|gap| *gap > 0.0) {
+                // Cool-down before this sonication
+                let color = if self.is_short(bar) {
+                    SHORT_GAP_COLOR
+                } else {
+                    visuals.weak_text_color()
+                };
+                let middle = (top + bottom) / 2.0;
+                painter.line_segment(
+                    [
+                        Pos2::new(x(bar.start - gap), middle),
+                        Pos2::new(x(bar.start), middle),
+                    ],
+                    Stroke::new(1.0, color),
+                );
+            }
+            let selected = *selected_row == Some(bar.row);
+            let color = if selected {
+                visuals.selection.bg_fill
+            } else if self.is_short(bar) {
+                SHORT_GAP_COLOR
+            } else {
+                Color32::from_rgb(70, 130, 200)
+            };
+            // At least one pixel so very short sonications stay visible
+            let left = x(bar.start);
+            let right = x(bar.start + bar.duration).max(left + 1.0);
+            painter.rect_filled(
+                Rect::from_min_max(Pos2::new(left, top), Pos2::new(right, bottom)),
+                1.0,
+                color,
+            );
+        }
+
+        let hovered = response.hover_pos().and_then(|pos| {
+            let idx = ((pos.y - rect.top() - AXIS_HEIGHT) / ROW_HEIGHT).floor();
+            (idx >= 0.0).then(|| self.bars.get(idx as usize)).flatten()
+        });
+        if let Some(bar) = hovered {
+            if response.clicked() {
+                *selected_row = Some(bar.row);
+            }
+            response.on_hover_text(format!(
+                "Sonication {}\nStart +{}\nDuration {:.1} s{}",
+                bar.row + 1,
+                format_seconds(bar.start),
+                bar.duration,
+                bar.gap
+                    .map(|gap| format!("\nGap before {:.1} s", gap))
+                    .unwrap_or_default()
+            ));
+        }
+    }
+
+    // Returns true when the cool-down changed and the highlights need to be updated
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui, selected_row: &mut Option<usize>) -> bool {
+        if self.bars.is_empty() {
+            ui.label("This export has no sonication times or pulse columns");
+            return false;
+        }
+        let changed = ui
+            .add(
+                egui::DragValue::new(&mut self.min_cooldown)
+                    .clamp_range(0.0..=3600.0)
+                    .speed(1.0)
+                    .prefix("Minimum cool-down ")
+                    .suffix(" s"),
+            )
+            .changed();
+        ui.label(RichText::new(format!("Durations from {}", self.source)).weak());
+        ui.horizontal_top(|ui| {
+            self.show_statistics(ui);
+            ui.vertical(|ui| {
+                egui::ScrollArea::vertical()
+                    .id_source("timeline_chart")
+                    .max_height(300.0)
+                    .show(ui, |ui| self.show_chart(ui, selected_row));
+            });
+        });
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sonications started at 0 s, 65 s and 60 s, the first two last 10 s, the third has
+    // no repetition count and lasts 2 s
+    #[inline(always)]
+    fn treatment() -> DataFrame {
+        df!(
+            "Time" => [Some(0i64), Some(65_000), Some(60_000)],
+            "CumPulseDurperRep" => [1000.0, 1000.0, 1000.0],
+            "Num. of SubSonic" => [2.0, 2.0, 2.0],
+            "Num. of Repetitions" => [Some(5.0), Some(5.0), None]
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn formats_seconds_as_clock_time() {
+        assert_eq!(format_seconds(0.0), "0:00");
+        assert_eq!(format_seconds(59.4), "0:59");
+        assert_eq!(format_seconds(59.6), "1:00");
+        assert_eq!(format_seconds(3661.0), "1:01:01");
+        assert_eq!(format_seconds(-5.0), "0:00");
+    }
+
+    #[test]
+    fn durations_multiply_on_time_subspots_and_repetitions() {
+        let (durations, source) = sonication_durations(&treatment());
+        // A missing repetition count counts as one repetition
+        assert_eq!(durations, vec![Some(10.0), Some(10.0), Some(2.0)]);
+        assert!(source.contains("Num. of Repetitions"), "{}", source);
+    }
+
+    #[test]
+    fn durations_without_repetitions_use_one() {
+        let df = df!(
+            "CumPulseDurperRep" => [Some(500.0), None],
+            "Num. of SubSonic" => [4.0, 4.0]
+        )
+        .unwrap();
+        let (durations, source) = sonication_durations(&df);
+        assert_eq!(durations, vec![Some(2.0), None]);
+        assert!(source.ends_with("one repetition"), "{}", source);
+    }
+
+    #[test]
+    fn gaps_are_measured_from_the_previous_end_in_start_order() {
+        let mut timeline = Timeline::default();
+        timeline.analyse(&treatment());
+        let bars: Vec<(usize, f64, f64, Option<f64>)> = timeline
+            .bars
+            .iter()
+            .map(|bar| (bar.row, bar.start, bar.duration, bar.gap))
+            .collect();
+        assert_eq!(
+            bars,
+            vec![
+                (0, 0.0, 10.0, None),
+                (2, 60.0, 2.0, Some(50.0)),
+                (1, 65.0, 10.0, Some(3.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn short_cool_downs_are_highlighted() {
+        let mut timeline = Timeline::default();
+        timeline.analyse(&treatment());
+        let highlights = timeline.highlights();
+        assert_eq!(highlights.keys().copied().collect::<Vec<_>>(), vec![1]);
+        timeline.min_cooldown = 60.0;
+        let mut rows: Vec<usize> = timeline.highlights().into_keys().collect();
+        rows.sort();
+        assert_eq!(rows, vec![1, 2]);
+    }
+
+    #[test]
+    fn overlapping_sonications_have_a_negative_gap() {
+        let df = df!(
+            "Time" => [0i64, 5_000],
+            "CumPulseDurperRep" => [10_000.0, 1000.0],
+            "Num. of SubSonic" => [1.0, 1.0]
+        )
+        .unwrap();
+        let mut timeline = Timeline::default();
+        timeline.analyse(&df);
+        assert_eq!(timeline.bars[1].gap, Some(-5.0));
+    }
+}
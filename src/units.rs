@@ -0,0 +1,440 @@
+use anyhow::{Context, Result};
+use eframe::egui::{self, Grid, RichText};
+use polars::prelude::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Quantity {
+    Energy,
+    Volume,
+    Time,
+    Length,
+    Power,
+    Frequency,
+}
+
+impl Quantity {
+    pub const ALL: [Quantity; 6] = [
+        Quantity::Energy,
+        Quantity::Volume,
+        Quantity::Time,
+        Quantity::Length,
+        Quantity::Power,
+        Quantity::Frequency,
+    ];
+
+    #[inline(always)]
+    pub fn label(self) -> &'static str {
+        match self {
+            Quantity::Energy => "Energy",
+            Quantity::Volume => "Volume",
+            Quantity::Time => "Time",
+            Quantity::Length => "Length",
+            Quantity::Power => "Power",
+            Quantity::Frequency => "Frequency",
+        }
+    }
+}
+
+// Symbol, quantity and size in the first unit of the quantity
+const UNITS: [(&str, Quantity, f64); 15] = [
+    ("J", Quantity::Energy, 1.0),
+    ("kJ", Quantity::Energy, 1e3),
+    ("cc", Quantity::Volume, 1.0),
+    ("ml", Quantity::Volume, 1.0),
+    ("mm³", Quantity::Volume, 1e-3),
+    ("s", Quantity::Time, 1.0),
+    ("ms", Quantity::Time, 1e-3),
+    ("µs", Quantity::Time, 1e-6),
+    ("min", Quantity::Time, 60.0),
+    ("mm", Quantity::Length, 1.0),
+    ("cm", Quantity::Length, 10.0),
+    ("W", Quantity::Power, 1.0),
+    ("kW", Quantity::Power, 1e3),
+    ("Hz", Quantity::Frequency, 1.0),
+    ("kHz", Quantity::Frequency, 1e3),
+];
+
+// Columns whose header carries no unit, including the ones the summary loader derives
+const DEFAULT_UNITS: [(&str, &str); 8] = [
+    ("Pulse Duration", "ms"),
+    ("CumPulseDurperRep", "ms"),
+    ("Energy per subspot", "J"),
+    ("Act. Energy per subspot", "J"),
+    ("cum_vol", "cc"),
+    ("Focal RAS-R", "mm"),
+    ("Focal RAS-A", "mm"),
+    ("Focal RAS-S", "mm"),
+];
+
+#[inline(always)]
+fn lookup(symbol: &str) -> Option<(&'static str, Quantity, f64)> {
+    let symbol = match symbol.trim() {
+        "mL" | "cm³" | "cm3" => "ml",
+        "mm3" => "mm³",
+        "us" => "µs",
+        "sec" => "s",
+        other => other,
+    };
+    UNITS.into_iter().find(|(known, _, _)| *known == symbol)
+}
+
+// "Energy[J]" and "Target Volume [cc]" carry their unit in brackets at the end
+#[inline(always)]
+pub fn header_unit(name: &str) -> Option<String> {
+    let name = name.trim_end().strip_suffix(']')?;
+    let unit = name[name.rfind('[')? + 1..].trim();
+    (!unit.is_empty()).then(|| unit.to_string())
+}
+
+#[inline(always)]
+fn strip_unit(name: &str) -> &str {
+    match header_unit(name) {
+        Some(_) => name[..name.rfind('[').unwrap_or(name.len())].trim_end(),
+        None => name.trim_end(),
+    }
+}
+
+#[derive(Default)]
+pub struct UnitRegistry {
+    // Unit of the stored values per column, in column order
+    columns: Vec<(String, Option<String>)>,
+    // Units assigned by the user, they win over the header and the defaults
+    overrides: BTreeMap<String, String>,
+    // Unit each quantity is shown in, the stored unit when missing
+    display: HashMap<Quantity, &'static str>,
+}
+
+impl UnitRegistry {
+    #[inline(always)]
+    fn resolve(&self, name: &str) -> Option<String> {
+        if let Some(unit) = self.overrides.get(name) {
+            return (!unit.trim().is_empty()).then(|| unit.trim().to_string());
+        }
+        // Relative deviations keep the planned header, e.g. "Dev. % Energy[J]"
+        if name.starts_with("Dev. % ") {
+            return Some("%".to_string());
+        }
+        if let Some(unit) = header_unit(name) {
+            return Some(unit);
+        }
+        if let Some((_, unit)) = DEFAULT_UNITS.iter().find(|(column, _)| *column == name) {
+            return Some(unit.to_string());
+        }
+        // Actual values and absolute deviations share the unit of the planned column
+        let base = name
+            .strip_prefix("Dev. ")
+            .or_else(|| name.strip_prefix("Act. "))?;
+        self.resolve(base)
+    }
+
+    // Read the units of every column of a freshly loaded summary
+    #[inline(always)]
+    pub fn fill(&mut self, df: &DataFrame) {
+        self.columns = df
+            .get_column_names()
+            .iter()
+            .map(|name| (name.to_string(), None))
+            .collect();
+        self.refresh();
+    }
+
+    #[inline(always)]
+    fn refresh(&mut self) {
+        let units: Vec<Option<String>> = self
+            .columns
+            .iter()
+            .map(|(name, _)| self.resolve(name))
+            .collect();
+        for ((_, unit), resolved) in self.columns.iter_mut().zip(units) {
+            *unit = resolved;
+        }
+    }
+
+    // Unit of the stored values
+    #[inline(always)]
+    pub fn unit(&self, name: &str) -> Option<&str> {
+        self.columns
+            .iter()
+            .find(|(column, _)| column == name)
+            .and_then(|(_, unit)| unit.as_deref())
+    }
+
+    // Factor from the stored to the shown unit and the shown unit
+    #[inline(always)]
+    pub fn shown(&self, name: &str) -> Option<(f64, String)> {
+        let unit = self.unit(name)?;
+        let Some((_, quantity, size)) = lookup(unit) else {
+            return Some((1.0, unit.to_string()));
+        };
+        match self
+            .display
+            .get(&quantity)
+            .and_then(|symbol| lookup(symbol))
+        {
+            Some((symbol, _, shown_size)) => Some((size / shown_size, symbol.to_string())),
+            None => Some((1.0, unit.to_string())),
+        }
+    }
+
+    // Column header with the shown unit, headers that already name it are kept
+    #[inline(always)]
+    pub fn label(&self, name: &str) -> String {
+        let Some((_, shown)) = self.shown(name) else {
+            return name.to_string();
+        };
+        match header_unit(name) {
+            Some(header) if header == shown => name.to_string(),
+            Some(_) => format!("{} [{}]", strip_unit(name), shown),
+            None => format!("{} [{}]", name.trim_end(), shown),
+        }
+    }
+
+    // Conversion factor and header for every column with a unit
+    #[inline(always)]
+    pub fn table_units(&self) -> HashMap<String, (f64, String)> {
+        self.columns
+            .iter()
+            .filter_map(|(name, _)| {
+                let (factor, _) = self.shown(name)?;
+                Some((name.clone(), (factor, self.label(name))))
+            })
+            .collect()
+    }
+
+    // Stored units of the given columns, empty when unknown
+    #[inline(always)]
+    pub fn units_row(&self, names: &[&str]) -> Vec<String> {
+        names
+            .iter()
+            .map(|name| self.unit(name).unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[inline(always)]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut wtr = csv::Writer::from_path(path)?;
+        wtr.write_record(["column", "unit"])?;
+        for (column, unit) in &self.overrides {
+            wtr.write_record([column, unit])?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let mut overrides = BTreeMap::new();
+        for record in csv::Reader::from_path(path)?.records() {
+            let record = record?;
+            let column = record.get(0).context("expected two columns")?;
+            let unit = record.get(1).context("expected two columns")?;
+            overrides.insert(column.to_string(), unit.trim().to_string());
+        }
+        self.overrides = overrides;
+        self.refresh();
+        Ok(())
+    }
+
+    // Returns true when a unit changed and the table needs the new factors
+    #[inline(always)]
+    pub fn show(&mut self, ui: &mut egui::Ui) -> Result<bool> {
+        let mut changed = false;
+        let mut result = Ok(());
+        ui.horizontal(|ui| {
+            if ui.button("Load units").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("CSV", &["csv"])
+                    .pick_file()
+                {
+                    result = self.load(&path);
+                    changed = true;
+                }
+            }
+            if ui.button("Save units").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("CSV", &["csv"])
+                    .set_file_name("units.csv")
+                    .save_file()
+                {
+                    result = self.save(&path);
+                }
+            }
+        });
+        ui.horizontal_wrapped(|ui| {
+            for quantity in Quantity::ALL {
+                let current = self.display.get(&quantity).copied();
+                egui::ComboBox::from_label(quantity.label())
+                    .selected_text(current.unwrap_or("as stored"))
+                    .show_ui(ui, |ui| {
+                        let mut selected = current;
+                        ui.selectable_value(&mut selected, None, "as stored");
+                        for (symbol, _, _) in UNITS.iter().filter(|(_, q, _)| *q == quantity) {
+                            ui.selectable_value(&mut selected, Some(*symbol), *symbol);
+                        }
+                        if selected != current {
+                            match selected {
+                                Some(symbol) => self.display.insert(quantity, symbol),
+                                None => self.display.remove(&quantity),
+                            };
+                            changed = true;
+                        }
+                    });
+            }
+        });
+        ui.label(RichText::new("Edit a unit to override the header and the defaults").weak());
+        egui::ScrollArea::vertical()
+            .id_source("unit_registry")
+            .max_height(300.0)
+            .show(ui, |ui| {
+                Grid::new("unit_registry_grid")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        ui.strong("Column");
+                        ui.strong("Unit");
+                        ui.strong("Shown as");
+                        ui.end_row();
+                        let mut edited = None;
+                        for (name, unit) in &self.columns {
+                            ui.label(name);
+                            let mut text = self
+                                .overrides
+                                .get(name)
+                                .cloned()
+                                .or_else(|| unit.clone())
+                                .unwrap_or_default();
+                            if ui
+                                .add(egui::TextEdit::singleline(&mut text).desired_width(60.0))
+                                .changed()
+                            {
+                                edited = Some((name.clone(), text));
+                            }
+                            ui.label(self.label(name));
+                            ui.end_row();
+                        }
+                        if let Some((name, unit)) = edited {
+                            self.overrides.insert(name, unit);
+                            changed = true;
+                        }
+                    });
+            });
+        if changed {
+            self.refresh();
+        }
+        result.map(|()| changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(always)]
+    fn registry() -> UnitRegistry {
+        let df = df!(
+            "Energy[J]" => [1.0],
+            "Target Volume [cc]" => [1.0],
+            "Act. Energy per subspot" => [1.0],
+            "Dev. Pulse Duration" => [1.0],
+            "Dev. % Energy[J]" => [1.0],
+            "Focal RAS-R" => [1.0],
+            "Num. of SubSonic" => [1.0]
+        )
+        .unwrap();
+        let mut registry = UnitRegistry::default();
+        registry.fill(&df);
+        registry
+    }
+
+    #[test]
+    fn reads_units_from_headers() {
+        assert_eq!(header_unit("Energy[J]").as_deref(), Some("J"));
+        assert_eq!(header_unit("Target Volume [cc] ").as_deref(), Some("cc"));
+        assert_eq!(header_unit("Energy"), None);
+        assert_eq!(header_unit("Energy[]"), None);
+        assert_eq!(header_unit("J]"), None);
+    }
+
+    #[test]
+    fn resolves_headers_defaults_and_derived_columns() {
+        let registry = registry();
+        assert_eq!(registry.unit("Energy[J]"), Some("J"));
+        assert_eq!(registry.unit("Target Volume [cc]"), Some("cc"));
+        assert_eq!(registry.unit("Act. Energy per subspot"), Some("J"));
+        assert_eq!(registry.unit("Dev. Pulse Duration"), Some("ms"));
+        assert_eq!(registry.unit("Focal RAS-R"), Some("mm"));
+        assert_eq!(registry.unit("Num. of SubSonic"), None);
+        assert_eq!(registry.unit("Not loaded"), None);
+    }
+
+    #[test]
+    fn relative_deviations_are_percent_despite_the_header() {
+        assert_eq!(registry().unit("Dev. % Energy[J]"), Some("%"));
+    }
+
+    #[test]
+    fn overrides_win_and_can_clear_a_unit() {
+        let mut registry = registry();
+        registry
+            .overrides
+            .insert("Energy[J]".to_string(), "kJ".to_string());
+        registry
+            .overrides
+            .insert("Focal RAS-R".to_string(), " ".to_string());
+        registry.refresh();
+        assert_eq!(registry.unit("Energy[J]"), Some("kJ"));
+        assert_eq!(registry.unit("Focal RAS-R"), None);
+    }
+
+    #[test]
+    fn converts_to_the_shown_unit() {
+        let mut registry = registry();
+        assert_eq!(registry.shown("Energy[J]"), Some((1.0, "J".to_string())));
+        registry.display.insert(Quantity::Energy, "kJ");
+        registry.display.insert(Quantity::Time, "s");
+        registry.display.insert(Quantity::Volume, "mm³");
+        assert_eq!(registry.shown("Energy[J]"), Some((1e-3, "kJ".to_string())));
+        let (factor, unit) = registry.shown("Dev. Pulse Duration").unwrap();
+        assert!((factor - 1e-3).abs() < 1e-12);
+        assert_eq!(unit, "s");
+        let (factor, unit) = registry.shown("Target Volume [cc]").unwrap();
+        assert!((factor - 1e3).abs() < 1e-9);
+        assert_eq!(unit, "mm³");
+        // Units outside the table are shown as stored
+        assert_eq!(
+            registry.shown("Dev. % Energy[J]"),
+            Some((1.0, "%".to_string()))
+        );
+        assert_eq!(registry.shown("Num. of SubSonic"), None);
+    }
+
+    #[test]
+    fn spelling_variants_are_recognised() {
+        assert_eq!(lookup("mL").map(|(symbol, _, _)| symbol), Some("ml"));
+        assert_eq!(lookup(" us ").map(|(symbol, _, _)| symbol), Some("µs"));
+        assert_eq!(lookup("furlong"), None);
+    }
+
+    #[test]
+    fn labels_name_the_shown_unit() {
+        let mut registry = registry();
+        assert_eq!(registry.label("Energy[J]"), "Energy[J]");
+        assert_eq!(registry.label("Focal RAS-R"), "Focal RAS-R [mm]");
+        assert_eq!(registry.label("Num. of SubSonic"), "Num. of SubSonic");
+        registry.display.insert(Quantity::Energy, "kJ");
+        assert_eq!(registry.label("Energy[J]"), "Energy [kJ]");
+        assert_eq!(registry.label("Dev. % Energy[J]"), "Dev. % Energy [%]");
+    }
+
+    #[test]
+    fn units_row_follows_the_column_order() {
+        let registry = registry();
+        assert_eq!(
+            registry.units_row(&["Num. of SubSonic", "Energy[J]"]),
+            vec![String::new(), "J".to_string()]
+        );
+    }
+}
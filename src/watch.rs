@@ -0,0 +1,522 @@
+use crate::{archive, db::TreatmentDb, export, summary};
+use anyhow::{bail, Context, Result};
+use eframe::egui::{self, Color32, Grid, RichText};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+// Number of ingest results kept for the log window
+const LOG_LENGTH: usize = 200;
+// Polls a file is retried after a failed ingest, a changed file starts over
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Debug)]
+pub struct WatchSettings {
+    pub folders: Vec<PathBuf>,
+    // Treatments are filed into <archive_root>/<treatment date>/<file stem>/
+    pub archive_root: Option<PathBuf>,
+    pub interval_secs: u64,
+    // Also ingest files that were already in the folders when watching started
+    pub ingest_existing: bool,
+}
+
+impl Default for WatchSettings {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            folders: Vec::new(),
+            archive_root: None,
+            interval_secs: 5,
+            ingest_existing: false,
+        }
+    }
+}
+
+pub struct WatchEvent {
+    // None for problems of the watcher itself
+    pub source: Option<PathBuf>,
+    // Folder the treatment was filed into, or why it failed
+    pub result: Result<PathBuf, String>,
+}
+
+#[inline(always)]
+fn is_candidate(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    name.ends_with(".zip") || (name.ends_with(".csv") && name.contains("treatsummary"))
+}
+
+// Run one export through the summary pipeline and file it in the archive
+#[inline(always)]
+pub fn ingest(
+    path: &Path,
+    archive_root: &Path,
+    time_options: &summary::TimeOptions,
+    extract_options: &archive::ExtractOptions,
+    db: Option<&mut TreatmentDb>,
+) -> Result<PathBuf> {
+    let is_zip = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+    let bytes = if is_zip {
+        archive::find_summary(path)?
+            .context("no treatment summary found in archive")?
+            .1
+    } else {
+        std::fs::read(path)?
+    };
+    let (df, _) = summary::read_csv_bytes(bytes.clone(), time_options)?;
+    let date = summary::treatment_span(&df)
+        .map(|(start, _)| start.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "undated".to_string());
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .context("Failed to get file name")?;
+    let dest = archive_root.join(&date).join(stem);
+    if dest.exists() {
+        bail!("already filed in {}", dest.display());
+    }
+    // Filled in a temporary folder, so a failed ingest leaves nothing that blocks a retry
+    let partial = archive_root.join(&date).join(format!(".{}.partial", stem));
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    std::fs::create_dir_all(&partial).context("Failed to create directory")?;
+    let filed = (|| -> Result<()> {
+        std::fs::copy(path, partial.join(path.file_name().unwrap_or_default()))?;
+        export::write_csv(
+            &df,
+            BufWriter::new(File::create(
+                partial.join(format!("{}_simplify.csv", stem)),
+            )?),
+            &export::CsvOptions::default(),
+        )?;
+        if is_zip {
+            archive::extract_zip(path, &partial.join("extracted"), extract_options)?;
+        }
+        std::fs::rename(&partial, &dest)?;
+        Ok(())
+    })();
+    if let Err(e) = filed {
+        let _ = std::fs::remove_dir_all(&partial);
+        return Err(e);
+    }
+    if let Some(db) = db {
+        db.record_treatment(&path.display().to_string(), &bytes, &df)?;
+    }
+    Ok(dest)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum FileState {
+    // Seen with this size and time, possibly still being copied
+    Copying,
+    Ingested,
+    // Ingest attempts that failed for this size and time
+    Failed(u32),
+}
+
+// Size and modification time of every file seen, a file is ingested once it stopped changing
+type Seen = HashMap<PathBuf, (u64, Option<SystemTime>, FileState)>;
+
+// Whether a file should be ingested in this poll, remembers files that are new or changed
+#[inline(always)]
+fn is_ready(seen: &mut Seen, path: &Path, size: u64, modified: Option<SystemTime>) -> bool {
+    match seen.get(path) {
+        Some((s, m, state)) if (*s, *m) == (size, modified) => match state {
+            // Unchanged since the last poll, the copy has finished
+            FileState::Copying => true,
+            FileState::Ingested => false,
+            FileState::Failed(attempts) => *attempts < MAX_ATTEMPTS,
+        },
+        _ => {
+            seen.insert(path.to_path_buf(), (size, modified, FileState::Copying));
+            false
+        }
+    }
+}
+
+// Record the outcome of an ingest so failures are retried a few times
+#[inline(always)]
+fn record_attempt(seen: &mut Seen, path: &Path, ingested: bool) {
+    if let Some((_, _, state)) = seen.get_mut(path) {
+        *state = match (ingested, *state) {
+            (true, _) => FileState::Ingested,
+            (false, FileState::Failed(attempts)) => FileState::Failed(attempts + 1),
+            (false, _) => FileState::Failed(1),
+        };
+    }
+}
+
+#[inline(always)]
+fn scan(folders: &[PathBuf]) -> Vec<(PathBuf, u64, Option<SystemTime>)> {
+    folders
+        .iter()
+        .filter_map(|folder| std::fs::read_dir(folder).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_candidate(&entry.path()))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            metadata
+                .is_file()
+                .then(|| (entry.path(), metadata.len(), metadata.modified().ok()))
+        })
+        .collect()
+}
+
+#[inline(always)]
+fn watch_loop(
+    settings: WatchSettings,
+    archive_root: PathBuf,
+    time_options: summary::TimeOptions,
+    extract_options: archive::ExtractOptions,
+    stop: Arc<AtomicBool>,
+    sender: Sender<WatchEvent>,
+    ctx: egui::Context,
+) {
+    // The UI keeps its own connection, SQLite handles both
+    let db_path = TreatmentDb::default_path();
+    let mut db = match TreatmentDb::open(&db_path) {
+        Ok(db) => Some(db),
+        Err(e) => {
+            let event = WatchEvent {
+                source: None,
+                result: Err(format!(
+                    "Could not open {}, watched treatments are not stored in the database: {:#}",
+                    db_path.display(),
+                    e
+                )),
+            };
+            if sender.send(event).is_err() {
+                return;
+            }
+            None
+        }
+    };
+    let mut seen = Seen::new();
+    if !settings.ingest_existing {
+        for (path, size, modified) in scan(&settings.folders) {
+            seen.insert(path, (size, modified, FileState::Ingested));
+        }
+    }
+    while !stop.load(Ordering::Relaxed) {
+        for (path, size, modified) in scan(&settings.folders) {
+            if !is_ready(&mut seen, &path, size, modified) {
+                continue;
+            }
+            let result = ingest(
+                &path,
+                &archive_root,
+                &time_options,
+                &extract_options,
+                db.as_mut(),
+            )
+            .map_err(|e| format!("{:#}", e));
+            record_attempt(&mut seen, &path, result.is_ok());
+            if sender
+                .send(WatchEvent {
+                    source: Some(path),
+                    result,
+                })
+                .is_err()
+            {
+                return;
+            }
+            ctx.request_repaint();
+        }
+        // Sleep in small steps so stopping does not wait for a whole interval
+        for _ in 0..settings.interval_secs.max(1) * 10 {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FolderWatcher {
+    pub settings: WatchSettings,
+    running: Option<(Arc<AtomicBool>, Receiver<WatchEvent>)>,
+    log: Vec<(SystemTime, WatchEvent)>,
+}
+
+impl FolderWatcher {
+    #[inline(always)]
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    #[inline(always)]
+    pub fn start(
+        &mut self,
+        ctx: &egui::Context,
+        time_options: &summary::TimeOptions,
+        extract_options: &archive::ExtractOptions,
+    ) -> Result<()> {
+        if self.settings.folders.is_empty() {
+            bail!("add a folder to watch first");
+        }
+        let archive_root = self
+            .settings
+            .archive_root
+            .clone()
+            .context("choose an archive folder first")?;
+        self.stop();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = channel();
+        let settings = self.settings.clone();
+        let time_options = time_options.clone();
+        let extract_options = extract_options.clone();
+        let thread_stop = stop.clone();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            watch_loop(
+                settings,
+                archive_root,
+                time_options,
+                extract_options,
+                thread_stop,
+                sender,
+                ctx,
+            )
+        });
+        self.running = Some((stop, receiver));
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn stop(&mut self) {
+        if let Some((stop, _)) = self.running.take() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Events received since the last frame, also kept in the log
+    #[inline(always)]
+    pub fn poll(&mut self) -> Vec<&WatchEvent> {
+        let Some((_, receiver)) = &self.running else {
+            return Vec::new();
+        };
+        let start = self.log.len();
+        self.log
+            .extend(receiver.try_iter().map(|event| (SystemTime::now(), event)));
+        let new = self.log.len() - start;
+        if self.log.len() > LOG_LENGTH {
+            self.log.drain(..self.log.len() - LOG_LENGTH);
+        }
+        // More events than the log holds may arrive between two frames
+        let new = new.min(self.log.len());
+        self.log[self.log.len() - new..]
+            .iter()
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    // Returns an error message when watching could not be started
+    #[inline(always)]
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        time_options: &summary::TimeOptions,
+        extract_options: &archive::ExtractOptions,
+    ) -> Option<String> {
+        let mut error = None;
+        let running = self.is_running();
+        ui.add_enabled_ui(!running, |ui| {
+            let mut remove = None;
+            for (idx, folder) in self.settings.folders.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    if ui.small_button("✖").clicked() {
+                        remove = Some(idx);
+                    }
+                    ui.label(folder.display().to_string());
+                });
+            }
+            if let Some(idx) = remove {
+                self.settings.folders.remove(idx);
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Add folder").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                        if !self.settings.folders.contains(&path) {
+                            self.settings.folders.push(path);
+                        }
+                    }
+                }
+                if ui.button("Archive folder").clicked() {
+                    if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                        self.settings.archive_root = Some(path);
+                    }
+                }
+                ui.label(
+                    self.settings
+                        .archive_root
+                        .as_ref()
+                        .map(|path| path.display().to_string())
+                        .unwrap_or_else(|| "no archive folder".to_string()),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::DragValue::new(&mut self.settings.interval_secs)
+                        .clamp_range(1..=3600)
+                        .prefix("Check every ")
+                        .suffix(" s"),
+                );
+                ui.checkbox(
+                    &mut self.settings.ingest_existing,
+                    "Ingest files already in the folders",
+                );
+            });
+        });
+        let label = if running {
+            "Stop watching"
+        } else {
+            "Start watching"
+        };
+        if ui.button(RichText::new(label).size(20.0)).clicked() {
+            if running {
+                self.stop();
+            } else if let Err(e) = self.start(ui.ctx(), time_options, extract_options) {
+                error = Some(format!("Cannot watch folders: {}", e));
+            }
+        }
+        if !self.log.is_empty() {
+            egui::ScrollArea::vertical()
+                .id_source("watch_log")
+                .max_height(150.0)
+                .show(ui, |ui| {
+                    Grid::new("watch_log_grid").striped(true).show(ui, |ui| {
+                        for (time, event) in self.log.iter().rev() {
+                            let time: chrono::DateTime<chrono::Local> = (*time).into();
+                            ui.label(time.format("%H:%M:%S").to_string());
+                            ui.label(
+                                event
+                                    .source
+                                    .as_ref()
+                                    .map(|path| path.display().to_string())
+                                    .unwrap_or_default(),
+                            );
+                            match &event.result {
+                                Ok(dest) => {
+                                    ui.colored_label(Color32::GREEN, dest.display().to_string())
+                                }
+                                Err(e) => ui.colored_label(Color32::RED, e),
+                            };
+                            ui.end_row();
+                        }
+                    });
+                });
+        }
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUMMARY: &str = "Time,Energy[J],Num. of SubSonic,Num. of Pulses,Pulse Duration,Act. Energy[J],Target Volume [cc]
+20230615120000,1000,2,10,0.5,950,0.1
+20230615121000,1200,2,10,0.5,1100,0.2
+";
+
+    // Empty folder below the system temp dir, unique per test
+    #[inline(always)]
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ejs-watch-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[inline(always)]
+    fn ingest_summary(root: &Path) -> Result<PathBuf> {
+        let source = root.join("TreatSummary_1.csv");
+        std::fs::write(&source, SUMMARY).unwrap();
+        ingest(
+            &source,
+            &root.join("archive"),
+            &summary::TimeOptions::default(),
+            &archive::ExtractOptions::default(),
+            None,
+        )
+    }
+
+    #[test]
+    fn ingest_files_the_export_by_treatment_date() {
+        let root = temp_dir("filed");
+        let dest = ingest_summary(&root).unwrap();
+        let day = root.join("archive").join("2023-06-15");
+        assert_eq!(dest, day.join("TreatSummary_1"));
+        assert!(dest.join("TreatSummary_1.csv").is_file());
+        assert!(dest.join("TreatSummary_1_simplify.csv").is_file());
+        assert!(!day.join(".TreatSummary_1.partial").exists());
+        // A second ingest of the same export is refused and leaves the filed copy alone
+        let error = ingest_summary(&root).unwrap_err().to_string();
+        assert!(error.starts_with("already filed in"), "{}", error);
+        assert!(dest.join("TreatSummary_1_simplify.csv").is_file());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn ingest_replaces_a_partial_folder_left_by_a_crash() {
+        let root = temp_dir("partial");
+        let partial = root
+            .join("archive")
+            .join("2023-06-15")
+            .join(".TreatSummary_1.partial");
+        std::fs::create_dir_all(&partial).unwrap();
+        std::fs::write(partial.join("stale.csv"), "stale").unwrap();
+        let dest = ingest_summary(&root).unwrap();
+        assert!(!partial.exists());
+        assert!(!dest.join("stale.csv").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn files_are_ingested_once_they_stop_changing() {
+        let mut seen = Seen::new();
+        let path = Path::new("TreatSummary_1.csv");
+        let time = Some(SystemTime::UNIX_EPOCH);
+        assert!(!is_ready(&mut seen, path, 10, time));
+        // Still growing
+        assert!(!is_ready(&mut seen, path, 20, time));
+        assert!(is_ready(&mut seen, path, 20, time));
+        record_attempt(&mut seen, path, true);
+        assert!(!is_ready(&mut seen, path, 20, time));
+        // A changed file is picked up again once it is stable
+        assert!(!is_ready(&mut seen, path, 30, time));
+        assert!(is_ready(&mut seen, path, 30, time));
+    }
+
+    #[test]
+    fn failed_ingests_are_retried_a_few_times() {
+        let mut seen = Seen::new();
+        let path = Path::new("TreatSummary_1.csv");
+        assert!(!is_ready(&mut seen, path, 10, None));
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(is_ready(&mut seen, path, 10, None));
+            record_attempt(&mut seen, path, false);
+        }
+        assert!(!is_ready(&mut seen, path, 10, None));
+        // Changing the file resets the attempts
+        assert!(!is_ready(&mut seen, path, 11, None));
+        assert!(is_ready(&mut seen, path, 11, None));
+    }
+}
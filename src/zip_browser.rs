@@ -0,0 +1,221 @@
+use crate::{
+    archive::{self, ZipEntry},
+    dialect::{self, DialectOverrides},
+};
+use eframe::egui::{self, Color32, Grid, RichText};
+use std::path::{Path, PathBuf};
+
+// Lines shown in the preview of a text entry
+const PREVIEW_LINES: usize = 200;
+
+// Bytes, decoded text and whether it looks like a treatment summary
+type Preview = (Vec<u8>, String, bool);
+
+#[derive(Default)]
+pub struct ZipBrowser {
+    pub open: bool,
+    path: Option<PathBuf>,
+    entries: Vec<ZipEntry>,
+    // Entry that was recognised as the treatment summary
+    summary: Option<String>,
+    selected: Option<usize>,
+    preview: Option<Result<Preview, String>>,
+    error: Option<String>,
+}
+
+// Decode the start of a text entry for the preview
+#[inline(always)]
+fn preview_text(bytes: &[u8]) -> String {
+    let text = match dialect::normalize(bytes, &DialectOverrides::default()) {
+        Ok((normalized, _)) => String::from_utf8_lossy(&normalized).into_owned(),
+        Err(_) => String::from_utf8_lossy(bytes).into_owned(),
+    };
+    text.lines()
+        .take(PREVIEW_LINES)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[inline(always)]
+fn format_time(time: zip::DateTime) -> String {
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}",
+        time.year(),
+        time.month(),
+        time.day(),
+        time.hour(),
+        time.minute()
+    )
+}
+
+#[inline(always)]
+fn format_size(size: u64) -> String {
+    if size >= 1_000_000 {
+        format!("{:.1} MB", size as f64 / 1e6)
+    } else {
+        format!("{:.1} kB", size as f64 / 1e3)
+    }
+}
+
+impl ZipBrowser {
+    #[inline(always)]
+    pub fn open_archive(&mut self, path: &Path) {
+        *self = Self {
+            open: true,
+            path: Some(path.to_path_buf()),
+            ..Self::default()
+        };
+        match archive::list_entries(path) {
+            Ok(entries) => self.entries = entries,
+            Err(e) => self.error = Some(format!("{:#}", e)),
+        }
+        let unlisted = self
+            .entries
+            .iter()
+            .filter(|entry| entry.error.is_some())
+            .count();
+        if unlisted > 0 && self.error.is_none() {
+            self.error = Some(format!(
+                "{} nested archive{} could not be listed, hover {} for the reason",
+                unlisted,
+                if unlisted == 1 { "" } else { "s" },
+                if unlisted == 1 { "it" } else { "them" }
+            ));
+        }
+        self.summary = archive::find_summary(path)
+            .ok()
+            .flatten()
+            .map(|(name, _)| name);
+    }
+
+    #[inline(always)]
+    fn select(&mut self, idx: usize) {
+        self.selected = Some(idx);
+        let (Some(path), Some(entry)) = (&self.path, self.entries.get(idx)) else {
+            return;
+        };
+        self.preview = (!entry.is_archive && archive::is_table(&entry.archive_path)).then(|| {
+            archive::read_entry(path, &entry.archive_path)
+                .map(|bytes| {
+                    let text = preview_text(&bytes);
+                    let is_summary = archive::is_summary(&bytes);
+                    (bytes, text, is_summary)
+                })
+                .map_err(|e| format!("{:#}", e))
+        });
+    }
+
+    // Returns the archive and the bytes of the table picked to load as summary
+    #[inline(always)]
+    pub fn show(&mut self, ctx: &egui::Context) -> Option<(PathBuf, Vec<u8>)> {
+        let mut load = None;
+        let mut open = self.open;
+        egui::Window::new("ZIP contents")
+            .open(&mut open)
+            .default_width(800.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Open archive").clicked() {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("ZIP", &["zip"])
+                            .pick_file()
+                        {
+                            self.open_archive(&path);
+                        }
+                    }
+                    if let Some(path) = &self.path {
+                        ui.label(path.display().to_string());
+                    }
+                });
+                if let Some(error) = &self.error {
+                    ui.colored_label(Color32::RED, error);
+                }
+                match &self.summary {
+                    Some(name) => ui.label(format!("Treatment summary: {}", name)),
+                    None if self.path.is_some() => {
+                        ui.colored_label(Color32::RED, "No treatment summary found")
+                    }
+                    None => ui.label(""),
+                };
+                ui.horizontal_top(|ui| {
+                    let mut clicked = None;
+                    egui::ScrollArea::vertical()
+                        .id_source("zip_entries")
+                        .max_height(400.0)
+                        .max_width(400.0)
+                        .show(ui, |ui| {
+                            Grid::new("zip_entries_grid").striped(true).show(ui, |ui| {
+                                for (idx, entry) in self.entries.iter().enumerate() {
+                                    let name =
+                                        entry.archive_path.rsplit('/').next().unwrap_or_default();
+                                    let mut text = RichText::new(format!(
+                                        "{}{}{}",
+                                        "    ".repeat(entry.depth),
+                                        if entry.is_archive { "🗀 " } else { "" },
+                                        name
+                                    ));
+                                    if self.summary.as_ref() == Some(&entry.archive_path) {
+                                        text = text.strong();
+                                    }
+                                    let hover = match &entry.error {
+                                        Some(error) => {
+                                            text = text.color(Color32::RED);
+                                            format!("{}\n{}", entry.archive_path, error)
+                                        }
+                                        None => entry.archive_path.clone(),
+                                    };
+                                    if ui
+                                        .selectable_label(self.selected == Some(idx), text)
+                                        .on_hover_text(hover)
+                                        .clicked()
+                                    {
+                                        clicked = Some(idx);
+                                    }
+                                    ui.label(format_size(entry.size));
+                                    ui.label(entry.modified.map(format_time).unwrap_or_default());
+                                    ui.end_row();
+                                }
+                            });
+                        });
+                    if let Some(idx) = clicked {
+                        self.select(idx);
+                    }
+                    ui.vertical(|ui| match &self.preview {
+                        Some(Ok((bytes, text, is_summary))) => {
+                            ui.horizontal(|ui| {
+                                if ui
+                                    .button(RichText::new("Load as summary").strong())
+                                    .clicked()
+                                {
+                                    if let Some(path) = &self.path {
+                                        load = Some((path.clone(), bytes.clone()));
+                                    }
+                                }
+                                if !is_summary {
+                                    ui.label(
+                                        RichText::new("the treatment summary columns are missing")
+                                            .weak(),
+                                    );
+                                }
+                            });
+                            egui::ScrollArea::both()
+                                .id_source("zip_preview")
+                                .max_height(400.0)
+                                .show(ui, |ui| {
+                                    ui.label(RichText::new(text).monospace());
+                                });
+                        }
+                        Some(Err(e)) => {
+                            ui.colored_label(Color32::RED, e);
+                        }
+                        None if self.selected.is_some() => {
+                            ui.label("No preview for this entry");
+                        }
+                        None => {}
+                    });
+                });
+            });
+        self.open = open && load.is_none();
+        load
+    }
+}